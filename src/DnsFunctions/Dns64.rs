@@ -0,0 +1,271 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::DnsAcl::IpPrefix;
+use crate::DnsHosts::reverse_name;
+use crate::Dnspacket::{DnsPacket, DnsRecord, ResultCode};
+
+/// Prefix lengths RFC 6052 section 2.2 defines an address format for.
+const PREFIX_LENGTHS: [u8; 6] = [32, 40, 48, 56, 64, 96];
+/// TTL of synthesised records when the AAAA response had no SOA to take one from.
+const NO_SOA_TTL: u32 = 600;
+
+/// AAAA synthesis for IPv6-only clients behind a NAT64 (RFC 6147).
+#[derive(Clone, Debug)]
+pub struct Dns64 {
+    pub enabled: bool,
+    prefix: IpPrefix,
+    /// IPv6 ranges whose AAAA records count as missing, and IPv4 ranges
+    /// never synthesised from.
+    exclude: Vec<IpPrefix>,
+}
+
+impl Default for Dns64 {
+    fn default() -> Dns64 {
+        Dns64 {
+            enabled: false,
+            prefix: IpPrefix::new(IpAddr::V6(Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0)), 96).unwrap(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl Dns64 {
+    pub fn set_prefix(&mut self, value: &str) -> Result<(), String> {
+        let prefix: IpPrefix = value.parse()?;
+        let IpAddr::V6(address) = prefix.address() else {
+            return Err(format!("NAT64 prefix `{}` is not an IPv6 range", value));
+        };
+        if !PREFIX_LENGTHS.contains(&prefix.len()) {
+            return Err(format!("NAT64 prefix length must be 32, 40, 48, 56, 64 or 96, got {}", prefix.len()));
+        }
+        // Bits 64 to 71 are reserved and must be zero.
+        if prefix.len() > 64 && address.octets()[8] != 0 {
+            return Err(format!("NAT64 prefix `{}` sets the reserved bits 64 to 71", value));
+        }
+        self.prefix = prefix;
+        Ok(())
+    }
+
+    pub fn exclude(&mut self, ranges: Vec<IpPrefix>) {
+        self.exclude.extend(ranges);
+    }
+
+    /// The address under the NAT64 prefix standing for `address`.
+    pub fn embed(&self, address: Ipv4Addr) -> Ipv6Addr {
+        let IpAddr::V6(prefix) = self.prefix.address() else {
+            unreachable!("the NAT64 prefix is checked to be IPv6");
+        };
+        let mut octets = [0u8; 16];
+        let len = usize::from(self.prefix.len() / 8);
+        octets[..len].copy_from_slice(&prefix.octets()[..len]);
+        for (position, byte) in embedding(self.prefix.len()).zip(address.octets()) {
+            octets[position] = byte;
+        }
+        Ipv6Addr::from(octets)
+    }
+
+    /// The IPv4 address `address` stands for, if it is under the NAT64 prefix.
+    pub fn extract(&self, address: Ipv6Addr) -> Option<Ipv4Addr> {
+        if address.to_ipv4_mapped().is_some() || !self.prefix.contains(IpAddr::V6(address)) {
+            return None;
+        }
+        let octets = address.octets();
+        let mut embedded = [0u8; 4];
+        for (byte, position) in embedded.iter_mut().zip(embedding(self.prefix.len())) {
+            *byte = octets[position];
+        }
+        Some(Ipv4Addr::from(embedded))
+    }
+
+    /// Whether an AAAA response leaves the client nothing to connect to, so
+    /// the name's A records should be synthesised from. NXDOMAIN is final;
+    /// any other error counts as an empty answer.
+    pub fn wants_synthesis(&self, response: &DnsPacket) -> bool {
+        self.enabled
+            && response.header.rescode != ResultCode::NXDOMAIN
+            && !response.answers.iter().any(|record| match record {
+                // IPv4-mapped addresses are never usable (RFC 6147 section 5.1.4).
+                DnsRecord::AAAA { addr, .. } => addr.to_ipv4_mapped().is_none() && !self.excluded(IpAddr::V6(*addr)),
+                _ => false,
+            })
+    }
+
+    /// The AAAA answer made from `a_response`, or None if it holds no A
+    /// records to synthesise from. Synthesised records live no longer than
+    /// the negative answer to the AAAA query would have (section 5.1.7).
+    pub fn synthesize(&self, aaaa_response: &DnsPacket, a_response: &DnsPacket) -> Option<DnsPacket> {
+        let negative_ttl = aaaa_response
+            .authorities
+            .iter()
+            .find_map(|record| match record {
+                DnsRecord::SOA { ttl, minimum, .. } => Some((*ttl).min(*minimum)),
+                _ => None,
+            })
+            .unwrap_or(NO_SOA_TTL);
+
+        let mut response = DnsPacket::new();
+        response.header.response = true;
+        // The synthesised answer holds for the same client subnet as the A answer.
+        response.resources = a_response
+            .resources
+            .iter()
+            .filter(|record| matches!(record, DnsRecord::OPT { .. }))
+            .cloned()
+            .collect();
+        let mut synthesised = false;
+        for record in &a_response.answers {
+            match record {
+                DnsRecord::A { domain, addr, ttl } => {
+                    if !self.excluded(IpAddr::V4(*addr)) {
+                        response.answers.push(DnsRecord::AAAA {
+                            domain: domain.clone(),
+                            addr: self.embed(*addr),
+                            ttl: (*ttl).min(negative_ttl),
+                        });
+                        synthesised = true;
+                    }
+                }
+                // CNAMEs on the way to the A records apply to the AAAA query too.
+                other => response.answers.push(other.clone()),
+            }
+        }
+        synthesised.then_some(response)
+    }
+
+    /// For a reverse lookup of an address under the NAT64 prefix, the
+    /// in-addr.arpa name of the IPv4 address it stands for (section 5.3.1).
+    pub fn reverse_target(&self, name: &str) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        let address = parse_ip6_arpa(name)?;
+        self.extract(address).map(|address| reverse_name(IpAddr::V4(address)))
+    }
+
+    fn excluded(&self, address: IpAddr) -> bool {
+        self.exclude.iter().any(|range| range.contains(address))
+    }
+}
+
+/// The answer to the ip6.arpa `name` made from the PTR response for its
+/// IPv4 counterpart.
+pub fn synthesize_ptr(name: &str, ptr_response: &DnsPacket) -> DnsPacket {
+    let mut response = DnsPacket::new();
+    response.header.response = true;
+    response.header.rescode = ptr_response.header.rescode;
+    response.answers = ptr_response
+        .answers
+        .iter()
+        .filter_map(|record| match record {
+            DnsRecord::PTR { host, ttl, .. } => Some(DnsRecord::PTR {
+                domain: name.to_string(),
+                host: host.clone(),
+                ttl: *ttl,
+            }),
+            _ => None,
+        })
+        .collect();
+    response.authorities = ptr_response.authorities.clone();
+    response
+}
+
+/// Octets of an IPv6 address the embedded IPv4 address occupies, skipping
+/// the reserved octet 8.
+fn embedding(prefix_len: u8) -> impl Iterator<Item = usize> {
+    (usize::from(prefix_len / 8)..16).filter(|position| *position != 8).take(4)
+}
+
+/// The address a full 32-nibble ip6.arpa name is for.
+fn parse_ip6_arpa(name: &str) -> Option<Ipv6Addr> {
+    let name = name.trim_end_matches('.').to_lowercase();
+    let nibbles = name.strip_suffix(".ip6.arpa")?;
+    let digits = nibbles
+        .split('.')
+        .map(|label| match label.len() {
+            1 => u8::from_str_radix(label, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    if digits.len() != 32 {
+        return None;
+    }
+    let address = digits.iter().rev().fold(0u128, |address, digit| address << 4 | u128::from(*digit));
+    Some(Ipv6Addr::from(address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled(prefix: &str) -> Dns64 {
+        let mut dns64 = Dns64 {
+            enabled: true,
+            ..Dns64::default()
+        };
+        dns64.set_prefix(prefix).unwrap();
+        dns64
+    }
+
+    #[test]
+    fn embeds_addresses_by_prefix_length() {
+        let address = Ipv4Addr::new(192, 0, 2, 33);
+        // The examples of RFC 6052 section 2.4.
+        for (prefix, expected) in [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("64:ff9b::/96", "64:ff9b::c000:221"),
+        ] {
+            let dns64 = enabled(prefix);
+            let embedded = dns64.embed(address);
+            assert_eq!(embedded, expected.parse::<Ipv6Addr>().unwrap(), "{}", prefix);
+            assert_eq!(dns64.extract(embedded), Some(address));
+        }
+        assert_eq!(enabled("64:ff9b::/96").extract("2001:db8::1".parse().unwrap()), None);
+        assert!(Dns64::default().set_prefix("64:ff9b::/80").is_err());
+        assert!(Dns64::default().set_prefix("10.0.0.0/8").is_err());
+    }
+
+    #[test]
+    fn synthesises_from_usable_a_records() {
+        let mut dns64 = enabled("64:ff9b::/96");
+        dns64.exclude(vec!["10.0.0.0/8".parse().unwrap(), "2001:db8:dead::/48".parse().unwrap()]);
+
+        let mut aaaa = DnsPacket::new();
+        aaaa.answers.push(DnsRecord::AAAA {
+            domain: "v4only.example".to_string(),
+            addr: "2001:db8:dead::1".parse().unwrap(),
+            ttl: 300,
+        });
+        assert!(dns64.wants_synthesis(&aaaa));
+        aaaa.header.rescode = ResultCode::NXDOMAIN;
+        assert!(!dns64.wants_synthesis(&aaaa));
+
+        let mut a = DnsPacket::new();
+        for (addr, ttl) in [(Ipv4Addr::new(192, 0, 2, 1), 60), (Ipv4Addr::new(10, 0, 0, 1), 60)] {
+            a.answers.push(DnsRecord::A {
+                domain: "v4only.example".to_string(),
+                addr,
+                ttl,
+            });
+        }
+        let synthesised = dns64.synthesize(&DnsPacket::new(), &a).unwrap();
+        assert_eq!(synthesised.answers.len(), 1);
+        assert_eq!(synthesised.answers[0].ttl(), Some(60));
+        assert!(matches!(synthesised.answers[0], DnsRecord::AAAA { addr, .. } if addr == "64:ff9b::c000:201".parse::<Ipv6Addr>().unwrap()));
+
+        a.answers.remove(0);
+        assert!(dns64.synthesize(&DnsPacket::new(), &a).is_none());
+    }
+
+    #[test]
+    fn maps_reverse_names_to_ipv4() {
+        let dns64 = enabled("64:ff9b::/96");
+        let name = reverse_name("64:ff9b::c000:221".parse().unwrap());
+        assert_eq!(dns64.reverse_target(&name).as_deref(), Some("33.2.0.192.in-addr.arpa"));
+        assert_eq!(dns64.reverse_target(&reverse_name("2001:db8::1".parse().unwrap())), None);
+        assert_eq!(dns64.reverse_target("1.0.0.2.ip6.arpa"), None);
+        assert_eq!(Dns64::default().reverse_target(&name), None);
+    }
+}
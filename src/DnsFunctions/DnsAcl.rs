@@ -0,0 +1,160 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address range in CIDR notation; a bare address is a range of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpPrefix {
+    address: IpAddr,
+    len: u8,
+}
+
+impl IpPrefix {
+    pub fn new(address: IpAddr, len: u8) -> Option<IpPrefix> {
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        (len <= max).then_some(IpPrefix { address, len })
+    }
+
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    pub fn len(&self) -> u8 {
+        self.len
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, canonical(address)) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let differing = u32::from(network) ^ u32::from(address);
+                self.len == 0 || differing >> (32 - u32::from(self.len)) == 0
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let differing = u128::from(network) ^ u128::from(address);
+                self.len == 0 || differing >> (128 - u32::from(self.len)) == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpPrefix {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid address range `{}`", value);
+        let (address, len) = match value.split_once('/') {
+            Some((address, len)) => (address, Some(len)),
+            None => (value, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let len = match len {
+            Some(len) => len.parse().map_err(|_| invalid())?,
+            None if address.is_ipv4() => 32,
+            None => 128,
+        };
+        IpPrefix::new(address, len).ok_or_else(invalid)
+    }
+}
+
+/// IPv4 clients of a dual-stack socket show up as IPv4-mapped IPv6 addresses.
+fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+        IpAddr::V4(_) => address,
+    }
+}
+
+/// What happens to a client's request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AclAction {
+    Allow,
+    /// Answer REFUSED.
+    Refuse,
+    /// Send nothing back.
+    Drop,
+}
+
+impl FromStr for AclAction {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "allow" => Ok(AclAction::Allow),
+            "refuse" => Ok(AclAction::Refuse),
+            "drop" => Ok(AclAction::Drop),
+            _ => Err(format!("expected allow, refuse or drop, got `{}`", value)),
+        }
+    }
+}
+
+/// Rules checked in the order they were given; the first covering the client
+/// decides. Clients no rule covers are refused, unless there are no rules at all.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    rules: Vec<(AclAction, IpPrefix)>,
+}
+
+impl Acl {
+    /// Adds `allow 10.0.0.0/8, 2001:db8::/32` style rules.
+    pub fn add_rules(&mut self, value: &str) -> Result<(), String> {
+        let (action, ranges) = value
+            .split_once(char::is_whitespace)
+            .ok_or("expected `<allow|refuse|drop> <range>[, <range>...]`")?;
+        let action: AclAction = action.parse()?;
+        for range in ranges.split(',').map(str::trim).filter(|range| !range.is_empty()) {
+            self.rules.push((action, range.parse()?));
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn check(&self, client: IpAddr) -> AclAction {
+        if self.rules.is_empty() {
+            return AclAction::Allow;
+        }
+        self.rules
+            .iter()
+            .find(|(_, prefix)| prefix.contains(client))
+            .map_or(AclAction::Refuse, |(action, _)| *action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_match_their_ranges() {
+        let v4: IpPrefix = "10.1.0.0/16".parse().unwrap();
+        assert!(v4.contains("10.1.255.3".parse().unwrap()));
+        assert!(!v4.contains("10.2.0.1".parse().unwrap()));
+        assert!(v4.contains("::ffff:10.1.0.1".parse().unwrap()));
+        let v6: IpPrefix = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!v6.contains("10.1.0.1".parse().unwrap()));
+        let everything: IpPrefix = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains("192.0.2.1".parse().unwrap()));
+        assert_eq!("::1".parse::<IpPrefix>().unwrap().len(), 128);
+        assert!("10.0.0.0/33".parse::<IpPrefix>().is_err());
+        assert!("10.0.0/8".parse::<IpPrefix>().is_err());
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let mut acl = Acl::default();
+        assert_eq!(acl.check("192.0.2.1".parse().unwrap()), AclAction::Allow);
+
+        acl.add_rules("drop 10.0.0.66").unwrap();
+        acl.add_rules("allow 10.0.0.0/8, ::1").unwrap();
+        assert_eq!(acl.check("10.0.0.66".parse().unwrap()), AclAction::Drop);
+        assert_eq!(acl.check("10.0.0.67".parse().unwrap()), AclAction::Allow);
+        assert_eq!(acl.check("::1".parse().unwrap()), AclAction::Allow);
+        assert_eq!(acl.check("192.0.2.1".parse().unwrap()), AclAction::Refuse);
+
+        assert!(acl.add_rules("deny 10.0.0.0/8").is_err());
+        assert!(acl.add_rules("allow").is_err());
+    }
+}
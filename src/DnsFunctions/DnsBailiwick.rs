@@ -0,0 +1,204 @@
+use crate::Dnspacket::{DnsPacket, DnsRecord};
+
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+/// Removes everything from a response that the server, being authoritative for
+/// `zone` and asked about `qname`, has no business telling us:
+///
+/// * answers must sit inside `zone` and on the CNAME chain starting at `qname`;
+/// * authority records must belong to `qname` or one of its ancestors inside `zone`;
+/// * additional records are only kept as addresses for nameserver or mail hosts
+///   named above, and only when those hosts are themselves inside `zone`.
+///
+/// Anything dropped here never reaches the resolver's state.
+pub fn scrub_response(packet: &mut DnsPacket, zone: &str, qname: &str) {
+    let mut chain = vec![qname.to_string()];
+    let mut answers = Vec::new();
+    let mut pending = std::mem::take(&mut packet.answers);
+    loop {
+        let before = answers.len();
+        pending.retain(|record: &DnsRecord| {
+            let owner = match record.domain() {
+                Some(owner) => owner,
+                None => return false,
+            };
+            if !is_subdomain(owner, zone) || !chain.iter().any(|name| name == owner) {
+                return true;
+            }
+            if let DnsRecord::CNAME { host, .. } = record {
+                chain.push(host.clone());
+            }
+            answers.push(record.clone());
+            false
+        });
+        if answers.len() == before {
+            break;
+        }
+    }
+    packet.answers = answers;
+
+    packet.authorities.retain(|record| match record.domain() {
+        Some(owner) => is_subdomain(owner, zone) && is_subdomain(qname, owner),
+        None => false,
+    });
+
+    let mut hosts: Vec<&str> = Vec::new();
+    for record in packet.answers.iter().chain(packet.authorities.iter()) {
+        match record {
+            DnsRecord::NS { host, .. } | DnsRecord::MX { host, .. } => hosts.push(host),
+            _ => (),
+        }
+    }
+    let resources = packet
+        .resources
+        .iter()
+        .filter(|record| match record {
+            DnsRecord::OPT { .. } => true,
+            DnsRecord::A { domain, .. } | DnsRecord::AAAA { domain, .. } => {
+                is_subdomain(domain, zone) && hosts.contains(&domain.as_str())
+            }
+            _ => false,
+        })
+        .cloned()
+        .collect();
+    packet.resources = resources;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn a(domain: &str, addr: [u8; 4]) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr: Ipv4Addr::from(addr),
+            ttl: 300,
+        }
+    }
+
+    fn ns(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::NS {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            ttl: 300,
+        }
+    }
+
+    fn cname(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::CNAME {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            ttl: 300,
+        }
+    }
+
+    #[test]
+    fn subdomain_matches_whole_labels_only() {
+        assert!(is_subdomain("www.example.com", "example.com"));
+        assert!(is_subdomain("example.com", "example.com"));
+        assert!(is_subdomain("example.com", ""));
+        assert!(!is_subdomain("badexample.com", "example.com"));
+        assert!(!is_subdomain("example.com", "www.example.com"));
+    }
+
+    #[test]
+    fn referral_keeps_in_bailiwick_glue() {
+        let mut packet = DnsPacket::new();
+        packet.authorities.push(ns("example.com", "ns1.example.com"));
+        packet.authorities.push(ns("example.com", "ns2.other.com"));
+        packet.resources.push(a("ns1.example.com", [192, 0, 2, 1]));
+        packet.resources.push(a("ns2.other.com", [192, 0, 2, 2]));
+
+        scrub_response(&mut packet, "com", "www.example.com");
+
+        assert_eq!(packet.authorities.len(), 2);
+        assert_eq!(
+            packet.resources,
+            vec![a("ns1.example.com", [192, 0, 2, 1]), a("ns2.other.com", [192, 0, 2, 2])]
+        );
+    }
+
+    #[test]
+    fn referral_drops_out_of_bailiwick_glue() {
+        let mut packet = DnsPacket::new();
+        packet.authorities.push(ns("example.com", "ns1.example.net"));
+        packet.resources.push(a("ns1.example.net", [192, 0, 2, 1]));
+        packet.resources.push(a("www.bank.org", [203, 0, 113, 66]));
+
+        scrub_response(&mut packet, "com", "www.example.com");
+
+        assert_eq!(packet.authorities, vec![ns("example.com", "ns1.example.net")]);
+        assert!(packet.resources.is_empty());
+    }
+
+    #[test]
+    fn referral_drops_glue_for_hosts_not_in_ns_set() {
+        let mut packet = DnsPacket::new();
+        packet.authorities.push(ns("example.com", "ns1.example.com"));
+        packet.resources.push(a("www.example.com", [203, 0, 113, 66]));
+
+        scrub_response(&mut packet, "com", "www.example.com");
+
+        assert!(packet.resources.is_empty());
+    }
+
+    #[test]
+    fn referral_drops_ns_for_unrelated_zones() {
+        let mut packet = DnsPacket::new();
+        packet.authorities.push(ns("example.com", "ns1.example.com"));
+        packet.authorities.push(ns("victim.com", "ns.attacker.com"));
+        packet.authorities.push(ns("org", "ns.attacker.com"));
+        packet.resources.push(a("ns.attacker.com", [203, 0, 113, 66]));
+
+        scrub_response(&mut packet, "com", "www.example.com");
+
+        assert_eq!(packet.authorities, vec![ns("example.com", "ns1.example.com")]);
+        assert!(packet.resources.is_empty());
+    }
+
+    #[test]
+    fn answers_follow_cname_chain_within_zone() {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(a("web.example.com", [192, 0, 2, 10]));
+        packet.answers.push(cname("www.example.com", "web.example.com"));
+        packet.answers.push(a("mail.example.com", [192, 0, 2, 25]));
+
+        scrub_response(&mut packet, "example.com", "www.example.com");
+
+        assert_eq!(
+            packet.answers,
+            vec![cname("www.example.com", "web.example.com"), a("web.example.com", [192, 0, 2, 10])]
+        );
+    }
+
+    #[test]
+    fn answers_stop_at_cname_leaving_zone() {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(cname("www.example.com", "cdn.example.net"));
+        packet.answers.push(a("cdn.example.net", [203, 0, 113, 66]));
+
+        scrub_response(&mut packet, "example.com", "www.example.com");
+
+        assert_eq!(packet.answers, vec![cname("www.example.com", "cdn.example.net")]);
+    }
+
+    #[test]
+    fn additional_keeps_opt_record() {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(a("www.example.com", [192, 0, 2, 10]));
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 1232,
+            flags: 0,
+            data: Vec::new(),
+        });
+        packet.resources.push(a("ns1.example.com", [192, 0, 2, 1]));
+
+        scrub_response(&mut packet, "example.com", "www.example.com");
+
+        assert_eq!(packet.resources.len(), 1);
+        assert!(matches!(packet.resources[0], DnsRecord::OPT { .. }));
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::DnsErrors::DnsResolverError;
+
+// Entries of hosts-format lists that name the machine itself, not something to block.
+const LOCAL_NAMES: [&str; 6] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+];
+
+/// How a blocked query is answered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockResponse {
+    NxDomain,
+    NoData,
+    Refused,
+    /// A and AAAA queries get these addresses; other types get no data.
+    Sinkhole(Vec<IpAddr>),
+}
+
+impl FromStr for BlockResponse {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "nxdomain" => Ok(BlockResponse::NxDomain),
+            "nodata" => Ok(BlockResponse::NoData),
+            "refused" => Ok(BlockResponse::Refused),
+            _ => value
+                .split(',')
+                .map(|address| address.trim().parse())
+                .collect::<Result<Vec<IpAddr>, _>>()
+                .map(BlockResponse::Sinkhole)
+                .map_err(|_| format!("expected nxdomain, nodata, refused or sinkhole addresses, got `{}`", value)),
+        }
+    }
+}
+
+//<---------------------------------- Suffix trie ------------------------------------->
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: HashMap<Box<str>, TrieNode>,
+    name: bool,
+    below: bool,
+}
+
+/// Domain names keyed label by label from the root, so a lookup costs one step
+/// per label of the query name however many entries there are.
+#[derive(Debug, Default)]
+pub struct SuffixTrie {
+    root: TrieNode,
+    len: usize,
+}
+
+impl SuffixTrie {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Adds `name` itself and/or every name below it.
+    pub fn insert(&mut self, name: &str, itself: bool, below: bool) {
+        let mut node = &mut self.root;
+        for label in labels(name) {
+            node = node.children.entry(label.into()).or_default();
+        }
+        if !node.name && !node.below {
+            self.len += 1;
+        }
+        node.name |= itself;
+        node.below |= below;
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let mut node = &self.root;
+        for label in labels(name) {
+            if node.below {
+                return true;
+            }
+            match node.children.get(label.as_str()) {
+                Some(child) => node = child,
+                None => return false,
+            }
+        }
+        node.name
+    }
+}
+
+fn labels(name: &str) -> impl Iterator<Item = String> + '_ {
+    name.trim_end_matches('.')
+        .rsplit('.')
+        .filter(|label| !label.is_empty())
+        .map(str::to_lowercase)
+}
+
+//<----------------------------------- Blocklist -------------------------------------->
+
+/// Names to block, and names the allowlists exempt from blocking.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    blocked: SuffixTrie,
+    allowed: SuffixTrie,
+}
+
+impl Blocklist {
+    pub fn new() -> Blocklist {
+        Blocklist::default()
+    }
+
+    pub fn load_blocklist(&mut self, path: &Path) -> Result<(), DnsResolverError> {
+        let contents = read_list(path)?;
+        let skipped = self.add_list(&contents, false);
+        println!("Loaded blocklist {}, {} lines skipped; {} names blocked", path.display(), skipped, self.blocked.len());
+        Ok(())
+    }
+
+    pub fn load_allowlist(&mut self, path: &Path) -> Result<(), DnsResolverError> {
+        let contents = read_list(path)?;
+        let skipped = self.add_list(&contents, true);
+        println!("Loaded allowlist {}, {} lines skipped; {} names allowed", path.display(), skipped, self.allowed.len());
+        Ok(())
+    }
+
+    /// Adds every entry of a hosts (`0.0.0.0 ads.example`), plain domain
+    /// (`ads.example`, or `*.ads.example` for everything below it) or adblock
+    /// (`||ads.example^`, with `@@` for exceptions) list, telling the formats
+    /// apart line by line. Lines that are none of these, such as adblock rules
+    /// for URLs or page elements, are skipped; returns how many were.
+    fn add_list(&mut self, contents: &str, allow: bool) -> usize {
+        let mut skipped = 0;
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(['#', '!', '[']) {
+                continue;
+            }
+            match parse_entries(line) {
+                Some(entries) => {
+                    for entry in entries {
+                        let trie = if allow || entry.exception { &mut self.allowed } else { &mut self.blocked };
+                        trie.insert(&entry.name, entry.itself, entry.below);
+                    }
+                }
+                None => skipped += 1,
+            }
+        }
+        skipped
+    }
+
+    pub fn is_blocked(&self, name: &str) -> bool {
+        self.blocked.matches(name) && !self.allowed.matches(name)
+    }
+}
+
+struct Entry {
+    name: String,
+    itself: bool,
+    below: bool,
+    exception: bool,
+}
+
+fn parse_entries(line: &str) -> Option<Vec<Entry>> {
+    let (rule, exception) = match line.strip_prefix("@@") {
+        Some(rule) => (rule, true),
+        None => (line, false),
+    };
+    if let Some(rule) = rule.strip_prefix("||") {
+        let name = rule.strip_suffix('^')?;
+        return valid_name(name).then(|| {
+            vec![Entry {
+                name: name.to_lowercase(),
+                itself: true,
+                below: true,
+                exception,
+            }]
+        });
+    }
+    if exception {
+        return None;
+    }
+
+    let line = line.split('#').next().unwrap_or("");
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields[..] {
+        [address, ref names @ ..] if !names.is_empty() && address.parse::<IpAddr>().is_ok() => {
+            let entries = names
+                .iter()
+                .map(|name| name.trim_end_matches('.').to_lowercase())
+                .filter(|name| !LOCAL_NAMES.contains(&name.as_str()) && name.parse::<IpAddr>().is_err())
+                .filter(|name| valid_name(name))
+                .map(|name| Entry { name, itself: true, below: false, exception: false })
+                .collect();
+            Some(entries)
+        }
+        [name] => {
+            let (name, below) = match name.strip_prefix("*.") {
+                Some(parent) => (parent, true),
+                None => (name, false),
+            };
+            valid_name(name).then(|| {
+                vec![Entry {
+                    name: name.trim_end_matches('.').to_lowercase(),
+                    itself: !below,
+                    below,
+                    exception: false,
+                }]
+            })
+        }
+        _ => None,
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+fn read_list(path: &Path) -> Result<String, DnsResolverError> {
+    fs::read_to_string(path).map_err(|e| DnsResolverError::ConfigError(format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trie_matches_exact_names_and_subtrees() {
+        let mut trie = SuffixTrie::default();
+        trie.insert("ads.example.com", true, false);
+        trie.insert("tracker.net", true, true);
+        trie.insert("wild.example", false, true);
+        assert_eq!(trie.len(), 3);
+
+        assert!(trie.matches("ads.example.com"));
+        assert!(trie.matches("ADS.Example.com."));
+        assert!(!trie.matches("x.ads.example.com"));
+        assert!(!trie.matches("example.com"));
+        assert!(trie.matches("tracker.net"));
+        assert!(trie.matches("a.b.tracker.net"));
+        assert!(!trie.matches("nottracker.net"));
+        assert!(!trie.matches("wild.example"));
+        assert!(trie.matches("a.wild.example"));
+    }
+
+    #[test]
+    fn reads_hosts_plain_and_adblock_formats() {
+        let mut list = Blocklist::new();
+        let skipped = list.add_list(
+            "# hosts\n0.0.0.0 ads.example.com metrics.example.com # trailing\n127.0.0.1 localhost\n\
+             ! adblock\n[Adblock Plus 2.0]\n||tracker.net^\n@@||ok.tracker.net^\n||example.org/banner.png\n\
+             plain.example\n*.wild.example\nnot a domain\n",
+            false,
+        );
+        assert_eq!(skipped, 2);
+
+        assert!(list.is_blocked("ads.example.com"));
+        assert!(list.is_blocked("metrics.example.com"));
+        assert!(!list.is_blocked("localhost"));
+        assert!(list.is_blocked("cdn.tracker.net"));
+        assert!(!list.is_blocked("ok.tracker.net"));
+        assert!(!list.is_blocked("www.ok.tracker.net"));
+        assert!(list.is_blocked("plain.example"));
+        assert!(!list.is_blocked("sub.plain.example"));
+        assert!(list.is_blocked("a.wild.example"));
+        assert!(!list.is_blocked("wild.example"));
+        assert!(!list.is_blocked("example.org"));
+    }
+
+    #[test]
+    fn allowlists_override_blocklists() {
+        let mut list = Blocklist::new();
+        list.add_list("||example.com^\n", false);
+        list.add_list("www.example.com\n", true);
+        assert!(list.is_blocked("ads.example.com"));
+        assert!(!list.is_blocked("www.example.com"));
+    }
+
+    #[test]
+    fn parses_block_responses() {
+        assert_eq!("nodata".parse(), Ok(BlockResponse::NoData));
+        assert_eq!(
+            "0.0.0.0, ::".parse(),
+            Ok(BlockResponse::Sinkhole(vec!["0.0.0.0".parse().unwrap(), "::".parse().unwrap()]))
+        );
+        assert!("blackhole".parse::<BlockResponse>().is_err());
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::DnsAcl::IpPrefix;
+use crate::DnsEcs::ClientSubnet;
+use crate::DnsInflight::QueryKey;
+use crate::Dnspacket::{DnsPacket, DnsRecord, ResultCode};
+
+/// Upper bound on how long anything is kept, whatever its TTL says.
+const MAX_TTL: u32 = 86_400;
+
+#[derive(Debug)]
+struct CachedResponse {
+    rescode: ResultCode,
+    answers: Vec<DnsRecord>,
+    authorities: Vec<DnsRecord>,
+    stored: Instant,
+    ttl: u32,
+    /// The client subnet the answer was given for (RFC 7871 section 7.3.1);
+    /// None if it holds for everyone.
+    scope: Option<IpPrefix>,
+}
+
+impl CachedResponse {
+    fn expires(&self) -> Instant {
+        self.stored + Duration::from_secs(self.ttl.into())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    by_key: HashMap<QueryKey, Vec<CachedResponse>>,
+    // Responses held across all keys.
+    len: usize,
+}
+
+/// Resolved answers kept for their TTL; negative answers for the SOA's
+/// negative TTL (RFC 2308). Errors are never kept. A question may have an
+/// answer per client subnet, each only handed to clients inside it.
+#[derive(Debug)]
+pub struct ResponseCache {
+    entries: Mutex<Entries>,
+    capacity: usize,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize) -> ResponseCache {
+        ResponseCache {
+            entries: Mutex::new(Entries::default()),
+            capacity,
+        }
+    }
+
+    /// The cached response for a client in `subnet` with its TTLs counted
+    /// down by the time it has been kept. An answer given for a subnet comes
+    /// back with an ECS option naming it.
+    pub fn get(&self, key: &QueryKey, subnet: Option<IpAddr>) -> Option<DnsPacket> {
+        self.get_at(key, subnet, Instant::now())
+    }
+
+    fn get_at(&self, key: &QueryKey, subnet: Option<IpAddr>, now: Instant) -> Option<DnsPacket> {
+        let mut entries = self.entries.lock().unwrap();
+        let Entries { by_key, len } = &mut *entries;
+        let responses = by_key.get_mut(key)?;
+        let before = responses.len();
+        responses.retain(|entry| entry.expires() > now);
+        *len -= before - responses.len();
+        let entry = responses
+            .iter()
+            .filter(|entry| match entry.scope {
+                Some(scope) => subnet.is_some_and(|address| scope.contains(address)),
+                None => true,
+            })
+            .max_by_key(|entry| entry.scope.map_or(0, |scope| scope.len()))?;
+
+        let age = now.saturating_duration_since(entry.stored).as_secs() as u32;
+        let aged = |record: &DnsRecord| record.with_ttl(record.ttl().unwrap_or(0).saturating_sub(age));
+        let mut response = DnsPacket::new();
+        response.header.response = true;
+        response.header.rescode = entry.rescode;
+        response.answers = entry.answers.iter().map(aged).collect();
+        response.authorities = entry.authorities.iter().map(aged).collect();
+        if let Some(scope) = entry.scope {
+            let subnet = ClientSubnet {
+                address: scope.address(),
+                source_prefix: scope.len(),
+                scope_prefix: scope.len(),
+            };
+            response.resources.push(DnsRecord::OPT {
+                packet_len: 0,
+                flags: 0,
+                data: subnet.option(),
+            });
+        }
+        Some(response)
+    }
+
+    pub fn insert(&self, key: QueryKey, response: &DnsPacket) {
+        self.insert_at(key, response, Instant::now())
+    }
+
+    fn insert_at(&self, key: QueryKey, response: &DnsPacket, now: Instant) {
+        let Some(ttl) = cache_ttl(response) else {
+            return;
+        };
+        if self.capacity == 0 {
+            return;
+        }
+        // A scope longer than the prefix sent only narrows it down to that prefix.
+        let scope = ClientSubnet::from_packet(response)
+            .filter(|subnet| subnet.scope_prefix > 0)
+            .and_then(|subnet| IpPrefix::new(subnet.address, subnet.scope_prefix.min(subnet.source_prefix)));
+
+        let mut entries = self.entries.lock().unwrap();
+        let Entries { by_key, len } = &mut *entries;
+        if let Some(responses) = by_key.get_mut(&key) {
+            let before = responses.len();
+            responses.retain(|entry| entry.scope != scope);
+            *len -= before - responses.len();
+        }
+        if *len >= self.capacity {
+            by_key.retain(|_, responses| {
+                responses.retain(|entry| entry.expires() > now);
+                !responses.is_empty()
+            });
+            *len = by_key.values().map(Vec::len).sum();
+        }
+        if *len >= self.capacity {
+            // Still full: make room by giving up the entry closest to expiring.
+            let soonest = by_key
+                .iter()
+                .flat_map(|(key, responses)| responses.iter().enumerate().map(move |(i, entry)| (key, i, entry.expires())))
+                .min_by_key(|(_, _, expires)| *expires)
+                .map(|(key, i, _)| (key.clone(), i));
+            if let Some((soonest, i)) = soonest {
+                let responses = by_key.get_mut(&soonest).unwrap();
+                responses.remove(i);
+                if responses.is_empty() {
+                    by_key.remove(&soonest);
+                }
+                *len -= 1;
+            }
+        }
+        by_key.entry(key).or_default().push(CachedResponse {
+            rescode: response.header.rescode,
+            answers: response.answers.clone(),
+            authorities: response.authorities.clone(),
+            stored: now,
+            ttl,
+            scope,
+        });
+        *len += 1;
+    }
+}
+
+/// The lowest TTL among the answers, or for NXDOMAIN and empty answers the
+/// lower of the SOA's TTL and minimum field. None for what is not to be kept.
+fn cache_ttl(response: &DnsPacket) -> Option<u32> {
+    let ttl = match response.header.rescode {
+        ResultCode::NOERROR if !response.answers.is_empty() => {
+            response.answers.iter().filter_map(DnsRecord::ttl).min()?
+        }
+        ResultCode::NOERROR | ResultCode::NXDOMAIN => response.authorities.iter().find_map(|record| match record {
+            DnsRecord::SOA { ttl, minimum, .. } => Some((*ttl).min(*minimum)),
+            _ => None,
+        })?,
+        _ => return None,
+    };
+    (ttl > 0).then_some(ttl.min(MAX_TTL))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Dnspacket::{DnsQuestion, QueryType};
+    use std::net::Ipv4Addr;
+
+    fn key(name: &str) -> QueryKey {
+        QueryKey::new(&DnsQuestion::new(name.to_string(), QueryType::A))
+    }
+
+    fn answer(ttls: &[u32]) -> DnsPacket {
+        let mut response = DnsPacket::new();
+        for ttl in ttls {
+            response.answers.push(DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: *ttl,
+            });
+        }
+        response
+    }
+
+    fn negative(rescode: ResultCode, ttl: u32, minimum: u32) -> DnsPacket {
+        let mut response = DnsPacket::new();
+        response.header.rescode = rescode;
+        response.authorities.push(DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum,
+            ttl,
+        });
+        response
+    }
+
+    #[test]
+    fn counts_ttls_down_and_expires() {
+        let cache = ResponseCache::new(10);
+        let now = Instant::now();
+        cache.insert_at(key("www.example.com"), &answer(&[300, 60]), now);
+
+        let later = cache.get_at(&key("WWW.example.com"), None, now + Duration::from_secs(20)).unwrap();
+        let ttls: Vec<u32> = later.answers.iter().filter_map(DnsRecord::ttl).collect();
+        assert_eq!(ttls, vec![280, 40]);
+        assert!(cache.get_at(&key("www.example.com"), None, now + Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn keeps_negative_answers_for_the_soa_minimum() {
+        assert_eq!(cache_ttl(&negative(ResultCode::NXDOMAIN, 3600, 300)), Some(300));
+        assert_eq!(cache_ttl(&negative(ResultCode::NOERROR, 60, 300)), Some(60));
+        assert_eq!(cache_ttl(&negative(ResultCode::SERVFAIL, 60, 300)), None);
+        assert_eq!(cache_ttl(&DnsPacket::new()), None);
+        assert_eq!(cache_ttl(&answer(&[0])), None);
+        assert_eq!(cache_ttl(&answer(&[604_800])), Some(MAX_TTL));
+    }
+
+    #[test]
+    fn evicts_the_entry_closest_to_expiring_when_full() {
+        let cache = ResponseCache::new(2);
+        let now = Instant::now();
+        cache.insert_at(key("a.example"), &answer(&[300]), now);
+        cache.insert_at(key("b.example"), &answer(&[30]), now);
+        cache.insert_at(key("c.example"), &answer(&[300]), now);
+        assert!(cache.get_at(&key("a.example"), None, now).is_some());
+        assert!(cache.get_at(&key("b.example"), None, now).is_none());
+        assert!(cache.get_at(&key("c.example"), None, now).is_some());
+    }
+
+    #[test]
+    fn hands_scoped_answers_only_to_their_subnet() {
+        let cache = ResponseCache::new(10);
+        let now = Instant::now();
+        let mut regional = answer(&[300]);
+        regional.resources.push(DnsRecord::OPT {
+            packet_len: 1232,
+            flags: 0,
+            data: ClientSubnet {
+                address: "198.51.100.0".parse().unwrap(),
+                source_prefix: 24,
+                scope_prefix: 16,
+            }
+            .option(),
+        });
+        cache.insert_at(key("cdn.example"), &regional, now);
+        let inside = Some("198.51.7.0".parse().unwrap());
+        let hit = cache.get_at(&key("cdn.example"), inside, now).unwrap();
+        assert_eq!(ClientSubnet::from_packet(&hit).map(|subnet| subnet.scope_prefix), Some(16));
+        assert!(cache.get_at(&key("cdn.example"), Some("203.0.113.1".parse().unwrap()), now).is_none());
+        assert!(cache.get_at(&key("cdn.example"), None, now).is_none());
+
+        // A global answer is there for everyone, but the regional one still wins inside its subnet.
+        cache.insert_at(key("cdn.example"), &answer(&[60]), now);
+        assert!(cache.get_at(&key("cdn.example"), None, now).is_some());
+        let hit = cache.get_at(&key("cdn.example"), inside, now).unwrap();
+        assert_eq!(hit.answers[0].ttl(), Some(300));
+    }
+}
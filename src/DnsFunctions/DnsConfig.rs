@@ -0,0 +1,579 @@
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::DnsErrors::DnsResolverError;
+use crate::DnsAcl::{Acl, IpPrefix};
+use crate::DnsRateLimit::RateLimits;
+use crate::Dns64::Dns64;
+use crate::DnsEcs::EcsPolicy;
+use crate::DnsCookie::parse_secret;
+use crate::DnsBlocklist::BlockResponse;
+use crate::DnsHosts::parse_static_record;
+use crate::Dnspacket::DnsRecord;
+use crate::DnsForwarder::{parse_forwarder, parse_upstream, ForwardStrategy, Upstream, UpstreamPrivacy};
+use crate::DnsTsig::TsigKey;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResolutionMode {
+    Recursive,
+    Forward,
+}
+
+impl FromStr for ResolutionMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "recursive" => Ok(ResolutionMode::Recursive),
+            "forward" => Ok(ResolutionMode::Forward),
+            _ => Err(format!("expected recursive or forward, got `{}`", value)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QnameMinimisation {
+    Off,
+    Relaxed,
+    Strict,
+}
+
+impl FromStr for QnameMinimisation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(QnameMinimisation::Off),
+            "relaxed" => Ok(QnameMinimisation::Relaxed),
+            "strict" => Ok(QnameMinimisation::Strict),
+            _ => Err(format!("expected off, relaxed or strict, got `{}`", value)),
+        }
+    }
+}
+
+/// Who may transfer or update a zone: a client address, or anyone signing
+/// with a TSIG key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AclEntry {
+    Address(IpAddr),
+    Key(String),
+}
+
+/// Clients that see their own local zones, routes and cache. A client is in
+/// the first view whose addresses, client subnet ranges or keys cover it.
+#[derive(Clone, Debug, Default)]
+pub struct ViewConfig {
+    pub name: String,
+    pub clients: Vec<IpPrefix>,
+    pub client_subnets: Vec<IpPrefix>,
+    pub keys: Vec<String>,
+    pub zones: Vec<(String, PathBuf)>,
+    pub forward_zones: Vec<(String, Vec<Upstream>)>,
+    pub stub_zones: Vec<(String, Vec<SocketAddr>)>,
+}
+
+//<------------------------------------ Config ---------------------------------------->
+
+/// Settings read from a `key = value` file; `#` starts a comment.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub mode: ResolutionMode,
+    pub forwarders: Vec<Upstream>,
+    pub forward_strategy: ForwardStrategy,
+    pub health_check_interval: u64,
+    pub upstream_privacy: UpstreamPrivacy,
+    // Trust anchors for encrypted forwarders instead of the built-in roots.
+    pub upstream_ca: Option<PathBuf>,
+    pub forward_zones: Vec<(String, Vec<Upstream>)>,
+    pub stub_zones: Vec<(String, Vec<SocketAddr>)>,
+    pub zones: Vec<(String, PathBuf)>,
+    pub hosts_files: Vec<PathBuf>,
+    pub static_records: Vec<DnsRecord>,
+    pub blocklists: Vec<PathBuf>,
+    pub allowlists: Vec<PathBuf>,
+    pub block_response: BlockResponse,
+    pub response_policy_zones: Vec<String>,
+    pub allow_transfer: Vec<(String, Vec<AclEntry>)>,
+    pub allow_update: Vec<(String, Vec<AclEntry>)>,
+    pub also_notify: Vec<(String, Vec<SocketAddr>)>,
+    pub secondary_zones: Vec<(String, Vec<SocketAddr>)>,
+    pub secondary_keys: Vec<(String, String)>,
+    pub tsig_keys: Vec<TsigKey>,
+    pub recursion_acl: Acl,
+    // Without rules of its own, the recursion ACL's, so restricting recursion
+    // does not leave the cache open to snooping by everyone else.
+    pub cache_acl: Acl,
+    pub transfer_acl: Acl,
+    pub update_acl: Acl,
+    pub rate_limits: RateLimits,
+    pub views: Vec<ViewConfig>,
+    pub cache_size: usize,
+    pub dns64: Dns64,
+    pub ecs: EcsPolicy,
+    pub cookies: bool,
+    pub require_cookies: bool,
+    // Shared by servers behind one address so they accept each other's cookies.
+    pub cookie_secret: Option<[u8; 16]>,
+    pub dot_port: Option<u16>,
+    pub doh_port: Option<u16>,
+    pub tls_certificate: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_idle_timeout: u64,
+    pub qname_minimisation: QnameMinimisation,
+    pub use_0x20: bool,
+    pub max_waiters_per_query: usize,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            mode: ResolutionMode::Recursive,
+            forwarders: Vec::new(),
+            forward_strategy: ForwardStrategy::Ordered,
+            health_check_interval: 30,
+            upstream_privacy: UpstreamPrivacy::Strict,
+            upstream_ca: None,
+            forward_zones: Vec::new(),
+            stub_zones: Vec::new(),
+            zones: Vec::new(),
+            hosts_files: Vec::new(),
+            static_records: Vec::new(),
+            blocklists: Vec::new(),
+            allowlists: Vec::new(),
+            block_response: BlockResponse::NxDomain,
+            response_policy_zones: Vec::new(),
+            allow_transfer: Vec::new(),
+            allow_update: Vec::new(),
+            also_notify: Vec::new(),
+            secondary_zones: Vec::new(),
+            secondary_keys: Vec::new(),
+            tsig_keys: Vec::new(),
+            recursion_acl: Acl::default(),
+            cache_acl: Acl::default(),
+            transfer_acl: Acl::default(),
+            update_acl: Acl::default(),
+            rate_limits: RateLimits::default(),
+            views: Vec::new(),
+            cache_size: 10_000,
+            dns64: Dns64::default(),
+            ecs: EcsPolicy::default(),
+            cookies: true,
+            require_cookies: false,
+            cookie_secret: None,
+            dot_port: None,
+            doh_port: None,
+            tls_certificate: None,
+            tls_key: None,
+            tls_idle_timeout: 10,
+            qname_minimisation: QnameMinimisation::Relaxed,
+            use_0x20: false,
+            max_waiters_per_query: 64,
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Config, DnsResolverError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| DnsResolverError::ConfigError(format!("{}: {}", path, e)))?;
+        Config::parse(&contents)
+    }
+
+    fn validate(&self) -> Result<(), DnsResolverError> {
+        if self.mode == ResolutionMode::Forward && self.forwarders.is_empty() {
+            return Err(DnsResolverError::ConfigError(
+                "mode = forward needs at least one entry in `forwarders`".to_string(),
+            ));
+        }
+        for (origin, _) in &self.secondary_zones {
+            if self.zones.iter().any(|(zone, _)| zone == origin) {
+                return Err(DnsResolverError::ConfigError(format!(
+                    "{} is configured both as a zone file and as a secondary zone",
+                    origin
+                )));
+            }
+        }
+        for (origin, _) in &self.also_notify {
+            if !self.zones.iter().any(|(zone, _)| zone == origin) {
+                return Err(DnsResolverError::ConfigError(format!(
+                    "also_notify for {}, which is not loaded from a zone file",
+                    origin
+                )));
+            }
+        }
+        for origin in &self.response_policy_zones {
+            let loaded = self.zones.iter().any(|(zone, _)| zone == origin)
+                || self.secondary_zones.iter().any(|(zone, _)| zone == origin);
+            if !loaded {
+                return Err(DnsResolverError::ConfigError(format!(
+                    "rpz {}, which is neither a zone nor a secondary zone",
+                    origin
+                )));
+            }
+        }
+        for view in &self.views {
+            if view.clients.is_empty() && view.client_subnets.is_empty() && view.keys.is_empty() {
+                return Err(DnsResolverError::ConfigError(format!("view {} matches no clients", view.name)));
+            }
+        }
+        for (index, key) in self.tsig_keys.iter().enumerate() {
+            if self.tsig_keys[..index].iter().any(|other| other.name == key.name) {
+                return Err(DnsResolverError::ConfigError(format!("TSIG key {} is defined twice", key.name)));
+            }
+        }
+        let acl_keys = self.allow_transfer.iter().chain(&self.allow_update).flat_map(|(_, entries)| {
+            entries.iter().filter_map(|entry| match entry {
+                AclEntry::Key(name) => Some(name),
+                AclEntry::Address(_) => None,
+            })
+        });
+        let view_keys = self.views.iter().flat_map(|view| &view.keys);
+        for name in acl_keys.chain(self.secondary_keys.iter().map(|(_, name)| name)).chain(view_keys) {
+            if self.tsig_key(name).is_none() {
+                return Err(DnsResolverError::ConfigError(format!("unknown TSIG key {}", name)));
+            }
+        }
+        for (origin, _) in &self.secondary_keys {
+            if !self.secondary_zones.iter().any(|(zone, _)| zone == origin) {
+                return Err(DnsResolverError::ConfigError(format!(
+                    "secondary_key for {}, which is not a secondary zone",
+                    origin
+                )));
+            }
+        }
+        let tls = self.dot_port.is_some() || self.doh_port.is_some();
+        if tls && (self.tls_certificate.is_none() || self.tls_key.is_none()) {
+            return Err(DnsResolverError::ConfigError(
+                "dot_port and doh_port need both `tls_certificate` and `tls_key`".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn tsig_key(&self, name: &str) -> Option<&TsigKey> {
+        self.tsig_keys.iter().find(|key| key.name == name)
+    }
+
+    /// The key transfers of a secondary zone are signed with, and its
+    /// primaries' NOTIFY messages have to be signed with.
+    pub fn secondary_key(&self, zone: &str) -> Option<&TsigKey> {
+        let (_, name) = self.secondary_keys.iter().find(|(origin, _)| origin == zone)?;
+        self.tsig_key(name)
+    }
+
+    /// The key NOTIFY messages for a local zone are signed with: the first one
+    /// its transfers are allowed with, which its secondaries pull it with.
+    pub fn notify_key(&self, zone: &str) -> Option<&TsigKey> {
+        self.allow_transfer
+            .iter()
+            .filter(|(allowed_zone, _)| allowed_zone == zone)
+            .flat_map(|(_, entries)| entries)
+            .find_map(|entry| match entry {
+                AclEntry::Key(name) => self.tsig_key(name),
+                AclEntry::Address(_) => None,
+            })
+    }
+
+    /// Zone transfers are refused unless the client's address, or the key its
+    /// request was signed with, is listed for the zone.
+    pub fn transfer_allowed(&self, zone: &str, client: IpAddr, key: Option<&str>) -> bool {
+        listed(&self.allow_transfer, zone, client, key)
+    }
+
+    /// Dynamic updates are refused unless the client's address, or the key its
+    /// request was signed with, is listed for the zone.
+    pub fn update_allowed(&self, zone: &str, client: IpAddr, key: Option<&str>) -> bool {
+        listed(&self.allow_update, zone, client, key)
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, DnsResolverError> {
+        let mut config = Config::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| config_error(line_number, "expected `key = value`"))?;
+            let (key, value) = (key.trim(), value.trim());
+
+            match key {
+                "mode" => {
+                    config.mode = value.parse().map_err(|e: String| config_error(line_number, &e))?
+                }
+                "forwarders" => {
+                    config.forwarders = parse_list(value)
+                        .map(parse_forwarder)
+                        .collect::<Result<_, _>>()
+                        .map_err(|e| config_error(line_number, &e))?
+                }
+                "forward_strategy" => {
+                    config.forward_strategy =
+                        value.parse().map_err(|e: String| config_error(line_number, &e))?
+                }
+                "forward_zone" => {
+                    config.forward_zones.push(parse_zone_servers(value, line_number, parse_forwarder)?)
+                }
+                "stub_zone" => config.stub_zones.push(parse_zone_servers(value, line_number, parse_upstream)?),
+                "zone" => config.zones.push(parse_zone_file(value, line_number)?),
+                "secondary_zone" => {
+                    config.secondary_zones.push(parse_zone_servers(value, line_number, parse_upstream)?)
+                }
+                "allow_transfer" => config.allow_transfer.push(parse_zone_clients(value, line_number)?),
+                "allow_update" => config.allow_update.push(parse_zone_clients(value, line_number)?),
+                "hosts_file" => config.hosts_files.push(PathBuf::from(value)),
+                "static_record" => config
+                    .static_records
+                    .push(parse_static_record(value).map_err(|e| config_error(line_number, &e))?),
+                "blocklist" => config.blocklists.push(PathBuf::from(value)),
+                "allowlist" => config.allowlists.push(PathBuf::from(value)),
+                "block_response" => {
+                    config.block_response = value.parse().map_err(|e: String| config_error(line_number, &e))?
+                }
+                "rpz" => config.response_policy_zones.push(value.trim_end_matches('.').to_lowercase()),
+                "also_notify" => config.also_notify.push(parse_zone_servers(value, line_number, parse_upstream)?),
+                "secondary_key" => config.secondary_keys.push(parse_zone_key(value, line_number)?),
+                "tsig_key" => {
+                    config.tsig_keys.push(TsigKey::parse(value).map_err(|e| config_error(line_number, &e))?)
+                }
+                "recursion_acl" => config.recursion_acl.add_rules(value).map_err(|e| config_error(line_number, &e))?,
+                "cache_acl" => config.cache_acl.add_rules(value).map_err(|e| config_error(line_number, &e))?,
+                "transfer_acl" => config.transfer_acl.add_rules(value).map_err(|e| config_error(line_number, &e))?,
+                "update_acl" => config.update_acl.add_rules(value).map_err(|e| config_error(line_number, &e))?,
+                "rrl_responses_per_second" => {
+                    config.rate_limits.responses_per_second = parse_number(value, line_number)?
+                }
+                "rrl_slip" => config.rate_limits.slip = parse_number(value, line_number)?,
+                "rrl_log_only" => config.rate_limits.log_only = parse_bool(value, line_number)?,
+                "rrl_ipv4_prefix" => config.rate_limits.ipv4_prefix = parse_prefix_length(value, 32, line_number)?,
+                "rrl_ipv6_prefix" => config.rate_limits.ipv6_prefix = parse_prefix_length(value, 128, line_number)?,
+                "client_queries_per_second" => {
+                    config.rate_limits.queries_per_second = parse_number(value, line_number)?
+                }
+                "view" => {
+                    let (name, clients) = value.split_once(char::is_whitespace).unwrap_or((value, ""));
+                    if config.views.iter().any(|view| view.name == name) {
+                        return Err(config_error(line_number, &format!("view {} is defined twice", name)));
+                    }
+                    config.views.push(ViewConfig {
+                        name: name.to_string(),
+                        clients: parse_ranges(clients, line_number)?,
+                        ..ViewConfig::default()
+                    });
+                }
+                "view_match_ecs" => {
+                    let (view, ranges) = view_setting(&mut config.views, value, line_number)?;
+                    view.client_subnets.extend(parse_ranges(ranges, line_number)?);
+                }
+                "view_match_key" => {
+                    let (view, key) = view_setting(&mut config.views, value, line_number)?;
+                    view.keys.push(key.trim_end_matches('.').to_lowercase());
+                }
+                "view_zone" => {
+                    let (view, zone) = view_setting(&mut config.views, value, line_number)?;
+                    view.zones.push(parse_zone_file(zone, line_number)?);
+                }
+                "view_forward_zone" => {
+                    let (view, zone) = view_setting(&mut config.views, value, line_number)?;
+                    view.forward_zones.push(parse_zone_servers(zone, line_number, parse_forwarder)?);
+                }
+                "view_stub_zone" => {
+                    let (view, zone) = view_setting(&mut config.views, value, line_number)?;
+                    view.stub_zones.push(parse_zone_servers(zone, line_number, parse_upstream)?);
+                }
+                "cache_size" => config.cache_size = parse_number(value, line_number)?,
+                "dns64" => config.dns64.enabled = parse_bool(value, line_number)?,
+                "dns64_prefix" => config
+                    .dns64
+                    .set_prefix(value)
+                    .map_err(|e| config_error(line_number, &e))?,
+                "dns64_exclude" => config.dns64.exclude(parse_ranges(value, line_number)?),
+                "ecs_zones" => config
+                    .ecs
+                    .zones
+                    .extend(parse_list(value).map(|zone| zone.trim_end_matches('.').to_lowercase())),
+                "ecs_ipv4_prefix" => config.ecs.ipv4_prefix = parse_prefix_length(value, 32, line_number)?,
+                "ecs_ipv6_prefix" => config.ecs.ipv6_prefix = parse_prefix_length(value, 128, line_number)?,
+                "cookies" => config.cookies = parse_bool(value, line_number)?,
+                "require_cookies" => config.require_cookies = parse_bool(value, line_number)?,
+                "cookie_secret" => {
+                    config.cookie_secret = Some(parse_secret(value).map_err(|e| config_error(line_number, &e))?)
+                }
+                "dot_port" => config.dot_port = Some(parse_number(value, line_number)?),
+                "doh_port" => config.doh_port = Some(parse_number(value, line_number)?),
+                "tls_certificate" => config.tls_certificate = Some(PathBuf::from(value)),
+                "tls_key" => config.tls_key = Some(PathBuf::from(value)),
+                "tls_idle_timeout" => config.tls_idle_timeout = parse_number(value, line_number)?,
+                "upstream_privacy" => {
+                    config.upstream_privacy = value.parse().map_err(|e: String| config_error(line_number, &e))?
+                }
+                "upstream_ca" => config.upstream_ca = Some(PathBuf::from(value)),
+                "health_check_interval" => {
+                    config.health_check_interval = match parse_number(value, line_number)? {
+                        0 => return Err(config_error(line_number, "health_check_interval must be at least 1 second")),
+                        seconds => seconds,
+                    }
+                }
+                "qname_minimisation" => {
+                    config.qname_minimisation =
+                        value.parse().map_err(|e: String| config_error(line_number, &e))?
+                }
+                "use_0x20" => config.use_0x20 = parse_bool(value, line_number)?,
+                "max_waiters_per_query" => {
+                    config.max_waiters_per_query = parse_number(value, line_number)?
+                }
+                _ => return Err(config_error(line_number, &format!("unknown setting `{}`", key))),
+            }
+        }
+
+        if config.cache_acl.is_empty() {
+            config.cache_acl = config.recursion_acl.clone();
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn listed(acl: &[(String, Vec<AclEntry>)], zone: &str, client: IpAddr, key: Option<&str>) -> bool {
+    acl.iter()
+        .filter(|(allowed_zone, _)| allowed_zone == zone)
+        .flat_map(|(_, entries)| entries)
+        .any(|entry| match entry {
+            AclEntry::Address(address) => *address == client,
+            AclEntry::Key(name) => key == Some(name.as_str()),
+        })
+}
+
+fn parse_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
+
+/// `corp.example 10.0.0.53, 10.0.1.53:5353`
+fn parse_zone_servers<T>(
+    value: &str,
+    line_number: usize,
+    parse_server: fn(&str) -> Result<T, String>,
+) -> Result<(String, Vec<T>), DnsResolverError> {
+    let (zone, servers) = value
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| config_error(line_number, "expected `<domain> <server>[, <server>...]`"))?;
+    let servers = parse_list(servers)
+        .map(parse_server)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| config_error(line_number, &e))?;
+    if servers.is_empty() {
+        return Err(config_error(line_number, "expected at least one server"));
+    }
+    Ok((zone.trim_end_matches('.').to_lowercase(), servers))
+}
+
+/// The view named by the first word of `value`, and the rest of the value.
+fn view_setting<'a, 'b>(
+    views: &'a mut [ViewConfig],
+    value: &'b str,
+    line_number: usize,
+) -> Result<(&'a mut ViewConfig, &'b str), DnsResolverError> {
+    let (name, rest) = value
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| config_error(line_number, "expected `<view> <setting>`"))?;
+    let view = views
+        .iter_mut()
+        .find(|view| view.name == name)
+        .ok_or_else(|| config_error(line_number, &format!("view {} is not defined above", name)))?;
+    Ok((view, rest.trim()))
+}
+
+/// `10.0.0.0/8, 2001:db8::/32`
+fn parse_ranges(value: &str, line_number: usize) -> Result<Vec<IpPrefix>, DnsResolverError> {
+    parse_list(value)
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .map_err(|e: String| config_error(line_number, &e))
+}
+
+/// `example.com /etc/dns/example.com.zone`
+fn parse_zone_file(value: &str, line_number: usize) -> Result<(String, PathBuf), DnsResolverError> {
+    let (zone, path) = value
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| config_error(line_number, "expected `<domain> <zone file>`"))?;
+    Ok((zone.trim_end_matches('.').to_lowercase(), PathBuf::from(path.trim())))
+}
+
+/// `example.com 192.0.2.2, 2001:db8::2, key xfr-key`
+fn parse_zone_clients(value: &str, line_number: usize) -> Result<(String, Vec<AclEntry>), DnsResolverError> {
+    let (zone, clients) = value.split_once(char::is_whitespace).ok_or_else(|| {
+        config_error(line_number, "expected `<domain> <address or key name>[, <address or key name>...]`")
+    })?;
+    let clients = parse_list(clients)
+        .map(|client| match client.strip_prefix("key ") {
+            Some(name) => Ok(AclEntry::Key(name.trim().trim_end_matches('.').to_lowercase())),
+            None => client
+                .parse()
+                .map(AclEntry::Address)
+                .map_err(|_| config_error(line_number, &format!("invalid address `{}`", client))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((zone.trim_end_matches('.').to_lowercase(), clients))
+}
+
+/// `example.net xfr-key`
+fn parse_zone_key(value: &str, line_number: usize) -> Result<(String, String), DnsResolverError> {
+    let (zone, key) = value
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| config_error(line_number, "expected `<domain> <key name>`"))?;
+    Ok((
+        zone.trim_end_matches('.').to_lowercase(),
+        key.trim().trim_end_matches('.').to_lowercase(),
+    ))
+}
+
+fn parse_bool(value: &str, line_number: usize) -> Result<bool, DnsResolverError> {
+    match value {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(config_error(line_number, &format!("expected true or false, got `{}`", value))),
+    }
+}
+
+fn parse_number<T: FromStr>(value: &str, line_number: usize) -> Result<T, DnsResolverError> {
+    value
+        .parse()
+        .map_err(|_| config_error(line_number, &format!("expected a number, got `{}`", value)))
+}
+
+fn parse_prefix_length(value: &str, max: u8, line_number: usize) -> Result<u8, DnsResolverError> {
+    match parse_number(value, line_number)? {
+        length if length <= max => Ok(length),
+        _ => Err(config_error(line_number, &format!("prefix length {} is longer than {}", value, max))),
+    }
+}
+
+fn config_error(line_number: usize, message: &str) -> DnsResolverError {
+    DnsResolverError::ConfigError(format!("line {}: {}", line_number, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DnsAcl::AclAction;
+
+    #[test]
+    fn cache_acl_defaults_to_the_recursion_acl() {
+        let inside: IpAddr = "10.0.0.1".parse().unwrap();
+        let outside: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let config = Config::parse("recursion_acl = allow 10.0.0.0/8\n").unwrap();
+        assert_eq!(config.cache_acl.check(inside), AclAction::Allow);
+        assert_eq!(config.cache_acl.check(outside), AclAction::Refuse);
+
+        let config = Config::parse("recursion_acl = allow 10.0.0.0/8\ncache_acl = allow 0.0.0.0/0\n").unwrap();
+        assert_eq!(config.cache_acl.check(outside), AclAction::Allow);
+
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.cache_acl.check(outside), AclAction::Allow);
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use siphasher::sip::SipHasher24;
+
+use crate::Dnspacket::{DnsPacket, DnsRecord, ResultCode};
+
+const OPTION_COOKIE: u16 = 10;
+const CLIENT_COOKIE_LEN: usize = 8;
+const SERVER_COOKIE_LEN: usize = 16;
+const MAX_SERVER_COOKIE_LEN: usize = 32;
+const VERSION: u8 = 1;
+// Server cookies are good for an hour after they are issued, and from five
+// minutes before, for servers whose clocks run ahead (RFC 9018 section 4.3).
+const COOKIE_LIFETIME: u32 = 3600;
+const CLOCK_SKEW: u32 = 300;
+// BADCOOKIE is extended RCODE 23: 1 in the OPT record's upper flag bits, 7 in the header.
+const BADCOOKIE_HIGH: u32 = 1;
+const BADCOOKIE_LOW: u8 = 7;
+const EDNS_PAYLOAD_SIZE: u16 = 1232;
+const MAX_UPSTREAMS: usize = 10_000;
+
+/// What a request's COOKIE option amounts to (RFC 7873 section 5.2).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CookieCheck {
+    Absent,
+    /// A length no cookie can have; answered with FORMERR.
+    Malformed,
+    /// A client cookie alone, or with a server cookie we did not issue to
+    /// this client or that has expired.
+    ClientOnly([u8; CLIENT_COOKIE_LEN]),
+    /// A server cookie we issued to this client within the last hour.
+    Valid([u8; CLIENT_COOKIE_LEN]),
+}
+
+/// Issues and checks server cookies in the format of RFC 9018, so servers
+/// sharing the secret accept each other's.
+pub struct ServerCookies {
+    secret: [u8; 16],
+}
+
+impl ServerCookies {
+    pub fn new(secret: [u8; 16]) -> ServerCookies {
+        ServerCookies { secret }
+    }
+
+    pub fn check(&self, request: &DnsPacket, client: IpAddr) -> CookieCheck {
+        self.check_at(request, client, unix_time())
+    }
+
+    fn check_at(&self, request: &DnsPacket, client: IpAddr, now: u32) -> CookieCheck {
+        let Some(option) = request.edns_option(OPTION_COOKIE) else {
+            return CookieCheck::Absent;
+        };
+        let len = option.len();
+        if len != CLIENT_COOKIE_LEN && !(CLIENT_COOKIE_LEN + 8..=CLIENT_COOKIE_LEN + MAX_SERVER_COOKIE_LEN).contains(&len) {
+            return CookieCheck::Malformed;
+        }
+        let (client_cookie, server_cookie) = option.split_at(CLIENT_COOKIE_LEN);
+        let client_cookie: [u8; CLIENT_COOKIE_LEN] = client_cookie.try_into().unwrap();
+        let valid = server_cookie.len() == SERVER_COOKIE_LEN && server_cookie[0] == VERSION && {
+            let issued = u32::from_be_bytes(server_cookie[4..8].try_into().unwrap());
+            let fresh = now.wrapping_sub(issued) <= COOKIE_LIFETIME || issued.wrapping_sub(now) <= CLOCK_SKEW;
+            fresh && server_cookie[8..] == self.hash(&client_cookie, &server_cookie[..8], client)
+        };
+        match valid {
+            true => CookieCheck::Valid(client_cookie),
+            false => CookieCheck::ClientOnly(client_cookie),
+        }
+    }
+
+    /// Gives the client a fresh server cookie in the response, if it sent a
+    /// client cookie to go with it.
+    pub fn attach(&self, check: &CookieCheck, response: &mut DnsPacket, client: IpAddr) {
+        if let CookieCheck::ClientOnly(client_cookie) | CookieCheck::Valid(client_cookie) = check {
+            let mut cookie = client_cookie.to_vec();
+            cookie.extend(self.server_cookie(client_cookie, client, unix_time()));
+            set_cookie(response, &cookie);
+        }
+    }
+
+    fn server_cookie(&self, client_cookie: &[u8], client: IpAddr, now: u32) -> Vec<u8> {
+        let mut cookie = vec![VERSION, 0, 0, 0];
+        cookie.extend(now.to_be_bytes());
+        let hash = self.hash(client_cookie, &cookie, client);
+        cookie.extend(hash);
+        cookie
+    }
+
+    /// SipHash-2-4 over the client cookie, the version, reserved and
+    /// timestamp fields, and the client's address.
+    fn hash(&self, client_cookie: &[u8], fields: &[u8], client: IpAddr) -> [u8; 8] {
+        let mut hasher = SipHasher24::new_with_key(&self.secret);
+        hasher.write(client_cookie);
+        hasher.write(fields);
+        match client {
+            IpAddr::V4(address) => hasher.write(&address.octets()),
+            IpAddr::V6(address) => match address.to_ipv4_mapped() {
+                Some(address) => hasher.write(&address.octets()),
+                None => hasher.write(&address.octets()),
+            },
+        }
+        hasher.finish().to_le_bytes()
+    }
+}
+
+/// Our client cookie for each upstream, and the server cookie it last gave us.
+pub struct ClientCookies {
+    secret: [u8; 16],
+    server_cookies: Mutex<HashMap<IpAddr, Vec<u8>>>,
+}
+
+impl ClientCookies {
+    pub fn new(secret: [u8; 16]) -> ClientCookies {
+        ClientCookies {
+            secret,
+            server_cookies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Puts the cookies for `server` into a query's OPT record.
+    pub fn attach(&self, query: &mut DnsPacket, server: IpAddr) {
+        let mut cookie = self.client_cookie(server).to_vec();
+        if let Some(server_cookie) = self.server_cookies.lock().unwrap().get(&server) {
+            cookie.extend(server_cookie);
+        }
+        query.set_edns_option(OPTION_COOKIE, &cookie);
+    }
+
+    /// Whether a response from `server` may be used: one with a cookie must
+    /// echo our client cookie (RFC 7873 section 5.3). The server cookie it
+    /// carries is kept for the next query.
+    pub fn accept(&self, response: &DnsPacket, server: IpAddr) -> bool {
+        let Some(option) = response.edns_option(OPTION_COOKIE) else {
+            return true;
+        };
+        if !(CLIENT_COOKIE_LEN + 8..=CLIENT_COOKIE_LEN + MAX_SERVER_COOKIE_LEN).contains(&option.len()) {
+            return false;
+        }
+        let (client_cookie, server_cookie) = option.split_at(CLIENT_COOKIE_LEN);
+        if client_cookie != self.client_cookie(server) {
+            return false;
+        }
+        let mut server_cookies = self.server_cookies.lock().unwrap();
+        if server_cookies.len() >= MAX_UPSTREAMS && !server_cookies.contains_key(&server) {
+            server_cookies.clear();
+        }
+        server_cookies.insert(server, server_cookie.to_vec());
+        true
+    }
+
+    /// A cookie per server, so one server cannot track us by the cookie
+    /// another was sent (RFC 7873 section 4.1).
+    fn client_cookie(&self, server: IpAddr) -> [u8; CLIENT_COOKIE_LEN] {
+        let mut hasher = SipHasher24::new_with_key(&self.secret);
+        match server {
+            IpAddr::V4(address) => hasher.write(&address.octets()),
+            IpAddr::V6(address) => hasher.write(&address.octets()),
+        }
+        hasher.finish().to_le_bytes()
+    }
+}
+
+pub fn is_bad_cookie(response: &DnsPacket) -> bool {
+    response.header.rescode == ResultCode::from_num(BADCOOKIE_LOW)
+        && response.resources.iter().any(|record| match record {
+            DnsRecord::OPT { flags, .. } => flags >> 24 == BADCOOKIE_HIGH,
+            _ => false,
+        })
+}
+
+/// Turns the response into BADCOOKIE; `ServerCookies::attach` then supplies
+/// the cookie the client should retry with.
+pub fn set_bad_cookie(response: &mut DnsPacket) {
+    response.answers.clear();
+    response.authorities.clear();
+    response.resources.clear();
+    response.header.rescode = ResultCode::from_num(BADCOOKIE_LOW);
+    response.resources.push(DnsRecord::OPT {
+        packet_len: EDNS_PAYLOAD_SIZE,
+        flags: BADCOOKIE_HIGH << 24,
+        data: Vec::new(),
+    });
+}
+
+/// A 16 byte secret written as 32 hex digits.
+pub fn parse_secret(value: &str) -> Result<[u8; 16], String> {
+    let invalid = || format!("expected 32 hex digits, got `{}`", value);
+    if value.len() != 32 || !value.is_ascii() {
+        return Err(invalid());
+    }
+    let mut secret = [0u8; 16];
+    for (i, byte) in secret.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(secret)
+}
+
+fn set_cookie(response: &mut DnsPacket, cookie: &[u8]) {
+    if !response.resources.iter().any(|record| matches!(record, DnsRecord::OPT { .. })) {
+        response.resources.push(DnsRecord::OPT {
+            packet_len: EDNS_PAYLOAD_SIZE,
+            flags: 0,
+            data: Vec::new(),
+        });
+    }
+    response.set_edns_option(OPTION_COOKIE, cookie);
+}
+
+fn unix_time() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_cookie(cookie: &[u8]) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: EDNS_PAYLOAD_SIZE,
+            flags: 0,
+            data: Vec::new(),
+        });
+        packet.set_edns_option(OPTION_COOKIE, cookie);
+        packet
+    }
+
+    fn hex(value: &str) -> Vec<u8> {
+        (0..value.len()).step_by(2).map(|i| u8::from_str_radix(&value[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn issues_and_checks_server_cookies() {
+        // RFC 9018 appendix A.1.
+        let cookies = ServerCookies::new(parse_secret("e5e973e5a6b2a43f48e7dc849e37bfcf").unwrap());
+        let client: IpAddr = "198.51.100.100".parse().unwrap();
+        let client_cookie = hex("2464c4abcf10c957");
+        let issued = 1_559_731_985;
+        let server_cookie = cookies.server_cookie(&client_cookie, client, issued);
+        assert_eq!(server_cookie, hex("010000005cf79f111f8130c3eee29480"));
+
+        let mut cookie = client_cookie.clone();
+        cookie.extend(&server_cookie);
+        let request = with_cookie(&cookie);
+        let client_cookie: [u8; 8] = client_cookie.try_into().unwrap();
+        assert_eq!(cookies.check_at(&request, client, issued + 1800), CookieCheck::Valid(client_cookie));
+        assert_eq!(cookies.check_at(&request, client, issued - 60), CookieCheck::Valid(client_cookie));
+        // Expired, or handed out to someone else.
+        assert_eq!(cookies.check_at(&request, client, issued + 7200), CookieCheck::ClientOnly(client_cookie));
+        let other = "198.51.100.101".parse().unwrap();
+        assert_eq!(cookies.check_at(&request, other, issued), CookieCheck::ClientOnly(client_cookie));
+
+        assert_eq!(cookies.check_at(&with_cookie(&client_cookie), client, issued), CookieCheck::ClientOnly(client_cookie));
+        assert_eq!(cookies.check_at(&with_cookie(&[1, 2, 3]), client, issued), CookieCheck::Malformed);
+        assert_eq!(cookies.check_at(&DnsPacket::new(), client, issued), CookieCheck::Absent);
+    }
+
+    #[test]
+    fn remembers_server_cookies_for_matching_responses() {
+        let cookies = ClientCookies::new([7; 16]);
+        let server: IpAddr = "192.0.2.53".parse().unwrap();
+        let mut query = with_cookie(&[]);
+        cookies.attach(&mut query, server);
+        let sent = query.edns_option(OPTION_COOKIE).unwrap().to_vec();
+        assert_eq!(sent.len(), CLIENT_COOKIE_LEN);
+
+        let mut reply = sent.clone();
+        reply.extend([9; 16]);
+        assert!(cookies.accept(&with_cookie(&reply), server));
+        cookies.attach(&mut query, server);
+        assert_eq!(query.edns_option(OPTION_COOKIE), Some(&reply[..]));
+
+        // A reply that does not echo the client cookie is not from the server.
+        let mut forged = vec![0; CLIENT_COOKIE_LEN];
+        forged.extend([9; 16]);
+        assert!(!cookies.accept(&with_cookie(&forged), server));
+        assert!(cookies.accept(&DnsPacket::new(), server));
+
+        let mut response = DnsPacket::new();
+        set_bad_cookie(&mut response);
+        assert!(is_bad_cookie(&response));
+        assert!(parse_secret("e5e973e5").is_err());
+    }
+}
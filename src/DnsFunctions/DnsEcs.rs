@@ -0,0 +1,218 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::DnsBailiwick::is_subdomain;
+use crate::Dnspacket::{edns_option, DnsPacket};
+
+const OPTION_CLIENT_SUBNET: u16 = 8;
+const FAMILY_IPV4: u16 = 1;
+const FAMILY_IPV6: u16 = 2;
+
+/// Which upstream queries carry the client's subnet, and how much of it.
+#[derive(Clone, Debug)]
+pub struct EcsPolicy {
+    /// Names at or below these zones are sent with a subnet; none turns ECS off.
+    pub zones: Vec<String>,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+impl Default for EcsPolicy {
+    fn default() -> EcsPolicy {
+        // The longest prefixes RFC 7871 section 11.1 recommends revealing.
+        EcsPolicy {
+            zones: Vec::new(),
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+        }
+    }
+}
+
+impl EcsPolicy {
+    /// The subnet to send on behalf of `client`: the one it sent itself, if
+    /// any, else its own address, cut to the configured prefix length. None
+    /// when ECS is off, or the client asked for its address to be kept private
+    /// with a zero-length source prefix.
+    pub fn client_subnet(&self, client: IpAddr, sent: Option<ClientSubnet>) -> Option<ClientSubnet> {
+        if self.zones.is_empty() {
+            return None;
+        }
+        let (address, source_prefix) = match sent {
+            Some(sent) if sent.source_prefix == 0 => return None,
+            Some(sent) => (sent.address, sent.source_prefix),
+            None => {
+                let address = match client {
+                    IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(client, IpAddr::V4),
+                    IpAddr::V4(_) => client,
+                };
+                (address, u8::MAX)
+            }
+        };
+        let source_prefix = source_prefix.min(match address {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        });
+        Some(ClientSubnet {
+            address: truncate(address, source_prefix),
+            source_prefix,
+            scope_prefix: 0,
+        })
+    }
+
+    pub fn covers(&self, name: &str) -> bool {
+        self.zones.iter().any(|zone| is_subdomain(name, zone))
+    }
+}
+
+/// An EDNS Client Subnet option (RFC 7871 section 6).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ClientSubnet {
+    pub address: IpAddr,
+    pub source_prefix: u8,
+    pub scope_prefix: u8,
+}
+
+impl ClientSubnet {
+    /// The option in the packet's OPT record, if it carries a well-formed one.
+    pub fn from_packet(packet: &DnsPacket) -> Option<ClientSubnet> {
+        packet.edns_option(OPTION_CLIENT_SUBNET).and_then(ClientSubnet::parse)
+    }
+
+    /// The option in the wire format OPT RDATA holds.
+    pub fn option(&self) -> Vec<u8> {
+        edns_option(OPTION_CLIENT_SUBNET, &self.value())
+    }
+
+    /// Puts the option into the packet's OPT record in place of any it held.
+    /// Packets without EDNS are left alone.
+    pub fn attach(&self, packet: &mut DnsPacket) {
+        packet.set_edns_option(OPTION_CLIENT_SUBNET, &self.value());
+    }
+
+    fn value(&self) -> Vec<u8> {
+        let (family, octets) = match self.address {
+            IpAddr::V4(address) => (FAMILY_IPV4, address.octets().to_vec()),
+            IpAddr::V6(address) => (FAMILY_IPV6, address.octets().to_vec()),
+        };
+        let address = &octets[..usize::from(self.source_prefix).div_ceil(8)];
+        let mut value = Vec::with_capacity(4 + address.len());
+        value.extend(family.to_be_bytes());
+        value.push(self.source_prefix);
+        value.push(self.scope_prefix);
+        value.extend(address);
+        value
+    }
+
+    /// Whether a response's option answers the one sent: the same family,
+    /// source prefix and address (RFC 7871 section 7.3).
+    pub fn answers(&self, sent: &ClientSubnet) -> bool {
+        self.address == sent.address && self.source_prefix == sent.source_prefix
+    }
+
+    fn parse(value: &[u8]) -> Option<ClientSubnet> {
+        let [family_high, family_low, source_prefix, scope_prefix, address @ ..] = value else {
+            return None;
+        };
+        let family = u16::from_be_bytes([*family_high, *family_low]);
+        let width = match family {
+            FAMILY_IPV4 => 4,
+            FAMILY_IPV6 => 16,
+            _ => return None,
+        };
+        // Exactly the bytes the source prefix covers are sent.
+        if *source_prefix as usize > width * 8 || address.len() != (*source_prefix as usize).div_ceil(8) {
+            return None;
+        }
+        let mut octets = [0u8; 16];
+        octets[..address.len()].copy_from_slice(address);
+        let address = match family {
+            FAMILY_IPV4 => IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])),
+            _ => IpAddr::V6(Ipv6Addr::from(octets)),
+        };
+        Some(ClientSubnet {
+            address,
+            source_prefix: *source_prefix,
+            scope_prefix: *scope_prefix,
+        })
+    }
+}
+
+/// `address` with the bits past the first `len` cleared.
+fn truncate(address: IpAddr, len: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(address) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(len.min(32))).unwrap_or(0);
+            IpAddr::V4((u32::from(address) & mask).into())
+        }
+        IpAddr::V6(address) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(len.min(128))).unwrap_or(0);
+            IpAddr::V6((u128::from(address) & mask).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Dnspacket::{edns_options, DnsRecord};
+
+    fn with_options(data: Vec<u8>) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 1232,
+            flags: 0,
+            data,
+        });
+        packet
+    }
+
+    #[test]
+    fn reads_client_subnet_options() {
+        // A cookie option first, then ECS 192.0.2.0/24.
+        let mut data = vec![0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8];
+        data.extend([0, 8, 0, 7, 0, 1, 24, 0, 192, 0, 2]);
+        let subnet = ClientSubnet::from_packet(&with_options(data)).unwrap();
+        assert_eq!(subnet.address, "192.0.2.0".parse::<IpAddr>().unwrap());
+        assert_eq!((subnet.source_prefix, subnet.scope_prefix), (24, 0));
+
+        let v6 = ClientSubnet::from_packet(&with_options(vec![0, 8, 0, 8, 0, 2, 32, 0, 0x20, 0x01, 0x0d, 0xb8]));
+        assert_eq!(v6.unwrap().address, "2001:db8::".parse::<IpAddr>().unwrap());
+
+        // The address must be exactly as long as the source prefix needs.
+        assert!(ClientSubnet::from_packet(&with_options(vec![0, 8, 0, 8, 0, 1, 24, 0, 192, 0, 2, 1])).is_none());
+        assert!(ClientSubnet::from_packet(&with_options(vec![0, 8, 0, 9, 0, 1])).is_none());
+        assert!(ClientSubnet::from_packet(&DnsPacket::new()).is_none());
+    }
+
+    #[test]
+    fn sends_truncated_client_prefixes() {
+        let policy = EcsPolicy {
+            zones: vec!["cdn.example".to_string()],
+            ..EcsPolicy::default()
+        };
+        assert!(policy.covers("img.cdn.example") && !policy.covers("example"));
+
+        let subnet = policy.client_subnet("::ffff:198.51.100.77".parse().unwrap(), None).unwrap();
+        assert_eq!(subnet.address, "198.51.100.0".parse::<IpAddr>().unwrap());
+        assert_eq!(subnet.source_prefix, 24);
+        let sent = ClientSubnet {
+            address: "2001:db8:1:2::".parse().unwrap(),
+            source_prefix: 64,
+            scope_prefix: 0,
+        };
+        let subnet = policy.client_subnet("192.0.2.1".parse().unwrap(), Some(sent)).unwrap();
+        assert_eq!(subnet.address, "2001:db8:1::".parse::<IpAddr>().unwrap());
+        assert_eq!(subnet.source_prefix, 56);
+        let private = ClientSubnet { source_prefix: 0, ..sent };
+        assert!(policy.client_subnet("192.0.2.1".parse().unwrap(), Some(private)).is_none());
+        assert!(EcsPolicy::default().client_subnet("192.0.2.1".parse().unwrap(), None).is_none());
+
+        // Written into an OPT record next to the options already there, it reads back the same.
+        let mut packet = with_options(vec![0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8]);
+        subnet.attach(&mut packet);
+        assert_eq!(ClientSubnet::from_packet(&packet), Some(subnet));
+        let DnsRecord::OPT { data, .. } = &packet.resources[0] else {
+            unreachable!();
+        };
+        assert_eq!(edns_options(data).count(), 2);
+    }
+}
@@ -8,6 +8,12 @@ pub enum DnsResolverError {
     NoQuestionFound,
     NoNameserverFound,
     ResolutionFailed,
+    Timeout,
+    CaseMismatch,
+    TooManyWaiters,
+    ConfigError(String),
+    ZoneFileError(String),
+    Dropped,
 }
 
 impl fmt::Display for DnsResolverError {
@@ -23,6 +29,18 @@ impl fmt::Display for DnsResolverError {
                 write!(f, "Unable to find a valid nameserver"),
             DnsResolverError::ResolutionFailed => 
                 write!(f, "DNS resolution failed"),
+            DnsResolverError::Timeout => 
+                write!(f, "Timed out waiting for a nameserver"),
+            DnsResolverError::CaseMismatch => 
+                write!(f, "Response did not echo the query name's case"),
+            DnsResolverError::TooManyWaiters => 
+                write!(f, "Too many clients waiting on the same query"),
+            DnsResolverError::ConfigError(details) => 
+                write!(f, "Configuration error: {}", details),
+            DnsResolverError::ZoneFileError(details) => 
+                write!(f, "Zone file error: {}", details),
+            DnsResolverError::Dropped => 
+                write!(f, "Request dropped without an answer"),
         }
     }
 }
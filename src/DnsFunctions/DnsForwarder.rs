@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+const FAILURES_BEFORE_DOWN: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForwardStrategy {
+    RoundRobin,
+    Fastest,
+    Ordered,
+}
+
+impl FromStr for ForwardStrategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "round_robin" => Ok(ForwardStrategy::RoundRobin),
+            "fastest" => Ok(ForwardStrategy::Fastest),
+            "ordered" => Ok(ForwardStrategy::Ordered),
+            _ => Err(format!("expected round_robin, fastest or ordered, got `{}`", value)),
+        }
+    }
+}
+
+/// What a forwarder that cannot be reached over its encrypted transport
+/// is queried with instead (RFC 8310 section 5).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamPrivacy {
+    /// Nothing; the forwarder counts as failed.
+    Strict,
+    /// Cleartext UDP to port 53 of the same address.
+    Opportunistic,
+}
+
+impl FromStr for UpstreamPrivacy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "strict" => Ok(UpstreamPrivacy::Strict),
+            "opportunistic" => Ok(UpstreamPrivacy::Opportunistic),
+            _ => Err(format!("expected strict or opportunistic, got `{}`", value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Udp,
+    /// DNS over TLS (RFC 7858).
+    Tls,
+    /// DNS over HTTPS (RFC 8484), POSTing to this path.
+    Https(String),
+}
+
+/// A server queries are forwarded to, and how to reach it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Upstream {
+    pub addr: SocketAddr,
+    pub transport: Transport,
+    /// The name its certificate has to be valid for, also sent as SNI.
+    /// Without one the certificate has to be valid for the address.
+    pub name: Option<String>,
+    /// SHA-256 of a SubjectPublicKeyInfo in its certificate chain; when set
+    /// the key is checked instead of the chain (RFC 7858 section 4.2).
+    pub pin: Option<[u8; 32]>,
+}
+
+impl Upstream {
+    pub fn udp(addr: SocketAddr) -> Upstream {
+        Upstream {
+            addr,
+            transport: Transport::Udp,
+            name: None,
+            pin: None,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.transport != Transport::Udp
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.name.as_ref().map(|name| format!("{}@", name)).unwrap_or_default();
+        match &self.transport {
+            Transport::Udp => write!(f, "{}", self.addr),
+            Transport::Tls => write!(f, "tls://{}{}", name, self.addr),
+            Transport::Https(path) => write!(f, "https://{}{}{}", name, self.addr, path),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct UpstreamHealth {
+    healthy: bool,
+    failures: u32,
+    srtt: Option<u32>,
+}
+
+//<-------------------------------- Upstream pool -------------------------------------->
+
+/// A set of recursive servers that queries are forwarded to with RD=1.
+pub struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    strategy: ForwardStrategy,
+    next: AtomicUsize,
+    health: Mutex<HashMap<Upstream, UpstreamHealth>>,
+}
+
+impl UpstreamPool {
+    pub fn new(upstreams: Vec<Upstream>, strategy: ForwardStrategy) -> UpstreamPool {
+        let health = upstreams
+            .iter()
+            .map(|upstream| {
+                let health = UpstreamHealth {
+                    healthy: true,
+                    failures: 0,
+                    srtt: None,
+                };
+                (upstream.clone(), health)
+            })
+            .collect();
+        UpstreamPool {
+            upstreams,
+            strategy,
+            next: AtomicUsize::new(0),
+            health: Mutex::new(health),
+        }
+    }
+
+    pub fn upstreams(&self) -> &[Upstream] {
+        &self.upstreams
+    }
+
+    /// Every upstream in the order it should be tried for one query: healthy
+    /// servers arranged by the strategy, then the ones currently marked down as
+    /// a last resort.
+    pub fn candidates(&self) -> Vec<Upstream> {
+        let mut ordered = self.upstreams.clone();
+        match self.strategy {
+            ForwardStrategy::Ordered => (),
+            ForwardStrategy::RoundRobin => {
+                if !ordered.is_empty() {
+                    let start = self.next.fetch_add(1, Ordering::Relaxed) % ordered.len();
+                    ordered.rotate_left(start);
+                }
+            }
+            ForwardStrategy::Fastest => {
+                let health = self.health.lock().unwrap();
+                // Unmeasured servers sort first so they get a measurement.
+                ordered.sort_by_key(|upstream| health.get(upstream).and_then(|h| h.srtt).unwrap_or(0));
+            }
+        }
+
+        let health = self.health.lock().unwrap();
+        let (mut up, down): (Vec<Upstream>, Vec<Upstream>) = ordered
+            .into_iter()
+            .partition(|upstream| health.get(upstream).map(|h| h.healthy).unwrap_or(true));
+        up.extend(down);
+        up
+    }
+
+    pub fn record_success(&self, upstream: &Upstream, rtt: Duration) {
+        let sample = rtt.as_millis() as u32;
+        let mut health = self.health.lock().unwrap();
+        if let Some(entry) = health.get_mut(upstream) {
+            entry.healthy = true;
+            entry.failures = 0;
+            entry.srtt = Some(match entry.srtt {
+                Some(srtt) => (7 * srtt + sample) / 8,
+                None => sample,
+            });
+        }
+    }
+
+    pub fn mark_down(&self, upstream: &Upstream) {
+        let mut health = self.health.lock().unwrap();
+        if let Some(entry) = health.get_mut(upstream) {
+            if entry.healthy {
+                eprintln!("Upstream {} failed its health check", upstream);
+            }
+            entry.healthy = false;
+        }
+    }
+
+    pub fn record_failure(&self, upstream: &Upstream) {
+        let mut health = self.health.lock().unwrap();
+        if let Some(entry) = health.get_mut(upstream) {
+            entry.failures += 1;
+            if entry.failures >= FAILURES_BEFORE_DOWN && entry.healthy {
+                eprintln!("Upstream {} marked down", upstream);
+                entry.healthy = false;
+            }
+        }
+    }
+}
+
+/// Parses `10.0.0.1`, `10.0.0.1:5353`, `::1` or `[::1]:5353`, defaulting to port 53.
+pub fn parse_upstream(value: &str) -> Result<SocketAddr, String> {
+    parse_address(value, 53)
+}
+
+/// Parses a forwarder: an address as `parse_upstream` takes it for plain
+/// DNS, `tls://[<name>@]<address>` for DNS over TLS on port 853 by default,
+/// or `https://[<name>@]<address>[/<path>]` for DNS over HTTPS on port 443 at
+/// /dns-query by default. `<name>` is what the certificate has to be valid
+/// for; a `;pin-sha256=<base64>` suffix pins a key instead.
+pub fn parse_forwarder(value: &str) -> Result<Upstream, String> {
+    let Some((scheme, rest)) = value.split_once("://") else {
+        return parse_upstream(value).map(Upstream::udp);
+    };
+    let mut parts = rest.split(';');
+    let location = parts.next().unwrap_or("");
+    let (name, location) = match location.split_once('@') {
+        Some((name, location)) => (Some(name.to_string()), location),
+        None => (None, location),
+    };
+    let (authority, path) = location.split_at(location.find('/').unwrap_or(location.len()));
+    let (transport, port) = match scheme {
+        "tls" if path.is_empty() => (Transport::Tls, 853),
+        "https" if path.is_empty() => (Transport::Https("/dns-query".to_string()), 443),
+        "https" => (Transport::Https(path.to_string()), 443),
+        _ => return Err(format!("invalid forwarder `{}`", value)),
+    };
+    let mut upstream = Upstream {
+        addr: parse_address(authority, port)?,
+        transport,
+        name,
+        pin: None,
+    };
+    for part in parts {
+        let pin = part.strip_prefix("pin-sha256=").ok_or_else(|| format!("invalid forwarder `{}`", value))?;
+        let pin = STANDARD.decode(pin).ok().and_then(|pin| <[u8; 32]>::try_from(pin).ok());
+        upstream.pin = Some(pin.ok_or_else(|| format!("invalid SPKI pin in `{}`", value))?);
+    }
+    Ok(upstream)
+}
+
+fn parse_address(value: &str, default_port: u16) -> Result<SocketAddr, String> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    value
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, default_port))
+        .map_err(|_| format!("invalid upstream address `{}`", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_forwarders() {
+        assert_eq!(parse_forwarder("10.0.0.1").unwrap(), Upstream::udp("10.0.0.1:53".parse().unwrap()));
+
+        let tls = parse_forwarder("tls://dns.quad9.net@9.9.9.9").unwrap();
+        assert_eq!(tls.addr, "9.9.9.9:853".parse().unwrap());
+        assert_eq!(tls.transport, Transport::Tls);
+        assert_eq!(tls.name.as_deref(), Some("dns.quad9.net"));
+
+        let pin = "pin-sha256=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+        let https = parse_forwarder(&format!("https://[2620:fe::fe]:8443;{}", pin)).unwrap();
+        assert_eq!(https.addr, "[2620:fe::fe]:8443".parse().unwrap());
+        assert_eq!(https.transport, Transport::Https("/dns-query".to_string()));
+        assert_eq!(https.name, None);
+        assert!(https.pin.is_some());
+
+        assert!(parse_forwarder("tls://9.9.9.9/dns-query").is_err());
+        assert!(parse_forwarder("quic://9.9.9.9").is_err());
+        assert!(parse_forwarder("tls://9.9.9.9;pin-sha256=AAAA").is_err());
+        assert!(parse_forwarder("tls://9.9.9.9;port=853").is_err());
+    }
+
+    fn pool(strategy: ForwardStrategy) -> (UpstreamPool, [Upstream; 3]) {
+        let upstreams = ["10.0.0.1", "10.0.0.2", "10.0.0.3"].map(|addr| parse_forwarder(addr).unwrap());
+        (UpstreamPool::new(upstreams.to_vec(), strategy), upstreams)
+    }
+
+    #[test]
+    fn orders_candidates_by_strategy() {
+        let (ordered, [a, b, c]) = pool(ForwardStrategy::Ordered);
+        assert_eq!(ordered.candidates(), [a.clone(), b.clone(), c.clone()]);
+        assert_eq!(ordered.candidates(), [a.clone(), b.clone(), c.clone()]);
+
+        let (round_robin, _) = pool(ForwardStrategy::RoundRobin);
+        assert_eq!(round_robin.candidates(), [a.clone(), b.clone(), c.clone()]);
+        assert_eq!(round_robin.candidates(), [b.clone(), c.clone(), a.clone()]);
+        assert_eq!(round_robin.candidates(), [c.clone(), a.clone(), b.clone()]);
+
+        // Unmeasured servers first, then the quickest.
+        let (fastest, _) = pool(ForwardStrategy::Fastest);
+        fastest.record_success(&a, Duration::from_millis(50));
+        fastest.record_success(&b, Duration::from_millis(10));
+        assert_eq!(fastest.candidates(), [c.clone(), b.clone(), a.clone()]);
+        fastest.record_success(&c, Duration::from_millis(30));
+        assert_eq!(fastest.candidates(), [b, c, a]);
+    }
+
+    #[test]
+    fn tries_upstreams_that_are_down_last() {
+        let (pool, [a, b, c]) = pool(ForwardStrategy::Ordered);
+        for _ in 1..FAILURES_BEFORE_DOWN {
+            pool.record_failure(&a);
+        }
+        assert_eq!(pool.candidates(), [a.clone(), b.clone(), c.clone()]);
+        pool.record_failure(&a);
+        assert_eq!(pool.candidates(), [b.clone(), c.clone(), a.clone()]);
+
+        pool.mark_down(&b);
+        assert_eq!(pool.candidates(), [c.clone(), a.clone(), b.clone()]);
+
+        // An answer brings a server back, and resets its failures.
+        pool.record_success(&a, Duration::from_millis(20));
+        pool.record_failure(&a);
+        assert_eq!(pool.candidates(), [a, c, b]);
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::DnsErrors::DnsResolverError;
+use crate::Dnspacket::{DnsPacket, DnsRecord, QueryType};
+
+/// Static answers change whenever their files do, so they are not cached for long.
+pub const STATIC_TTL: u32 = 60;
+
+/// Names pinned by hosts files and `static_record` settings, answered before
+/// any zone or recursion. Reverse names of the listed addresses get PTR
+/// records pointing back at the first name given for them.
+#[derive(Debug, Default)]
+pub struct HostsTable {
+    addresses: HashMap<String, Vec<IpAddr>>,
+    // PTR targets synthesised from addresses, and ones configured explicitly,
+    // which replace them.
+    synthesised: HashMap<String, Vec<String>>,
+    pointers: HashMap<String, Vec<String>>,
+}
+
+impl HostsTable {
+    pub fn new() -> HostsTable {
+        HostsTable::default()
+    }
+
+    /// Adds every `<address> <name> [<alias>...]` line of an `/etc/hosts` style file.
+    pub fn load_hosts_file(&mut self, path: &Path) -> Result<(), DnsResolverError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| DnsResolverError::ConfigError(format!("{}: {}", path.display(), e)))?;
+        self.add_hosts(&contents)
+            .map_err(|e| DnsResolverError::ConfigError(format!("{}: {}", path.display(), e)))
+    }
+
+    fn add_hosts(&mut self, contents: &str) -> Result<(), String> {
+        for (index, line) in contents.lines().enumerate() {
+            let mut fields = line.split('#').next().unwrap_or("").split_whitespace();
+            let Some(address) = fields.next() else {
+                continue;
+            };
+            let address: IpAddr = address
+                .parse()
+                .map_err(|_| format!("line {}: invalid address `{}`", index + 1, address))?;
+            let names: Vec<&str> = fields.collect();
+            if names.is_empty() {
+                return Err(format!("line {}: expected a name after {}", index + 1, address));
+            }
+            for name in names {
+                self.add(address_record(name, address));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds an A, AAAA or PTR record.
+    pub fn add(&mut self, record: DnsRecord) {
+        match record {
+            DnsRecord::A { domain, addr, .. } => self.add_address(&domain, IpAddr::V4(addr)),
+            DnsRecord::AAAA { domain, addr, .. } => self.add_address(&domain, IpAddr::V6(addr)),
+            DnsRecord::PTR { domain, host, .. } => push_unique(&mut self.pointers, normalise(&domain), normalise(&host)),
+            _ => (),
+        }
+    }
+
+    fn add_address(&mut self, name: &str, address: IpAddr) {
+        let name = normalise(name);
+        push_unique(&mut self.synthesised, reverse_name(address), name.clone());
+        let addresses = self.addresses.entry(name).or_default();
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+
+    /// The answer for a name the table knows, None for every other name. A
+    /// listed name has no other data, so other types get an empty answer.
+    pub fn answer(&self, name: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let name = normalise(name);
+        let addresses = self.addresses.get(&name);
+        let pointers = self.pointers.get(&name).or_else(|| self.synthesised.get(&name));
+        if addresses.is_none() && pointers.is_none() {
+            return None;
+        }
+
+        let mut response = DnsPacket::new();
+        response.header.response = true;
+        for address in addresses.into_iter().flatten() {
+            let wanted = match address {
+                IpAddr::V4(_) => QueryType::A,
+                IpAddr::V6(_) => QueryType::AAAA,
+            };
+            if qtype == wanted || qtype == QueryType::ANY {
+                response.answers.push(address_record(&name, *address));
+            }
+        }
+        if qtype == QueryType::PTR || qtype == QueryType::ANY {
+            for host in pointers.into_iter().flatten() {
+                response.answers.push(DnsRecord::PTR {
+                    domain: name.clone(),
+                    host: host.clone(),
+                    ttl: STATIC_TTL,
+                });
+            }
+        }
+        Some(response)
+    }
+}
+
+/// `api.internal A 10.0.0.5`, `api.internal AAAA fd00::5` or
+/// `5.0.0.10.in-addr.arpa PTR api.internal`
+pub fn parse_static_record(value: &str) -> Result<DnsRecord, String> {
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    let [name, rtype, data] = fields[..] else {
+        return Err("expected `<name> <A, AAAA or PTR> <value>`".to_string());
+    };
+    match (rtype.to_uppercase().as_str(), data.parse::<IpAddr>()) {
+        ("A", Ok(address @ IpAddr::V4(_))) | ("AAAA", Ok(address @ IpAddr::V6(_))) => {
+            Ok(address_record(name, address))
+        }
+        ("A", _) => Err(format!("invalid IPv4 address `{}`", data)),
+        ("AAAA", _) => Err(format!("invalid IPv6 address `{}`", data)),
+        ("PTR", _) => Ok(DnsRecord::PTR {
+            domain: normalise(name),
+            host: normalise(data),
+            ttl: STATIC_TTL,
+        }),
+        _ => Err(format!("unsupported record type {}", rtype)),
+    }
+}
+
+/// The name reverse lookups of `address` ask for (RFC 1035 section 3.5, RFC 3596 section 2.5).
+pub fn reverse_name(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => {
+            let [a, b, c, d] = address.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(address) => {
+            let mut name = String::new();
+            for byte in address.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0x0f, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+fn address_record(name: &str, address: IpAddr) -> DnsRecord {
+    let domain = normalise(name);
+    match address {
+        IpAddr::V4(addr) => DnsRecord::A { domain, addr, ttl: STATIC_TTL },
+        IpAddr::V6(addr) => DnsRecord::AAAA { domain, addr, ttl: STATIC_TTL },
+    }
+}
+
+fn push_unique(map: &mut HashMap<String, Vec<String>>, key: String, value: String) {
+    let values = map.entry(key).or_default();
+    if !values.contains(&value) {
+        values.push(value);
+    }
+}
+
+fn normalise(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answers(table: &HostsTable, name: &str, qtype: QueryType) -> Option<Vec<DnsRecord>> {
+        table.answer(name, qtype).map(|response| response.answers)
+    }
+
+    #[test]
+    fn answers_addresses_and_synthesised_pointers_from_hosts_files() {
+        let mut table = HostsTable::new();
+        table
+            .add_hosts("# dev boxes\n10.0.0.5  api.internal api\n\nfd00::5 API.internal. # v6 too\n")
+            .unwrap();
+
+        let a = answers(&table, "Api.Internal.", QueryType::A).unwrap();
+        assert_eq!(a, vec![address_record("api.internal", "10.0.0.5".parse().unwrap())]);
+        assert_eq!(answers(&table, "api.internal", QueryType::AAAA).unwrap().len(), 1);
+        assert_eq!(answers(&table, "api.internal", QueryType::MX), Some(Vec::new()));
+        assert_eq!(answers(&table, "other.internal", QueryType::A), None);
+
+        let ptr = answers(&table, "5.0.0.10.in-addr.arpa", QueryType::PTR).unwrap();
+        let hosts: Vec<&str> = ptr
+            .iter()
+            .map(|record| match record {
+                DnsRecord::PTR { host, .. } => host.as_str(),
+                other => panic!("expected a PTR record, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(hosts, vec!["api.internal", "api"]);
+
+        assert!(table.add_hosts("10.0.0.300 bad").is_err());
+        assert!(table.add_hosts("10.0.0.6").is_err());
+    }
+
+    #[test]
+    fn explicit_pointers_replace_synthesised_ones() {
+        let mut table = HostsTable::new();
+        table.add(parse_static_record("api.internal A 10.0.0.5").unwrap());
+        table.add(parse_static_record("5.0.0.10.in-addr.arpa PTR devbox.internal").unwrap());
+        let ptr = answers(&table, "5.0.0.10.in-addr.arpa", QueryType::PTR).unwrap();
+        assert!(matches!(&ptr[..], [DnsRecord::PTR { host, .. }] if host == "devbox.internal"));
+
+        assert!(parse_static_record("api.internal A fd00::5").is_err());
+        assert!(parse_static_record("api.internal TXT hello").is_err());
+    }
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(reverse_name("192.0.2.1".parse().unwrap()), "1.2.0.192.in-addr.arpa");
+        assert_eq!(
+            reverse_name("2001:db8::567:89ab".parse().unwrap()),
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hyper::{StatusCode, Uri};
+
+use crate::Dnspacket::{DnsPacket, DnsRecord};
+
+/// Where DNS over HTTPS is served (RFC 8484 section 4.1).
+pub const PATH: &str = "/dns-query";
+pub const CONTENT_TYPE: &str = "application/dns-message";
+
+/// The DNS message of a GET request, base64url-encoded in the `dns` parameter.
+pub fn get_message(uri: &Uri) -> Result<Vec<u8>, StatusCode> {
+    let encoded = uri
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .find_map(|parameter| parameter.strip_prefix("dns="))
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    // Padding is not supposed to be sent, but tolerate it.
+    URL_SAFE_NO_PAD
+        .decode(encoded.trim_end_matches('='))
+        .map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+/// How long HTTP caches may keep a response: no longer than its shortest
+/// TTL, which for negative answers is the SOA's (section 5.1). None for
+/// responses without records, such as errors.
+pub fn max_age(response: &DnsPacket) -> Option<u32> {
+    response
+        .answers
+        .iter()
+        .chain(&response.authorities)
+        .filter_map(|record| match record {
+            DnsRecord::SOA { ttl, minimum, .. } => Some((*ttl).min(*minimum)),
+            record => record.ttl(),
+        })
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Dnspacket::BytePacketBuffer;
+
+    #[test]
+    fn decodes_get_requests() {
+        // The example of RFC 8484 section 4.1.1.
+        let uri: Uri = "/dns-query?dns=AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB".parse().unwrap();
+        let mut buffer = BytePacketBuffer::new();
+        let message = get_message(&uri).unwrap();
+        buffer.buf[..message.len()].copy_from_slice(&message);
+        let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(packet.questions[0].name, "www.example.com");
+
+        assert_eq!(get_message(&"/dns-query?ct".parse().unwrap()), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(get_message(&"/dns-query?dns=!!".parse().unwrap()), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn caches_for_the_shortest_ttl() {
+        let mut response = DnsPacket::new();
+        assert_eq!(max_age(&response), None);
+        for ttl in [300, 60] {
+            response.answers.push(DnsRecord::A {
+                domain: "example.com".to_string(),
+                addr: "192.0.2.1".parse().unwrap(),
+                ttl,
+            });
+        }
+        assert_eq!(max_age(&response), Some(60));
+    }
+}
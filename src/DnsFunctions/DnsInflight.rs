@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+use crate::DnsEcs::ClientSubnet;
+use crate::DnsErrors::DnsResolverError;
+use crate::Dnspacket::{DnsPacket, DnsQuestion, QueryType};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct QueryKey {
+    name: String,
+    qtype: QueryType,
+    qclass: u16,
+    // Resolutions for different client subnets may get different answers.
+    subnet: Option<ClientSubnet>,
+}
+
+impl QueryKey {
+    pub fn new(question: &DnsQuestion) -> QueryKey {
+        QueryKey {
+            name: question.name.to_lowercase(),
+            qtype: question.qtype,
+            qclass: question.qclass,
+            subnet: None,
+        }
+    }
+
+    pub fn for_subnet(self, subnet: Option<ClientSubnet>) -> QueryKey {
+        QueryKey { subnet, ..self }
+    }
+}
+
+type Waiters = Vec<oneshot::Sender<Option<DnsPacket>>>;
+
+//<------------------------------ In-flight queries ------------------------------------>
+
+/// Coalesces identical resolutions: the first caller for a key does the work and
+/// everyone who asks while it is running gets a copy of its result.
+pub struct InflightQueries {
+    pending: Mutex<HashMap<QueryKey, Waiters>>,
+    max_waiters: usize,
+}
+
+impl InflightQueries {
+    pub fn new(max_waiters: usize) -> InflightQueries {
+        InflightQueries {
+            pending: Mutex::new(HashMap::new()),
+            max_waiters,
+        }
+    }
+
+    pub async fn run<F>(&self, key: QueryKey, resolve: F) -> Result<DnsPacket, DnsResolverError>
+    where
+        F: Future<Output = Result<DnsPacket, DnsResolverError>>,
+    {
+        let receiver = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get_mut(&key) {
+                Some(waiters) => {
+                    if waiters.len() >= self.max_waiters {
+                        return Err(DnsResolverError::TooManyWaiters);
+                    }
+                    let (sender, receiver) = oneshot::channel();
+                    waiters.push(sender);
+                    Some(receiver)
+                }
+                None => {
+                    pending.insert(key.clone(), Vec::new());
+                    None
+                }
+            }
+        };
+
+        if let Some(receiver) = receiver {
+            return receiver
+                .await
+                .ok()
+                .flatten()
+                .ok_or(DnsResolverError::ResolutionFailed);
+        }
+
+        let mut leader = Leader {
+            inflight: self,
+            key: Some(key),
+        };
+        let result = resolve.await;
+        leader.finish(result.as_ref().ok());
+        result
+    }
+}
+
+/// Removes the key even if the leading resolution is dropped half way, which
+/// closes the waiters' channels instead of leaving them hanging.
+struct Leader<'a> {
+    inflight: &'a InflightQueries,
+    key: Option<QueryKey>,
+}
+
+impl Leader<'_> {
+    fn finish(&mut self, response: Option<&DnsPacket>) {
+        let waiters = match self.key.take() {
+            Some(key) => self.inflight.pending.lock().unwrap().remove(&key),
+            None => None,
+        };
+        for waiter in waiters.unwrap_or_default() {
+            let _ = waiter.send(response.cloned());
+        }
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        self.finish(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn key() -> QueryKey {
+        QueryKey::new(&DnsQuestion::new("example.com".to_string(), QueryType::A))
+    }
+
+    /// A resolution that counts its runs and waits for `gate` so others can join it.
+    async fn resolution(
+        runs: &AtomicUsize,
+        gate: oneshot::Receiver<()>,
+        result: Result<DnsPacket, DnsResolverError>,
+    ) -> Result<DnsPacket, DnsResolverError> {
+        runs.fetch_add(1, Ordering::SeqCst);
+        let _ = gate.await;
+        result
+    }
+
+    async fn open(gate: oneshot::Sender<()>) {
+        tokio::task::yield_now().await;
+        let _ = gate.send(());
+    }
+
+    fn never() -> oneshot::Receiver<()> {
+        oneshot::channel().1
+    }
+
+    #[tokio::test]
+    async fn coalesces_concurrent_resolutions() {
+        let inflight = InflightQueries::new(8);
+        let runs = AtomicUsize::new(0);
+        let (gate, opened) = oneshot::channel();
+        let mut answer = DnsPacket::new();
+        answer.header.id = 7;
+
+        let (leader, first, second, _) = tokio::join!(
+            inflight.run(key(), resolution(&runs, opened, Ok(answer))),
+            inflight.run(key(), resolution(&runs, never(), Err(DnsResolverError::Timeout))),
+            inflight.run(key(), resolution(&runs, never(), Err(DnsResolverError::Timeout))),
+            open(gate),
+        );
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        for response in [leader, first, second] {
+            assert_eq!(response.unwrap().header.id, 7);
+        }
+        assert!(inflight.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_waiters_past_the_bound() {
+        let inflight = InflightQueries::new(1);
+        let runs = AtomicUsize::new(0);
+        let (gate, opened) = oneshot::channel();
+
+        let (leader, waiter, refused, _) = tokio::join!(
+            inflight.run(key(), resolution(&runs, opened, Ok(DnsPacket::new()))),
+            inflight.run(key(), resolution(&runs, never(), Ok(DnsPacket::new()))),
+            inflight.run(key(), resolution(&runs, never(), Ok(DnsPacket::new()))),
+            open(gate),
+        );
+        assert!(leader.is_ok() && waiter.is_ok());
+        assert!(matches!(refused, Err(DnsResolverError::TooManyWaiters)));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn forgets_failed_resolutions() {
+        let inflight = InflightQueries::new(8);
+        let runs = AtomicUsize::new(0);
+        let (gate, opened) = oneshot::channel();
+
+        let (leader, waiter, _) = tokio::join!(
+            inflight.run(key(), resolution(&runs, opened, Err(DnsResolverError::Timeout))),
+            inflight.run(key(), resolution(&runs, never(), Ok(DnsPacket::new()))),
+            open(gate),
+        );
+        assert!(matches!(leader, Err(DnsResolverError::Timeout)));
+        assert!(matches!(waiter, Err(DnsResolverError::ResolutionFailed)));
+        assert!(inflight.pending.lock().unwrap().is_empty());
+
+        // The next query for the key resolves afresh.
+        let (_, opened) = oneshot::channel();
+        assert!(inflight.run(key(), resolution(&runs, opened, Ok(DnsPacket::new()))).await.is_ok());
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+}
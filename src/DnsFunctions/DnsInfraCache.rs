@@ -0,0 +1,284 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Values follow Unbound's infra cache defaults where it has one.
+const UNKNOWN_SERVER_RTT: u32 = 376;
+const MIN_RTO: u32 = 50;
+const MAX_RTO: u32 = 120_000;
+const MAX_QUERY_TIMEOUT: u32 = 5_000;
+const EXPLORE_PROBABILITY: f64 = 0.05;
+const TIMEOUT_BACKOFF_THRESHOLD: u32 = 3;
+const TIMEOUT_BACKOFF: Duration = Duration::from_secs(60);
+const LAME_BACKOFF: Duration = Duration::from_secs(900);
+const STATS_TTL: Duration = Duration::from_secs(900);
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdnsStatus {
+    Unknown,
+    Supported,
+    Unsupported,
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerStats {
+    pub srtt: u32,
+    pub rttvar: u32,
+    pub rto: u32,
+    pub timeouts: u32,
+    pub edns: EdnsStatus,
+    pub preserves_case: bool,
+    pub backoff_until: Option<Instant>,
+    pub lame_zones: HashMap<String, Instant>,
+    pub updated: Instant,
+}
+
+impl ServerStats {
+    fn new(now: Instant) -> ServerStats {
+        ServerStats {
+            srtt: 0,
+            rttvar: 0,
+            rto: UNKNOWN_SERVER_RTT,
+            timeouts: 0,
+            edns: EdnsStatus::Unknown,
+            preserves_case: true,
+            backoff_until: None,
+            lame_zones: HashMap::new(),
+            updated: now,
+        }
+    }
+
+    fn is_measured(&self) -> bool {
+        self.srtt != 0 || self.rttvar != 0
+    }
+
+    fn is_usable(&self, zone: &str, now: Instant) -> bool {
+        if self.backoff_until.is_some_and(|until| until > now) {
+            return false;
+        }
+        match self.lame_zones.get(zone) {
+            Some(until) => *until <= now,
+            None => true,
+        }
+    }
+}
+
+//<---------------------------- Infrastructure cache ------------------------------------>
+
+pub struct InfraCache {
+    servers: Mutex<HashMap<SocketAddr, ServerStats>>,
+}
+
+impl InfraCache {
+    pub fn new() -> InfraCache {
+        InfraCache {
+            servers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn with_stats<T>(&self, addr: SocketAddr, f: impl FnOnce(&mut ServerStats) -> T) -> T {
+        let now = Instant::now();
+        let mut servers = self.servers.lock().unwrap();
+        if servers.len() >= MAX_ENTRIES && !servers.contains_key(&addr) {
+            servers.retain(|_, stats| now.duration_since(stats.updated) < STATS_TTL);
+        }
+        let stats = servers.entry(addr).or_insert_with(|| ServerStats::new(now));
+        if now.duration_since(stats.updated) >= STATS_TTL {
+            *stats = ServerStats::new(now);
+        }
+        stats.updated = now;
+        f(stats)
+    }
+
+    /// Picks the server with the lowest expected RTT, skipping servers that are
+    /// lame for `zone` or backed off after repeated timeouts. Once in a while a
+    /// random usable server is picked instead so that estimates stay fresh.
+    pub fn select(&self, zone: &str, candidates: &[SocketAddr]) -> Option<SocketAddr> {
+        let now = Instant::now();
+        let servers = self.servers.lock().unwrap();
+        let rated: Vec<(SocketAddr, u32, bool)> = candidates
+            .iter()
+            .map(|addr| match servers.get(addr) {
+                Some(stats) if now.duration_since(stats.updated) < STATS_TTL => {
+                    (*addr, stats.rto, stats.is_usable(zone, now))
+                }
+                _ => (*addr, UNKNOWN_SERVER_RTT, true),
+            })
+            .collect();
+        drop(servers);
+
+        let mut usable: Vec<(SocketAddr, u32)> = rated
+            .iter()
+            .filter(|(_, _, usable)| *usable)
+            .map(|(addr, rtt, _)| (*addr, *rtt))
+            .collect();
+        if usable.is_empty() {
+            // Everything is backed off; probe rather than fail the query outright.
+            usable = rated.iter().map(|(addr, rtt, _)| (*addr, *rtt)).collect();
+        }
+
+        let mut rng = rand::thread_rng();
+        if usable.len() > 1 && rng.gen_bool(EXPLORE_PROBABILITY) {
+            return usable.choose(&mut rng).map(|(addr, _)| *addr);
+        }
+
+        let best = usable.iter().map(|(_, rtt)| *rtt).min()?;
+        let fastest: Vec<SocketAddr> = usable
+            .iter()
+            .filter(|(_, rtt)| *rtt == best)
+            .map(|(addr, _)| *addr)
+            .collect();
+        fastest.choose(&mut rng).copied()
+    }
+
+    pub fn timeout(&self, addr: SocketAddr) -> Duration {
+        let servers = self.servers.lock().unwrap();
+        let rto = match servers.get(&addr) {
+            Some(stats) if stats.is_measured() => stats.rto,
+            _ => 1_000,
+        };
+        Duration::from_millis(rto.clamp(MIN_RTO, MAX_QUERY_TIMEOUT) as u64)
+    }
+
+    /// RFC 6298 smoothing of the round trip time to `addr`.
+    pub fn record_rtt(&self, addr: SocketAddr, rtt: Duration) {
+        let sample = (rtt.as_millis() as u32).max(1);
+        self.with_stats(addr, |stats| {
+            if stats.is_measured() {
+                let delta = stats.srtt.abs_diff(sample);
+                stats.rttvar = (3 * stats.rttvar + delta) / 4;
+                stats.srtt = (7 * stats.srtt + sample) / 8;
+            } else {
+                stats.srtt = sample;
+                stats.rttvar = sample / 2;
+            }
+            stats.rto = (stats.srtt + 4 * stats.rttvar).clamp(MIN_RTO, MAX_RTO);
+            stats.timeouts = 0;
+            stats.backoff_until = None;
+        });
+    }
+
+    pub fn record_timeout(&self, addr: SocketAddr) {
+        self.with_stats(addr, |stats| {
+            stats.timeouts += 1;
+            stats.rto = stats.rto.saturating_mul(2).min(MAX_RTO);
+            if stats.timeouts >= TIMEOUT_BACKOFF_THRESHOLD {
+                stats.backoff_until = Some(Instant::now() + TIMEOUT_BACKOFF);
+            }
+        });
+    }
+
+    pub fn edns(&self, addr: SocketAddr) -> EdnsStatus {
+        let servers = self.servers.lock().unwrap();
+        match servers.get(&addr) {
+            Some(stats) if Instant::now().duration_since(stats.updated) < STATS_TTL => stats.edns,
+            _ => EdnsStatus::Unknown,
+        }
+    }
+
+    pub fn record_edns(&self, addr: SocketAddr, supported: bool) {
+        self.with_stats(addr, |stats| {
+            stats.edns = if supported {
+                EdnsStatus::Supported
+            } else {
+                EdnsStatus::Unsupported
+            };
+        });
+    }
+
+    pub fn preserves_case(&self, addr: SocketAddr) -> bool {
+        let servers = self.servers.lock().unwrap();
+        match servers.get(&addr) {
+            Some(stats) if Instant::now().duration_since(stats.updated) < STATS_TTL => {
+                stats.preserves_case
+            }
+            _ => true,
+        }
+    }
+
+    pub fn record_case_mismatch(&self, addr: SocketAddr) {
+        self.with_stats(addr, |stats| stats.preserves_case = false);
+    }
+
+    pub fn mark_lame(&self, addr: SocketAddr, zone: &str) {
+        self.with_stats(addr, |stats| {
+            stats.lame_zones
+                .insert(zone.to_string(), Instant::now() + LAME_BACKOFF);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(last: u8) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, last], 53))
+    }
+
+    fn stats(cache: &InfraCache, addr: SocketAddr) -> ServerStats {
+        cache.servers.lock().unwrap()[&addr].clone()
+    }
+
+    #[test]
+    fn smooths_rtt_as_rfc_6298() {
+        let cache = InfraCache::new();
+        cache.record_rtt(addr(1), Duration::from_millis(100));
+        let first = stats(&cache, addr(1));
+        assert_eq!((first.srtt, first.rttvar, first.rto), (100, 50, 300));
+
+        cache.record_rtt(addr(1), Duration::from_millis(20));
+        let second = stats(&cache, addr(1));
+        // rttvar = (3 * 50 + |100 - 20|) / 4, srtt = (7 * 100 + 20) / 8
+        assert_eq!((second.srtt, second.rttvar, second.rto), (90, 57, 318));
+        assert_eq!(cache.timeout(addr(1)), Duration::from_millis(318));
+    }
+
+    #[test]
+    fn backs_off_after_timeouts() {
+        let cache = InfraCache::new();
+        cache.record_timeout(addr(1));
+        assert_eq!(stats(&cache, addr(1)).rto, 2 * UNKNOWN_SERVER_RTT);
+        cache.record_timeout(addr(1));
+        assert!(stats(&cache, addr(1)).backoff_until.is_none());
+        cache.record_timeout(addr(1));
+        assert!(stats(&cache, addr(1)).backoff_until.is_some());
+        for _ in 0..20 {
+            cache.record_timeout(addr(1));
+        }
+        assert_eq!(stats(&cache, addr(1)).rto, MAX_RTO);
+        assert_eq!(cache.select("example.com", &[addr(1), addr(2)]), Some(addr(2)));
+
+        // An answer ends the backoff.
+        cache.record_rtt(addr(1), Duration::from_millis(10));
+        assert_eq!(stats(&cache, addr(1)).timeouts, 0);
+        assert!(stats(&cache, addr(1)).backoff_until.is_none());
+    }
+
+    #[test]
+    fn lame_zones_expire() {
+        let cache = InfraCache::new();
+        cache.mark_lame(addr(1), "example.com");
+        let now = Instant::now();
+        assert!(!stats(&cache, addr(1)).is_usable("example.com", now));
+        assert!(stats(&cache, addr(1)).is_usable("example.org", now));
+        assert!(stats(&cache, addr(1)).is_usable("example.com", now + LAME_BACKOFF));
+        assert_eq!(cache.select("example.com", &[addr(1), addr(2)]), Some(addr(2)));
+    }
+
+    #[test]
+    fn selects_the_fastest_and_sometimes_explores() {
+        let cache = InfraCache::new();
+        cache.record_rtt(addr(1), Duration::from_millis(10));
+        cache.record_rtt(addr(2), Duration::from_millis(400));
+        let candidates = [addr(1), addr(2)];
+        let picks: Vec<SocketAddr> = (0..2000).map(|_| cache.select("example.com", &candidates).unwrap()).collect();
+        let slow = picks.iter().filter(|pick| **pick == addr(2)).count();
+        // Exploring picks at random 5% of the time, so about 50 of 2000.
+        assert!(slow > 0 && slow < 200, "{} slow picks", slow);
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::Dnspacket::{DnsPacket, DnsRecord, ResultCode};
+
+// Buckets tracked before idle ones are forgotten; a full bucket loses nothing
+// by being recreated.
+const MAX_TRACKED: usize = 100_000;
+const IDLE: Duration = Duration::from_secs(60);
+
+/// Rate limits for UDP clients, each counted per client prefix.
+#[derive(Clone, Debug)]
+pub struct RateLimits {
+    /// Responses per second of each category; 0 turns response limiting off.
+    pub responses_per_second: u32,
+    /// Every this many dropped responses one goes out truncated, so real
+    /// clients behind a spoofed address retry over TCP; 0 never does.
+    pub slip: u32,
+    /// Only log what would be limited.
+    pub log_only: bool,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    /// Queries per second before they are dropped unresolved; 0 is unlimited.
+    pub queries_per_second: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        RateLimits {
+            responses_per_second: 0,
+            slip: 2,
+            log_only: false,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+            queries_per_second: 0,
+        }
+    }
+}
+
+/// Responses are limited per kind, so a flood of one kind does not starve the others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ResponseCategory {
+    Answer,
+    NxDomain,
+    Error,
+    Referral,
+}
+
+impl ResponseCategory {
+    pub fn of(response: &DnsPacket) -> ResponseCategory {
+        match response.header.rescode {
+            ResultCode::NOERROR => {
+                let delegates = response.answers.is_empty()
+                    && response.authorities.iter().any(|r| matches!(r, DnsRecord::NS { .. }))
+                    && !response.authorities.iter().any(|r| matches!(r, DnsRecord::SOA { .. }));
+                if delegates {
+                    ResponseCategory::Referral
+                } else {
+                    ResponseCategory::Answer
+                }
+            }
+            ResultCode::NXDOMAIN => ResponseCategory::NxDomain,
+            _ => ResponseCategory::Error,
+        }
+    }
+}
+
+impl fmt::Display for ResponseCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseCategory::Answer => write!(f, "answer"),
+            ResponseCategory::NxDomain => write!(f, "NXDOMAIN"),
+            ResponseCategory::Error => write!(f, "error"),
+            ResponseCategory::Referral => write!(f, "referral"),
+        }
+    }
+}
+
+/// What to do with a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateDecision {
+    Send,
+    Drop,
+    /// Send an empty response with TC set instead.
+    Slip,
+}
+
+/// Holds up to one second's worth of tokens, refilled continuously.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // Responses dropped since the bucket last had a token.
+    dropped: u32,
+}
+
+impl Bucket {
+    fn take(&mut self, rate: u32, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(rate)).min(f64::from(rate));
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.dropped = 0;
+            true
+        } else {
+            self.dropped += 1;
+            false
+        }
+    }
+}
+
+/// Token buckets per client prefix: one per response category for response
+/// rate limiting, and one for the queries each prefix may have resolved.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    responses: Mutex<HashMap<(IpAddr, ResponseCategory), Bucket>>,
+    queries: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        RateLimiter {
+            limits,
+            responses: Mutex::new(HashMap::new()),
+            queries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a query from `client` may go on to be answered.
+    pub fn allow_query(&self, client: IpAddr) -> bool {
+        self.allow_query_at(client, Instant::now())
+    }
+
+    fn allow_query_at(&self, client: IpAddr, now: Instant) -> bool {
+        let rate = self.limits.queries_per_second;
+        if rate == 0 {
+            return true;
+        }
+        let prefix = self.prefix(client);
+        let Some(dropped) = over_limit(&self.queries, prefix, rate, now) else {
+            return true;
+        };
+        if dropped == 1 {
+            println!("{} queries from {}", self.limiting(), prefix);
+        }
+        self.limits.log_only
+    }
+
+    pub fn check_response(&self, client: IpAddr, response: &DnsPacket) -> RateDecision {
+        self.check_response_at(client, ResponseCategory::of(response), Instant::now())
+    }
+
+    fn check_response_at(&self, client: IpAddr, category: ResponseCategory, now: Instant) -> RateDecision {
+        let rate = self.limits.responses_per_second;
+        if rate == 0 {
+            return RateDecision::Send;
+        }
+        let prefix = self.prefix(client);
+        let Some(dropped) = over_limit(&self.responses, (prefix, category), rate, now) else {
+            return RateDecision::Send;
+        };
+        if dropped == 1 {
+            println!("{} {} responses to {}", self.limiting(), category, prefix);
+        }
+        match self.limits.slip {
+            _ if self.limits.log_only => RateDecision::Send,
+            0 => RateDecision::Drop,
+            slip if dropped % slip == 0 => RateDecision::Slip,
+            _ => RateDecision::Drop,
+        }
+    }
+
+    fn limiting(&self) -> &'static str {
+        if self.limits.log_only {
+            "Would limit"
+        } else {
+            "Limiting"
+        }
+    }
+
+    /// The client's address with the host bits past the configured prefix cleared.
+    fn prefix(&self, client: IpAddr) -> IpAddr {
+        match client {
+            IpAddr::V4(address) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.limits.ipv4_prefix)).unwrap_or(0);
+                IpAddr::V4((u32::from(address) & mask).into())
+            }
+            IpAddr::V6(address) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.limits.ipv6_prefix)).unwrap_or(0);
+                IpAddr::V6((u128::from(address) & mask).into())
+            }
+        }
+    }
+}
+
+/// Takes a token from the key's bucket; None if there was one, else how many
+/// times in a row the bucket has come up empty.
+fn over_limit<K: Eq + Hash>(buckets: &Mutex<HashMap<K, Bucket>>, key: K, rate: u32, now: Instant) -> Option<u32> {
+    let mut buckets = buckets.lock().unwrap();
+    if buckets.len() >= MAX_TRACKED {
+        buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < IDLE);
+    }
+    let bucket = buckets.entry(key).or_insert(Bucket {
+        tokens: f64::from(rate),
+        updated: now,
+        dropped: 0,
+    });
+    match bucket.take(rate, now) {
+        true => None,
+        false => Some(bucket.dropped),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(responses_per_second: u32, slip: u32) -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            responses_per_second,
+            slip,
+            queries_per_second: 2,
+            ..RateLimits::default()
+        })
+    }
+
+    #[test]
+    fn limits_each_prefix_and_category_separately() {
+        let limiter = limiter(3, 0);
+        let now = Instant::now();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let neighbour: IpAddr = "192.0.2.200".parse().unwrap();
+        for _ in 0..3 {
+            assert_eq!(limiter.check_response_at(client, ResponseCategory::Answer, now), RateDecision::Send);
+        }
+        // The neighbour shares the /24, and so the bucket.
+        assert_eq!(limiter.check_response_at(neighbour, ResponseCategory::Answer, now), RateDecision::Drop);
+        assert_eq!(limiter.check_response_at(client, ResponseCategory::NxDomain, now), RateDecision::Send);
+        let elsewhere = "198.51.100.1".parse().unwrap();
+        assert_eq!(limiter.check_response_at(elsewhere, ResponseCategory::Answer, now), RateDecision::Send);
+
+        // A token comes back every third of a second.
+        let later = now + Duration::from_millis(400);
+        assert_eq!(limiter.check_response_at(client, ResponseCategory::Answer, later), RateDecision::Send);
+        assert_eq!(limiter.check_response_at(client, ResponseCategory::Answer, later), RateDecision::Drop);
+    }
+
+    #[test]
+    fn slips_every_nth_dropped_response() {
+        let limiter = limiter(1, 2);
+        let now = Instant::now();
+        let client: IpAddr = "2001:db8::1".parse().unwrap();
+        let decisions: Vec<RateDecision> = (0..5)
+            .map(|_| limiter.check_response_at(client, ResponseCategory::Error, now))
+            .collect();
+        use RateDecision::*;
+        assert_eq!(decisions, vec![Send, Drop, Slip, Drop, Slip]);
+    }
+
+    #[test]
+    fn limits_queries_unless_logging_only() {
+        let now = Instant::now();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let limiter = limiter(0, 2);
+        assert!(limiter.allow_query_at(client, now));
+        assert!(limiter.allow_query_at(client, now));
+        assert!(!limiter.allow_query_at(client, now));
+        assert_eq!(limiter.check_response_at(client, ResponseCategory::Answer, now), RateDecision::Send);
+
+        let logging = RateLimiter::new(RateLimits {
+            queries_per_second: 1,
+            log_only: true,
+            ..RateLimits::default()
+        });
+        assert!(logging.allow_query_at(client, now));
+        assert!(logging.allow_query_at(client, now));
+    }
+
+    #[test]
+    fn categorises_responses() {
+        let mut response = DnsPacket::new();
+        response.authorities.push(DnsRecord::NS {
+            domain: "sub.example.com".to_string(),
+            host: "ns.sub.example.com".to_string(),
+            ttl: 60,
+        });
+        assert_eq!(ResponseCategory::of(&response), ResponseCategory::Referral);
+        response.header.rescode = ResultCode::NXDOMAIN;
+        assert_eq!(ResponseCategory::of(&response), ResponseCategory::NxDomain);
+        response.header.rescode = ResultCode::SERVFAIL;
+        assert_eq!(ResponseCategory::of(&response), ResponseCategory::Error);
+        response.header.rescode = ResultCode::NOERROR;
+        response.authorities.clear();
+        assert_eq!(ResponseCategory::of(&response), ResponseCategory::Answer);
+    }
+}
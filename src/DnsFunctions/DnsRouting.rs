@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::DnsForwarder::UpstreamPool;
+
+#[derive(Clone)]
+pub enum Route {
+    /// Send the question with RD=1 to recursive servers.
+    Forward(Arc<UpstreamPool>),
+    /// Start the iterative walk at these authoritative servers instead of the root.
+    Stub(Vec<SocketAddr>),
+}
+
+//<------------------------------- Routing table --------------------------------------->
+
+/// Maps domain suffixes to the servers responsible for them. The empty suffix
+/// stands for the root and matches every name.
+#[derive(Clone)]
+pub struct RoutingTable {
+    routes: HashMap<String, Route>,
+}
+
+impl RoutingTable {
+    pub fn new() -> RoutingTable {
+        RoutingTable {
+            routes: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, suffix: &str, route: Route) {
+        self.routes.insert(normalise(suffix), route);
+    }
+
+    pub fn pools(&self) -> impl Iterator<Item = &Arc<UpstreamPool>> {
+        self.routes.values().filter_map(|route| match route {
+            Route::Forward(pool) => Some(pool),
+            Route::Stub(_) => None,
+        })
+    }
+
+    /// Longest matching suffix for `name`, together with its route.
+    pub fn lookup(&self, name: &str) -> Option<(&str, &Route)> {
+        let name = normalise(name);
+        let mut candidate = name.as_str();
+        loop {
+            if let Some((suffix, route)) = self.routes.get_key_value(candidate) {
+                return Some((suffix.as_str(), route));
+            }
+            if candidate.is_empty() {
+                return None;
+            }
+            candidate = candidate.split_once('.').map_or("", |(_, parent)| parent);
+        }
+    }
+}
+
+fn normalise(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stub(port: u16) -> Route {
+        Route::Stub(vec![SocketAddr::from(([127, 0, 0, 1], port))])
+    }
+
+    fn port(route: Option<(&str, &Route)>) -> Option<(String, u16)> {
+        route.map(|(suffix, route)| match route {
+            Route::Stub(servers) => (suffix.to_string(), servers[0].port()),
+            Route::Forward(_) => (suffix.to_string(), 0),
+        })
+    }
+
+    #[test]
+    fn longest_suffix_wins() {
+        let mut table = RoutingTable::new();
+        table.insert("example", stub(1));
+        table.insert("corp.example.", stub(2));
+
+        assert_eq!(port(table.lookup("www.corp.example")), Some(("corp.example".to_string(), 2)));
+        assert_eq!(port(table.lookup("CORP.example")), Some(("corp.example".to_string(), 2)));
+        assert_eq!(port(table.lookup("www.example")), Some(("example".to_string(), 1)));
+        assert_eq!(port(table.lookup("notcorp.example")), Some(("example".to_string(), 1)));
+        assert_eq!(port(table.lookup("example.com")), None);
+    }
+
+    #[test]
+    fn root_route_matches_everything() {
+        let mut table = RoutingTable::new();
+        table.insert(".", stub(1));
+        table.insert("10.in-addr.arpa", stub(2));
+
+        assert_eq!(port(table.lookup("example.com")), Some((String::new(), 1)));
+        assert_eq!(port(table.lookup("1.0.0.10.in-addr.arpa")), Some(("10.in-addr.arpa".to_string(), 2)));
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::DnsAcl::IpPrefix;
+use crate::Dnspacket::{DnsPacket, DnsRecord};
+use crate::DnsZone::{Zone, ZoneSet};
+
+/// What a policy does to a query that triggers it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PolicyAction {
+    NxDomain,
+    NoData,
+    /// Answer as if no policy applied, and stop looking at later policies.
+    Passthru,
+    /// Send no answer at all.
+    Drop,
+    /// Answer from the trigger's records, which may be a CNAME to rewrite to.
+    LocalData(Vec<DnsRecord>),
+}
+
+impl fmt::Display for PolicyAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyAction::NxDomain => write!(f, "NXDOMAIN"),
+            PolicyAction::NoData => write!(f, "NODATA"),
+            PolicyAction::Passthru => write!(f, "PASSTHRU"),
+            PolicyAction::Drop => write!(f, "DROP"),
+            PolicyAction::LocalData(records) => match records.first() {
+                Some(DnsRecord::CNAME { host, .. }) => write!(f, "CNAME {}", host),
+                _ => write!(f, "local data"),
+            },
+        }
+    }
+}
+
+/// A policy that matched, for applying and logging.
+#[derive(Debug)]
+pub struct PolicyHit {
+    pub policy: String,
+    pub trigger: String,
+    pub action: PolicyAction,
+}
+
+impl fmt::Display for PolicyHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.policy, self.trigger, self.action)
+    }
+}
+
+//<--------------------------------- Policy zones ------------------------------------->
+
+/// Exact names, and `*.` wildcards standing for every name below their parent.
+#[derive(Debug, Default)]
+struct NameTriggers {
+    exact: HashMap<String, PolicyAction>,
+    below: HashMap<String, PolicyAction>,
+}
+
+impl NameTriggers {
+    fn insert(&mut self, name: &str, action: PolicyAction) {
+        match name.strip_prefix("*.") {
+            Some(parent) => self.below.insert(parent.to_string(), action),
+            None => self.exact.insert(name.to_string(), action),
+        };
+    }
+
+    fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.below.is_empty()
+    }
+
+    /// The exact entry for `name`, else the wildcard of its closest parent.
+    fn get(&self, name: &str) -> Option<&PolicyAction> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        if let Some(action) = self.exact.get(&name) {
+            return Some(action);
+        }
+        let mut parent = name.as_str();
+        while let Some((_, rest)) = parent.split_once('.') {
+            if let Some(action) = self.below.get(rest) {
+                return Some(action);
+            }
+            parent = rest;
+        }
+        None
+    }
+}
+
+/// `24.0.2.0.192` or `48.zz.db8.2001`: the prefix length, then the address
+/// with its octets (IPv4) or 16-bit groups (IPv6) reversed, `zz` standing for
+/// the `::` run of zero groups.
+fn parse_prefix(labels: &str) -> Option<IpPrefix> {
+    let mut labels = labels.split('.');
+    let len: u8 = labels.next()?.parse().ok()?;
+    let mut parts: Vec<&str> = labels.collect();
+    parts.reverse();
+
+    if parts.len() == 4 && !parts.contains(&"zz") {
+        let octets: Vec<u8> = parts.iter().map(|part| part.parse().ok()).collect::<Option<_>>()?;
+        let address = Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]);
+        return IpPrefix::new(IpAddr::V4(address), len);
+    }
+
+    let mut groups = Vec::with_capacity(8);
+    for part in &parts {
+        if *part == "zz" {
+            if parts.len() > 8 {
+                return None;
+            }
+            groups.resize(groups.len() + 9 - parts.len(), 0);
+        } else {
+            groups.push(u16::from_str_radix(part, 16).ok()?);
+        }
+    }
+    if groups.len() != 8 || parts.iter().filter(|part| **part == "zz").count() > 1 {
+        return None;
+    }
+    let mut segments = [0u16; 8];
+    segments.copy_from_slice(&groups);
+    IpPrefix::new(IpAddr::V6(Ipv6Addr::from(segments)), len)
+}
+
+/// The triggers of one policy zone, indexed from its records as laid out by
+/// the RPZ draft: `<name>` for query names, `<prefix>.rpz-ip` for addresses
+/// in answers, `<name>.rpz-nsdname` and `<prefix>.rpz-nsip` for the
+/// nameservers of the answering zone.
+#[derive(Debug)]
+struct PolicyZone {
+    name: String,
+    serial: Option<u32>,
+    qnames: NameTriggers,
+    response_ips: Vec<(IpPrefix, PolicyAction)>,
+    nsdnames: NameTriggers,
+    nsips: Vec<(IpPrefix, PolicyAction)>,
+}
+
+impl PolicyZone {
+    fn empty(name: &str) -> PolicyZone {
+        PolicyZone {
+            name: name.to_string(),
+            serial: None,
+            qnames: NameTriggers::default(),
+            response_ips: Vec::new(),
+            nsdnames: NameTriggers::default(),
+            nsips: Vec::new(),
+        }
+    }
+
+    fn from_zone(zone: &Zone) -> PolicyZone {
+        let mut policy = PolicyZone::empty(&zone.origin);
+        policy.serial = Some(zone.serial());
+
+        let mut owners: BTreeMap<&str, Vec<DnsRecord>> = BTreeMap::new();
+        for record in zone.records() {
+            if let Some(owner) = record.domain() {
+                owners.entry(owner).or_default().push(record.clone());
+            }
+        }
+        let suffix = format!(".{}", zone.origin);
+        let mut skipped = 0;
+        for (owner, records) in owners {
+            // The apex only holds the SOA and NS records every zone needs.
+            let Some(trigger) = owner.strip_suffix(suffix.as_str()) else {
+                continue;
+            };
+            if !policy.add(trigger, records) {
+                skipped += 1;
+            }
+        }
+        println!(
+            "Loaded response policy zone {} at serial {}, {} triggers skipped",
+            zone.origin,
+            zone.serial(),
+            skipped
+        );
+        policy
+    }
+
+    /// Indexes one trigger; false for ones we do not support or cannot read.
+    fn add(&mut self, trigger: &str, records: Vec<DnsRecord>) -> bool {
+        let Some(action) = policy_action(records) else {
+            return false;
+        };
+        let (rest, kind) = trigger.rsplit_once('.').unwrap_or(("", trigger));
+        match kind {
+            "rpz-ip" | "rpz-nsip" => {
+                let Some(prefix) = parse_prefix(rest) else {
+                    return false;
+                };
+                let list = if kind == "rpz-ip" { &mut self.response_ips } else { &mut self.nsips };
+                list.push((prefix, action));
+            }
+            "rpz-nsdname" if !rest.is_empty() => self.nsdnames.insert(rest, action),
+            "rpz-client-ip" | "rpz-nsdname" => return false,
+            _ => self.qnames.insert(trigger, action),
+        }
+        true
+    }
+
+    fn qname_hit(&self, name: &str) -> Option<PolicyHit> {
+        let action = self.qnames.get(name)?;
+        Some(self.hit(format!("QNAME {}", name), action))
+    }
+
+    fn response_hit(&self, response: &DnsPacket, nameservers: &Nameservers) -> Option<PolicyHit> {
+        let addresses: Vec<IpAddr> = response
+            .answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+                DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+                _ => None,
+            })
+            .collect();
+        if let Some((address, action)) = longest_match(&self.response_ips, &addresses) {
+            return Some(self.hit(format!("IP {}", address), action));
+        }
+        for name in &nameservers.names {
+            if let Some(action) = self.nsdnames.get(name) {
+                return Some(self.hit(format!("NSDNAME {}", name), action));
+            }
+        }
+        let (address, action) = longest_match(&self.nsips, &nameservers.addresses)?;
+        Some(self.hit(format!("NSIP {}", address), action))
+    }
+
+    fn hit(&self, trigger: String, action: &PolicyAction) -> PolicyHit {
+        PolicyHit {
+            policy: self.name.clone(),
+            trigger,
+            action: action.clone(),
+        }
+    }
+}
+
+/// The trigger with the longest prefix covering any of `addresses`.
+fn longest_match<'a>(
+    triggers: &'a [(IpPrefix, PolicyAction)],
+    addresses: &[IpAddr],
+) -> Option<(IpAddr, &'a PolicyAction)> {
+    addresses
+        .iter()
+        .flat_map(|address| {
+            triggers
+                .iter()
+                .filter(|(prefix, _)| prefix.contains(*address))
+                .map(move |(prefix, action)| (prefix.len(), *address, action))
+        })
+        .max_by_key(|(len, _, _)| *len)
+        .map(|(_, address, action)| (address, action))
+}
+
+/// `CNAME .` is NXDOMAIN, `CNAME *.` NODATA, `CNAME rpz-passthru.` and
+/// `CNAME rpz-drop.` the actions they name. Anything else is local data.
+fn policy_action(records: Vec<DnsRecord>) -> Option<PolicyAction> {
+    if let [DnsRecord::CNAME { host, .. }] = &records[..] {
+        match host.as_str() {
+            "" => return Some(PolicyAction::NxDomain),
+            "*" => return Some(PolicyAction::NoData),
+            "rpz-passthru" => return Some(PolicyAction::Passthru),
+            "rpz-drop" => return Some(PolicyAction::Drop),
+            special if special.starts_with("rpz-") => return None,
+            _ => (),
+        }
+    }
+    Some(PolicyAction::LocalData(records))
+}
+
+//<------------------------------- Response policy ----------------------------------->
+
+/// The names and addresses of the nameservers for the zone an answer came from.
+#[derive(Debug, Default)]
+pub struct Nameservers {
+    pub names: Vec<String>,
+    pub addresses: Vec<IpAddr>,
+}
+
+/// The configured policy zones in order of precedence: the first zone with a
+/// matching trigger decides, whatever later zones say.
+#[derive(Debug, Default)]
+pub struct ResponsePolicy {
+    zones: Vec<PolicyZone>,
+}
+
+impl ResponsePolicy {
+    pub fn new(names: &[String]) -> ResponsePolicy {
+        ResponsePolicy {
+            zones: names.iter().map(|name| PolicyZone::empty(name)).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    /// Whether every policy zone is indexed from the version `zones` holds.
+    pub fn is_current(&self, zones: &ZoneSet) -> bool {
+        self.zones
+            .iter()
+            .all(|policy| policy.serial == zones.get(&policy.name).map(Zone::serial))
+    }
+
+    /// Re-indexes the policy zones that changed since they were last indexed.
+    pub fn refresh(&mut self, zones: &ZoneSet) {
+        for policy in &mut self.zones {
+            match zones.get(&policy.name) {
+                Some(zone) if policy.serial != Some(zone.serial()) => *policy = PolicyZone::from_zone(zone),
+                Some(_) => (),
+                None => *policy = PolicyZone::empty(&policy.name),
+            }
+        }
+    }
+
+    /// Query name triggers, which can be applied before resolving anything.
+    pub fn qname_hit(&self, name: &str) -> Option<PolicyHit> {
+        self.zones.iter().find_map(|policy| policy.qname_hit(name))
+    }
+
+    /// Whether `response_hit` needs the nameservers of the answering zone.
+    pub fn needs_nameservers(&self) -> bool {
+        self.zones
+            .iter()
+            .any(|policy| !policy.nsdnames.is_empty() || !policy.nsips.is_empty())
+    }
+
+    /// Response IP, NSDNAME and NSIP triggers, in that order within each zone.
+    pub fn response_hit(&self, response: &DnsPacket, nameservers: &Nameservers) -> Option<PolicyHit> {
+        self.zones
+            .iter()
+            .find_map(|policy| policy.response_hit(response, nameservers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DnsZoneFile::parse_zone;
+    use std::net::Ipv4Addr;
+
+    const POLICY: &str = "
+$TTL 300
+@                   SOA  ns hostmaster 1 3600 600 86400 60
+                    NS   ns
+ns                  A    192.0.2.1
+bad.example         CNAME .
+*.bad.example       CNAME .
+empty.example       CNAME *.
+ok.bad.example      CNAME rpz-passthru.
+quiet.example       CNAME rpz-drop.
+moved.example       CNAME walled.garden.example.
+pinned.example      A    192.0.2.80
+tcp.example         CNAME rpz-tcp-only.
+24.0.2.0.198.rpz-ip CNAME .
+32.7.2.0.198.rpz-ip CNAME rpz-passthru.
+48.zz.db8.2001.rpz-ip CNAME *.
+ns.evil.example.rpz-nsdname CNAME rpz-drop.
+32.9.113.0.203.rpz-nsip CNAME .
+";
+
+    fn policy() -> PolicyZone {
+        let zone = Zone::new("rpz.local", parse_zone(POLICY, "rpz.local").unwrap()).unwrap();
+        PolicyZone::from_zone(&zone)
+    }
+
+    fn answer(address: &str) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(match address.parse().unwrap() {
+            IpAddr::V4(addr) => DnsRecord::A { domain: "x.example".to_string(), addr, ttl: 60 },
+            IpAddr::V6(addr) => DnsRecord::AAAA { domain: "x.example".to_string(), addr, ttl: 60 },
+        });
+        packet
+    }
+
+    fn action(hit: Option<PolicyHit>) -> Option<PolicyAction> {
+        hit.map(|hit| hit.action)
+    }
+
+    #[test]
+    fn matches_query_names_and_wildcards() {
+        let policy = policy();
+        assert_eq!(action(policy.qname_hit("Bad.Example.")), Some(PolicyAction::NxDomain));
+        assert_eq!(action(policy.qname_hit("www.bad.example")), Some(PolicyAction::NxDomain));
+        assert_eq!(action(policy.qname_hit("ok.bad.example")), Some(PolicyAction::Passthru));
+        assert_eq!(action(policy.qname_hit("empty.example")), Some(PolicyAction::NoData));
+        assert_eq!(action(policy.qname_hit("quiet.example")), Some(PolicyAction::Drop));
+        assert!(matches!(
+            action(policy.qname_hit("moved.example")),
+            Some(PolicyAction::LocalData(records)) if records.len() == 1
+        ));
+        assert!(matches!(action(policy.qname_hit("pinned.example")), Some(PolicyAction::LocalData(_))));
+        assert_eq!(action(policy.qname_hit("tcp.example")), None);
+        assert_eq!(action(policy.qname_hit("good.example")), None);
+        assert_eq!(action(policy.qname_hit("ns.rpz.local")), None);
+    }
+
+    #[test]
+    fn matches_response_and_nameserver_addresses() {
+        let policy = policy();
+        let none = Nameservers::default();
+        assert_eq!(action(policy.response_hit(&answer("198.0.2.5"), &none)), Some(PolicyAction::NxDomain));
+        // The longest prefix wins.
+        assert_eq!(action(policy.response_hit(&answer("198.0.2.7"), &none)), Some(PolicyAction::Passthru));
+        assert_eq!(action(policy.response_hit(&answer("198.0.3.7"), &none)), None);
+        assert_eq!(action(policy.response_hit(&answer("2001:db8::1"), &none)), Some(PolicyAction::NoData));
+        assert_eq!(action(policy.response_hit(&answer("2001:db9::1"), &none)), None);
+
+        let nameservers = Nameservers {
+            names: vec!["ns.evil.example".to_string()],
+            addresses: vec![IpAddr::V4(Ipv4Addr::new(203, 0, 113, 9))],
+        };
+        let hit = policy.response_hit(&answer("192.0.2.1"), &nameservers).unwrap();
+        assert_eq!(hit.to_string(), "rpz.local: NSDNAME ns.evil.example -> DROP");
+        let nameservers = Nameservers {
+            names: vec!["ns.fine.example".to_string()],
+            ..nameservers
+        };
+        assert_eq!(action(policy.response_hit(&answer("192.0.2.1"), &nameservers)), Some(PolicyAction::NxDomain));
+    }
+
+    #[test]
+    fn parses_address_prefixes() {
+        let v4 = parse_prefix("32.1.2.0.192").unwrap();
+        assert_eq!(Some(v4), IpPrefix::new("192.0.2.1".parse().unwrap(), 32));
+        let v6 = parse_prefix("128.1.zz.db8.2001").unwrap();
+        assert_eq!(Some(v6), IpPrefix::new("2001:db8::1".parse().unwrap(), 128));
+        assert!(v6.contains("2001:db8::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db8::2".parse().unwrap()));
+        assert!(parse_prefix("33.1.2.0.192").is_none());
+        assert!(parse_prefix("64.zz.1.zz.2001").is_none());
+        assert!(parse_prefix("24.x.2.0.192").is_none());
+    }
+
+    #[test]
+    fn earlier_zones_take_precedence() {
+        let mut policy = ResponsePolicy::new(&["first".to_string(), "second".to_string()]);
+        policy.zones[0].qnames.insert("a.example", PolicyAction::Passthru);
+        policy.zones[1].qnames.insert("a.example", PolicyAction::NxDomain);
+        policy.zones[1].qnames.insert("b.example", PolicyAction::NoData);
+        assert_eq!(action(policy.qname_hit("a.example")), Some(PolicyAction::Passthru));
+        let hit = policy.qname_hit("b.example").unwrap();
+        assert_eq!(hit.policy, "second");
+        assert!(!policy.needs_nameservers());
+    }
+}
@@ -0,0 +1,134 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+use crate::DnsErrors::DnsResolverError;
+use crate::Dnspacket::{BytePacketBuffer, DnsPacket, DnsRecord};
+use crate::DnsTransfer::MAX_MESSAGE_SIZE;
+
+/// EDNS option holding padding (RFC 7830).
+const OPTION_PADDING: u16 = 12;
+/// Clients pad queries, and servers responses, to a multiple of these many
+/// bytes (RFC 8467 section 4.1).
+pub const QUERY_BLOCK_SIZE: usize = 128;
+pub const RESPONSE_BLOCK_SIZE: usize = 468;
+
+/// Accepts TLS connections with the certificate chain and private key in
+/// the PEM files `certificate` and `key`, offering the ALPN protocols `alpn`.
+pub fn acceptor(certificate: &Path, key: &Path, alpn: &[&[u8]]) -> Result<TlsAcceptor, DnsResolverError> {
+    let chain = certificates(certificate)?;
+    let key_der: Option<PrivateKeyDer> = read_pem(key, |reader| rustls_pemfile::private_key(reader))?;
+    let key_der = key_der.ok_or_else(|| tls_error(key, "no private key found"))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, key_der))
+        .map_err(|e| tls_error(certificate, &e.to_string()))?;
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The certificates in the PEM file at `path`, of which there has to be one at least.
+pub fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, DnsResolverError> {
+    let certificates = read_pem(path, |reader| rustls_pemfile::certs(reader).collect::<Result<Vec<_>, _>>())?;
+    if certificates.is_empty() {
+        return Err(tls_error(path, "no certificates found"));
+    }
+    Ok(certificates)
+}
+
+/// Whether a query asks for its response to be padded.
+pub fn wants_padding(request: &DnsPacket) -> bool {
+    request.edns_option(OPTION_PADDING).is_some()
+}
+
+/// Pads `packet` to a multiple of `block_size` bytes, so the length of an
+/// encrypted message says little about the name it is for. Messages
+/// without an OPT record cannot carry padding and are left alone, as are
+/// those padding would take past the largest message TCP can frame.
+pub fn pad(packet: &mut DnsPacket, block_size: usize) -> Result<(), DnsResolverError> {
+    if !packet.resources.iter().any(|record| matches!(record, DnsRecord::OPT { .. })) {
+        return Ok(());
+    }
+    let mut unpadded = packet.clone();
+    unpadded.set_edns_option(OPTION_PADDING, &[]);
+    let mut buffer = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+    unpadded.write(&mut buffer)?;
+    let padding = (block_size - buffer.pos() % block_size) % block_size;
+    if buffer.pos() + padding > MAX_MESSAGE_SIZE {
+        return Ok(());
+    }
+    packet.set_edns_option(OPTION_PADDING, &vec![0; padding]);
+    Ok(())
+}
+
+fn read_pem<T>(
+    path: &Path,
+    parse: impl FnOnce(&mut BufReader<File>) -> std::io::Result<T>,
+) -> Result<T, DnsResolverError> {
+    let file = File::open(path).map_err(|e| tls_error(path, &e.to_string()))?;
+    parse(&mut BufReader::new(file)).map_err(|e| tls_error(path, &e.to_string()))
+}
+
+fn tls_error(path: &Path, details: &str) -> DnsResolverError {
+    DnsResolverError::ConfigError(format!("{}: {}", path.display(), details))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Dnspacket::{DnsQuestion, QueryType};
+
+    #[test]
+    fn pads_responses_with_opt_to_block_size() {
+        let mut response = DnsPacket::new();
+        response.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        pad(&mut response, RESPONSE_BLOCK_SIZE).unwrap();
+        assert!(!wants_padding(&response));
+
+        response.resources.push(DnsRecord::OPT {
+            packet_len: 1232,
+            flags: 0,
+            data: Vec::new(),
+        });
+        pad(&mut response, RESPONSE_BLOCK_SIZE).unwrap();
+        assert!(wants_padding(&response));
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        assert_eq!(buffer.pos() % RESPONSE_BLOCK_SIZE, 0);
+    }
+
+    #[test]
+    fn pads_large_responses_within_the_tcp_limit() {
+        let with_option = |length| {
+            let mut response = DnsPacket::new();
+            response.resources.push(DnsRecord::OPT {
+                packet_len: 1232,
+                flags: 0,
+                data: Vec::new(),
+            });
+            response.set_edns_option(65001, &vec![0; length]);
+            response
+        };
+
+        // Larger than a UDP buffer.
+        let mut response = with_option(8000);
+        pad(&mut response, RESPONSE_BLOCK_SIZE).unwrap();
+        assert!(wants_padding(&response));
+        let mut buffer = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+        response.write(&mut buffer).unwrap();
+        assert_eq!(buffer.pos() % RESPONSE_BLOCK_SIZE, 0);
+
+        // 65530 bytes with an empty padding option, so the next block would
+        // not fit the length prefix.
+        let mut response = with_option(MAX_MESSAGE_SIZE - 36);
+        pad(&mut response, RESPONSE_BLOCK_SIZE).unwrap();
+        assert!(!wants_padding(&response));
+    }
+}
@@ -0,0 +1,288 @@
+use std::io::Error as E;
+
+use crate::Dnspacket::{BytePacketBuffer, DnsPacket, DnsRecord};
+use crate::DnsZone::{serial_gt, Zone, ZoneDiff};
+
+/// TCP messages carry a 16 bit length prefix, which bounds every message.
+pub const MAX_MESSAGE_SIZE: usize = 65535;
+const HEADER_SIZE: usize = 12;
+// Room left in every message for the TSIG record of a signed transfer.
+const TSIG_RESERVE: usize = 1024;
+
+//<------------------------------- Transfer records ------------------------------------>
+
+/// A full transfer: the SOA, every other record, and the SOA again to mark the end.
+pub fn axfr_records(zone: &Zone) -> Vec<DnsRecord> {
+    let mut records: Vec<DnsRecord> = zone.records().cloned().collect();
+    records.push(zone.soa().clone());
+    records
+}
+
+/// An incremental transfer (RFC 1995) from `client_serial`. A client that is up
+/// to date only gets the SOA; one the journal cannot bring forward gets a full
+/// transfer instead, which IXFR clients have to accept.
+pub fn ixfr_records(zone: &Zone, client_serial: u32) -> Vec<DnsRecord> {
+    let Some(changes) = zone.changes_since(client_serial) else {
+        return axfr_records(zone);
+    };
+    let mut records = vec![zone.soa().clone()];
+    if changes.is_empty() {
+        return records;
+    }
+    for diff in changes {
+        records.push(diff.old_soa.clone());
+        records.extend(diff.removed.iter().cloned());
+        records.push(diff.new_soa.clone());
+        records.extend(diff.added.iter().cloned());
+    }
+    records.push(zone.soa().clone());
+    records
+}
+
+//<------------------------------ Receiving transfers ---------------------------------->
+
+/// What a primary sent back for our AXFR or IXFR.
+#[derive(Debug)]
+pub enum Transfer {
+    UpToDate,
+    Full(Vec<DnsRecord>),
+    Incremental(Vec<ZoneDiff>),
+}
+
+/// Interprets the answer records received so far, given the serial we already
+/// have. Returns None while the closing SOA has not arrived yet.
+pub fn parse_transfer(
+    records: &[DnsRecord],
+    current_serial: Option<u32>,
+) -> Result<Option<Transfer>, String> {
+    let Some(first) = records.first() else {
+        return Ok(None);
+    };
+    let final_serial = soa_serial(first).ok_or("transfer does not start with an SOA")?;
+    if records.len() == 1 {
+        return Ok(match current_serial {
+            Some(current) if !serial_gt(final_serial, current) => Some(Transfer::UpToDate),
+            _ => None,
+        });
+    }
+
+    // An IXFR answer goes on with the SOA of the version we have (RFC 1995).
+    let second_serial = soa_serial(&records[1]);
+    if second_serial.is_some() && second_serial == current_serial && second_serial != Some(final_serial) {
+        return parse_incremental(records, final_serial);
+    }
+
+    let last = &records[records.len() - 1];
+    if soa_serial(last) != Some(final_serial) {
+        return Ok(None);
+    }
+    Ok(Some(Transfer::Full(records[..records.len() - 1].to_vec())))
+}
+
+/// Walks the `old SOA, removed..., new SOA, added...` sequences up to the closing SOA.
+fn parse_incremental(records: &[DnsRecord], final_serial: u32) -> Result<Option<Transfer>, String> {
+    let mut diffs = Vec::new();
+    let mut index = 1;
+    loop {
+        let Some(old_soa) = records.get(index) else {
+            return Ok(None);
+        };
+        if soa_serial(old_soa) == Some(final_serial) {
+            if index + 1 != records.len() {
+                return Err("records after the closing SOA".to_string());
+            }
+            return Ok(Some(Transfer::Incremental(diffs)));
+        }
+
+        index += 1;
+        let removed_end = match records[index..].iter().position(|r| soa_serial(r).is_some()) {
+            Some(offset) => index + offset,
+            None => return Ok(None),
+        };
+        let new_soa = &records[removed_end];
+        let added_end = records[removed_end + 1..]
+            .iter()
+            .position(|r| soa_serial(r).is_some())
+            .map_or(records.len(), |offset| removed_end + 1 + offset);
+
+        diffs.push(ZoneDiff {
+            old_soa: old_soa.clone(),
+            new_soa: new_soa.clone(),
+            removed: records[index..removed_end].to_vec(),
+            added: records[removed_end + 1..added_end].to_vec(),
+        });
+        index = added_end;
+    }
+}
+
+fn soa_serial(record: &DnsRecord) -> Option<u32> {
+    match record {
+        DnsRecord::SOA { serial, .. } => Some(*serial),
+        _ => None,
+    }
+}
+
+//<------------------------------- Message packing ------------------------------------->
+
+/// Packs the records into as few authoritative responses to `request` as fit in
+/// `MAX_MESSAGE_SIZE` bytes each, leaving room to sign them. Only the first
+/// message repeats the question.
+pub fn transfer_messages(
+    request: &DnsPacket,
+    records: Vec<DnsRecord>,
+) -> Result<Vec<BytePacketBuffer>, E> {
+    let mut scratch = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+    let mut question_size = 0;
+    for question in &request.questions {
+        let start = scratch.pos();
+        question.clone().write(&mut scratch)?;
+        question_size += scratch.pos() - start;
+    }
+
+    let mut messages = Vec::new();
+    let mut message = response_to(request, true);
+    let mut size = HEADER_SIZE + question_size;
+    for record in records {
+        scratch.pos = 0;
+        let record_size = record.write(&mut scratch).map(|_| scratch.pos())?;
+        if size + record_size > MAX_MESSAGE_SIZE - TSIG_RESERVE && !message.answers.is_empty() {
+            messages.push(finish(message)?);
+            message = response_to(request, false);
+            size = HEADER_SIZE;
+        }
+        size += record_size;
+        message.answers.push(record);
+    }
+    messages.push(finish(message)?);
+    Ok(messages)
+}
+
+fn response_to(request: &DnsPacket, with_question: bool) -> DnsPacket {
+    let mut response = DnsPacket::new();
+    response.header.id = request.header.id;
+    response.header.response = true;
+    response.header.authoritative_answer = true;
+    if with_question {
+        response.questions = request.questions.clone();
+    }
+    response
+}
+
+fn finish(mut message: DnsPacket) -> Result<BytePacketBuffer, E> {
+    let mut buffer = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+    message.write(&mut buffer)?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Dnspacket::{DnsQuestion, QueryType};
+    use crate::DnsZoneFile::parse_zone;
+    use std::net::Ipv4Addr;
+
+    fn zone_file(serial: u32, hosts: usize) -> Vec<DnsRecord> {
+        let mut contents = format!("$TTL 60\n@ SOA ns1 hostmaster {} 1 1 1 1\n  NS ns1\nns1 A 192.0.2.1\n", serial);
+        for host in 0..hosts {
+            contents.push_str(&format!("host{} A 10.0.{}.{}\n", host, host / 256, host % 256));
+        }
+        parse_zone(&contents, "example.com").unwrap()
+    }
+
+    fn serial(record: &DnsRecord) -> u32 {
+        match record {
+            DnsRecord::SOA { serial, .. } => *serial,
+            _ => panic!("expected an SOA, got {:?}", record),
+        }
+    }
+
+    #[test]
+    fn axfr_is_bracketed_by_the_soa() {
+        let zone = Zone::new("example.com", zone_file(1, 3)).unwrap();
+        let records = axfr_records(&zone);
+        assert_eq!(records.len(), 7);
+        assert_eq!(serial(&records[0]), 1);
+        assert_eq!(serial(&records[6]), 1);
+    }
+
+    #[test]
+    fn ixfr_sends_journaled_changes_and_falls_back_to_axfr() {
+        let mut zone = Zone::new("example.com", zone_file(1, 2)).unwrap();
+        zone.replace(zone_file(2, 3)).unwrap();
+        zone.replace(zone_file(3, 1)).unwrap();
+
+        let records = ixfr_records(&zone, 1);
+        let serials: Vec<u32> = records
+            .iter()
+            .filter(|r| matches!(r, DnsRecord::SOA { .. }))
+            .map(serial)
+            .collect();
+        assert_eq!(serials, vec![3, 1, 2, 2, 3, 3]);
+        assert!(records.contains(&DnsRecord::A {
+            domain: "host2.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 2),
+            ttl: 60,
+        }));
+
+        assert_eq!(ixfr_records(&zone, 3).len(), 1);
+        assert_eq!(ixfr_records(&zone, 7).len(), axfr_records(&zone).len());
+    }
+
+    #[test]
+    fn replace_requires_a_newer_serial() {
+        let mut zone = Zone::new("example.com", zone_file(5, 1)).unwrap();
+        assert!(zone.replace(zone_file(5, 2)).is_err());
+        assert!(zone.replace(zone_file(5, 1)).is_ok());
+    }
+
+    #[test]
+    fn parses_full_and_incremental_transfers() {
+        let mut primary = Zone::new("example.com", zone_file(1, 2)).unwrap();
+        let full = axfr_records(&primary);
+        assert!(parse_transfer(&full[..3], None).unwrap().is_none());
+        match parse_transfer(&full, None).unwrap() {
+            Some(Transfer::Full(records)) => assert_eq!(records.len(), full.len() - 1),
+            other => panic!("expected a full transfer, got {:?}", other),
+        }
+
+        primary.replace(zone_file(2, 3)).unwrap();
+        primary.replace(zone_file(3, 1)).unwrap();
+        let incremental = ixfr_records(&primary, 1);
+        assert!(parse_transfer(&incremental[..4], Some(1)).unwrap().is_none());
+
+        let mut secondary = Zone::new("example.com", zone_file(1, 2)).unwrap();
+        match parse_transfer(&incremental, Some(1)).unwrap() {
+            Some(Transfer::Incremental(diffs)) => {
+                assert_eq!(diffs.len(), 2);
+                for diff in &diffs {
+                    secondary.apply(diff).unwrap();
+                }
+            }
+            other => panic!("expected an incremental transfer, got {:?}", other),
+        }
+        assert_eq!(axfr_records(&secondary), axfr_records(&primary));
+
+        assert!(matches!(parse_transfer(&ixfr_records(&primary, 3), Some(3)), Ok(Some(Transfer::UpToDate))));
+    }
+
+    #[test]
+    fn large_transfers_are_split_into_messages() {
+        let zone = Zone::new("example.com", zone_file(1, 5000)).unwrap();
+        let mut request = DnsPacket::new();
+        request.header.id = 7;
+        request.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::AXFR));
+
+        let messages = transfer_messages(&request, axfr_records(&zone)).unwrap();
+        assert!(messages.len() > 1);
+        let mut total = 0;
+        for (index, mut buffer) in messages.into_iter().enumerate() {
+            assert!(buffer.pos() <= MAX_MESSAGE_SIZE);
+            buffer.pos = 0;
+            let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+            assert_eq!(packet.header.id, 7);
+            assert_eq!(packet.questions.len(), usize::from(index == 0));
+            total += packet.answers.len();
+        }
+        assert_eq!(total, 5004);
+    }
+}
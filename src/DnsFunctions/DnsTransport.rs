@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Bytes;
+use hyper::client::conn::http2::SendRequest;
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Request, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::ring::default_provider;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+use crate::DnsErrors::DnsResolverError;
+use crate::DnsForwarder::{Transport, Upstream};
+use crate::DnsHttps;
+use crate::DnsTls;
+use crate::DnsTransfer::MAX_MESSAGE_SIZE;
+
+/// Idle DNS over TLS connections kept open to each upstream.
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+//<------------------------------ Encrypted upstreams ---------------------------------->
+
+/// Connections to forwarders reached over TLS or HTTPS. DNS over TLS
+/// connections are reused one query at a time; each DNS over HTTPS upstream
+/// gets one HTTP/2 connection that concurrent queries are multiplexed on.
+pub struct Transports {
+    provider: Arc<CryptoProvider>,
+    roots: Arc<RootCertStore>,
+    idle_tls: Mutex<HashMap<Upstream, Vec<TlsStream<TcpStream>>>>,
+    https: Mutex<HashMap<Upstream, SendRequest<Full<Bytes>>>>,
+}
+
+impl Transports {
+    /// Certificates are verified against the roots in the PEM file `ca_file`,
+    /// or the Mozilla root program's without one.
+    pub fn new(ca_file: Option<&Path>) -> Result<Transports, DnsResolverError> {
+        let roots = match ca_file {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in DnsTls::certificates(path)? {
+                    roots
+                        .add(certificate)
+                        .map_err(|e| DnsResolverError::ConfigError(format!("{}: {}", path.display(), e)))?;
+                }
+                roots
+            }
+            None => RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            },
+        };
+        Ok(Transports {
+            provider: Arc::new(default_provider()),
+            roots: Arc::new(roots),
+            idle_tls: Mutex::new(HashMap::new()),
+            https: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Sends `query` to an encrypted upstream and returns the response.
+    pub async fn exchange(&self, upstream: &Upstream, query: &[u8]) -> Result<Vec<u8>, DnsResolverError> {
+        match &upstream.transport {
+            Transport::Tls => self.exchange_tls(upstream, query).await,
+            Transport::Https(path) => self.exchange_https(upstream, path, query).await,
+            Transport::Udp => unreachable!("UDP upstreams are queried directly"),
+        }
+    }
+
+    async fn exchange_tls(&self, upstream: &Upstream, query: &[u8]) -> Result<Vec<u8>, DnsResolverError> {
+        let idle = self.idle_tls.lock().unwrap().get_mut(upstream).and_then(Vec::pop);
+        // The server may have closed an idle connection since; then open a new one.
+        let exchanged = match idle {
+            Some(stream) => exchange_framed(stream, query).await.ok(),
+            None => None,
+        };
+        let (stream, response) = match exchanged {
+            Some(exchanged) => exchanged,
+            None => exchange_framed(self.connect(upstream, b"dot").await?, query).await?,
+        };
+        let mut idle_tls = self.idle_tls.lock().unwrap();
+        let idle = idle_tls.entry(upstream.clone()).or_default();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(stream);
+        }
+        Ok(response)
+    }
+
+    async fn exchange_https(&self, upstream: &Upstream, path: &str, query: &[u8]) -> Result<Vec<u8>, DnsResolverError> {
+        let open = self.https.lock().unwrap().get(upstream).filter(|sender| !sender.is_closed()).cloned();
+        let mut sender = match open {
+            Some(sender) => sender,
+            None => {
+                let stream = self.connect(upstream, b"h2").await?;
+                let (sender, connection) =
+                    hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                        .await
+                        .map_err(network_error)?;
+                let name = upstream.to_string();
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        eprintln!("Connection to {} failed: {}", name, e);
+                    }
+                });
+                self.https.lock().unwrap().insert(upstream.clone(), sender.clone());
+                sender
+            }
+        };
+
+        let authority = match &upstream.name {
+            Some(name) => format!("{}:{}", name, upstream.addr.port()),
+            None => upstream.addr.to_string(),
+        };
+        let request = Request::post(format!("https://{}{}", authority, path))
+            .header(CONTENT_TYPE, DnsHttps::CONTENT_TYPE)
+            .header(ACCEPT, DnsHttps::CONTENT_TYPE)
+            .body(Full::new(Bytes::copy_from_slice(query)))
+            .map_err(network_error)?;
+        sender.ready().await.map_err(network_error)?;
+        let response = sender.send_request(request).await.map_err(network_error)?;
+        if response.status() != StatusCode::OK {
+            return Err(network_error(format!("HTTP status {}", response.status())));
+        }
+        let body = Limited::new(response.into_body(), MAX_MESSAGE_SIZE)
+            .collect()
+            .await
+            .map_err(network_error)?;
+        Ok(body.to_bytes().to_vec())
+    }
+
+    /// Opens a TLS connection to `upstream` offering the ALPN protocol `alpn`.
+    async fn connect(&self, upstream: &Upstream, alpn: &[u8]) -> Result<TlsStream<TcpStream>, DnsResolverError> {
+        let builder = ClientConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(network_error)?;
+        let mut config = match upstream.pin {
+            Some(pin) => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedKey {
+                    pin,
+                    provider: self.provider.clone(),
+                }))
+                .with_no_client_auth(),
+            None => builder.with_root_certificates(self.roots.clone()).with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![alpn.to_vec()];
+
+        let server_name = match &upstream.name {
+            Some(name) => ServerName::try_from(name.clone()).map_err(network_error)?,
+            None => ServerName::IpAddress(upstream.addr.ip().into()),
+        };
+        let stream = TcpStream::connect(upstream.addr).await?;
+        Ok(TlsConnector::from(Arc::new(config)).connect(server_name, stream).await?)
+    }
+}
+
+/// One length-prefixed exchange (RFC 1035 section 4.2.2), handing the
+/// connection back for reuse.
+async fn exchange_framed(
+    mut stream: TlsStream<TcpStream>,
+    query: &[u8],
+) -> io::Result<(TlsStream<TcpStream>, Vec<u8>)> {
+    let mut message = Vec::with_capacity(query.len() + 2);
+    message.extend((query.len() as u16).to_be_bytes());
+    message.extend(query);
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+    let length = stream.read_u16().await?;
+    let mut response = vec![0; length as usize];
+    stream.read_exact(&mut response).await?;
+    Ok((stream, response))
+}
+
+fn network_error(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> DnsResolverError {
+    DnsResolverError::NetworkError(io::Error::other(e))
+}
+
+/// Accepts a server whose certificate chain holds the pinned public key,
+/// whoever issued it and whatever name it is for.
+#[derive(Debug)]
+struct PinnedKey {
+    pin: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedKey {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .any(|certificate| spki_hash(certificate) == Some(self.pin));
+        match pinned {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::General("no certificate holds the pinned key".to_string())),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, certificate, signature, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, certificate, signature, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// SHA-256 of a certificate's DER-encoded SubjectPublicKeyInfo, as pinned.
+fn spki_hash(certificate: &CertificateDer<'_>) -> Option<[u8; 32]> {
+    let certificate = webpki::EndEntityCert::try_from(certificate).ok()?;
+    Some(Sha256::digest(certificate.subject_public_key_info().as_ref()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Dnspacket::{BytePacketBuffer, DnsPacket, DnsQuestion, QueryType};
+    use hyper::body::Incoming;
+    use hyper::service::service_fn;
+    use hyper::{Method, Response, Version};
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/DnsFunctions/testdata").join(name)
+    }
+
+    fn query(id: u16) -> Vec<u8> {
+        let mut query = DnsPacket::new();
+        query.header.id = id;
+        query.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        let mut buffer = BytePacketBuffer::new();
+        query.write(&mut buffer).unwrap();
+        buffer.buf[..buffer.pos].to_vec()
+    }
+
+    /// The query sent back as its own response.
+    fn answer(message: &[u8]) -> Vec<u8> {
+        let mut buffer = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+        buffer.buf[..message.len()].copy_from_slice(message);
+        let mut packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+        packet.header.response = true;
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.buf[..buffer.pos].to_vec()
+    }
+
+    fn response_id(message: &[u8]) -> u16 {
+        u16::from_be_bytes([message[0], message[1]])
+    }
+
+    fn upstream(addr: SocketAddr, transport: Transport, name: Option<&str>, pin: Option<[u8; 32]>) -> Upstream {
+        Upstream {
+            addr,
+            transport,
+            name: name.map(str::to_string),
+            pin,
+        }
+    }
+
+    /// A DNS over TLS server for localhost; counts the connections it accepts.
+    async fn dot_server() -> (SocketAddr, Arc<AtomicUsize>) {
+        let acceptor = DnsTls::acceptor(&testdata("localhost.pem"), &testdata("localhost.key"), &[b"dot"]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    while let Ok(length) = stream.read_u16().await {
+                        let mut message = vec![0; length as usize];
+                        stream.read_exact(&mut message).await.unwrap();
+                        let response = answer(&message);
+                        stream.write_u16(response.len() as u16).await.unwrap();
+                        stream.write_all(&response).await.unwrap();
+                        stream.flush().await.unwrap();
+                    }
+                });
+            }
+        });
+        (addr, connections)
+    }
+
+    /// A DNS over HTTPS server for localhost that only speaks HTTP/2.
+    async fn doh_server() -> SocketAddr {
+        let acceptor = DnsTls::acceptor(&testdata("localhost.pem"), &testdata("localhost.key"), &[b"h2"]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let stream = acceptor.accept(stream).await.unwrap();
+                let service = service_fn(|request: Request<Incoming>| async move {
+                    assert_eq!(request.version(), Version::HTTP_2);
+                    assert_eq!(request.method(), Method::POST);
+                    assert_eq!(request.uri().path(), DnsHttps::PATH);
+                    assert_eq!(request.headers()[CONTENT_TYPE], DnsHttps::CONTENT_TYPE);
+                    let body = request.into_body().collect().await?.to_bytes();
+                    Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::from(answer(&body)))))
+                });
+                tokio::spawn(
+                    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn reuses_idle_tls_connections() {
+        let (addr, connections) = dot_server().await;
+        let transports = Transports::new(Some(&testdata("ca.pem"))).unwrap();
+        let upstream = upstream(addr, Transport::Tls, Some("localhost"), None);
+
+        for id in [1, 2] {
+            let response = transports.exchange(&upstream, &query(id)).await.unwrap();
+            assert_eq!(response_id(&response), id);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn verifies_names_against_the_configured_roots() {
+        let (addr, _) = dot_server().await;
+        let transports = Transports::new(Some(&testdata("ca.pem"))).unwrap();
+
+        // The certificate is for localhost and 127.0.0.1.
+        let by_address = upstream(addr, Transport::Tls, None, None);
+        assert!(transports.exchange(&by_address, &query(1)).await.is_ok());
+        let other_name = upstream(addr, Transport::Tls, Some("dns.example"), None);
+        assert!(transports.exchange(&other_name, &query(2)).await.is_err());
+
+        // Nor is a server trusted that the roots did not issue.
+        let public_roots = Transports::new(None).unwrap();
+        let localhost = upstream(addr, Transport::Tls, Some("localhost"), None);
+        assert!(public_roots.exchange(&localhost, &query(3)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn checks_pinned_keys_instead_of_the_chain() {
+        let (addr, _) = dot_server().await;
+        // Roots that did not issue the certificate, to show the pin alone decides.
+        let transports = Transports::new(None).unwrap();
+        let leaf = &DnsTls::certificates(&testdata("localhost.pem")).unwrap()[0];
+        let pin = spki_hash(leaf).unwrap();
+
+        let pinned = upstream(addr, Transport::Tls, Some("dns.example"), Some(pin));
+        let response = transports.exchange(&pinned, &query(1)).await.unwrap();
+        assert_eq!(response_id(&response), 1);
+        let mispinned = upstream(addr, Transport::Tls, Some("dns.example"), Some([0; 32]));
+        assert!(transports.exchange(&mispinned, &query(2)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn posts_queries_over_http2() {
+        let addr = doh_server().await;
+        let transports = Transports::new(Some(&testdata("ca.pem"))).unwrap();
+        let upstream = upstream(addr, Transport::Https(DnsHttps::PATH.to_string()), Some("localhost"), None);
+
+        for id in [1, 2] {
+            let response = transports.exchange(&upstream, &query(id)).await.unwrap();
+            assert_eq!(response_id(&response), id);
+        }
+    }
+}
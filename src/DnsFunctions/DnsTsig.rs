@@ -0,0 +1,533 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Error as E;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha512};
+
+use crate::Dnspacket::{BytePacketBuffer, DnsHeader, DnsQuestion, DnsRecord, QueryType};
+
+/// Seconds of clock difference tolerated between signer and verifier.
+pub const FUDGE: u16 = 300;
+const CLASS_ANY: u16 = 255;
+// RFC 8945 section 5.3.1: at most 99 unsigned messages in a row in a stream.
+const MAX_UNSIGNED_MESSAGES: usize = 99;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn sign(&self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            TsigAlgorithm::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            TsigAlgorithm::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    fn verify(&self, secret: &[u8], data: &[u8], expected: &[u8]) -> bool {
+        match self {
+            TsigAlgorithm::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+                mac.update(data);
+                mac.verify_slice(expected).is_ok()
+            }
+            TsigAlgorithm::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+                mac.update(data);
+                mac.verify_slice(expected).is_ok()
+            }
+        }
+    }
+}
+
+impl FromStr for TsigAlgorithm {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim_end_matches('.').to_lowercase().as_str() {
+            "hmac-sha256" => Ok(TsigAlgorithm::HmacSha256),
+            "hmac-sha512" => Ok(TsigAlgorithm::HmacSha512),
+            _ => Err(format!("expected hmac-sha256 or hmac-sha512, got `{}`", value)),
+        }
+    }
+}
+
+//<------------------------------------- Keys ----------------------------------------->
+
+#[derive(Clone)]
+pub struct TsigKey {
+    pub name: String,
+    pub algorithm: TsigAlgorithm,
+    secret: Vec<u8>,
+}
+
+impl TsigKey {
+    pub fn new(name: &str, algorithm: TsigAlgorithm, secret: Vec<u8>) -> TsigKey {
+        TsigKey {
+            name: name.trim_end_matches('.').to_lowercase(),
+            algorithm,
+            secret,
+        }
+    }
+
+    /// `xfr-key hmac-sha256 <base64 secret>`
+    pub fn parse(value: &str) -> Result<TsigKey, String> {
+        let fields: Vec<&str> = value.split_whitespace().collect();
+        let [name, algorithm, secret] = fields[..] else {
+            return Err("expected `<key name> <algorithm> <base64 secret>`".to_string());
+        };
+        let secret = base64::engine::general_purpose::STANDARD
+            .decode(secret)
+            .map_err(|e| format!("invalid secret for key {}: {}", name, e))?;
+        Ok(TsigKey::new(name, algorithm.parse()?, secret))
+    }
+}
+
+// Secrets stay out of logs and error messages.
+impl fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TsigKey")
+            .field("name", &self.name)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Keyring {
+    keys: HashMap<String, TsigKey>,
+}
+
+impl Keyring {
+    pub fn new(keys: &[TsigKey]) -> Keyring {
+        Keyring {
+            keys: keys.iter().map(|key| (key.name.clone(), key.clone())).collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TsigKey> {
+        self.keys.get(&name.trim_end_matches('.').to_lowercase())
+    }
+
+    /// Checks the TSIG on a received request. Unsigned requests give `None`;
+    /// signed ones give the session their responses have to be signed with.
+    pub fn verify_request(&self, buffer: &BytePacketBuffer) -> Result<Option<TsigSession>, TsigFailure> {
+        let Ok((message, Some(tsig))) = split_tsig(buffer) else {
+            return Ok(None);
+        };
+        let key = match self.get(&tsig.key_name) {
+            Some(key) if tsig.algorithm.parse() == Ok(key.algorithm) => key,
+            _ => return Err(TsigFailure::new(TsigError::BADKEY, tsig, None)),
+        };
+
+        let mut session = TsigSession::new(key.clone());
+        if !session.verify_mac(&message, &tsig, true) {
+            return Err(TsigFailure::new(TsigError::BADSIG, tsig, None));
+        }
+        session.previous_mac = tsig.mac.clone();
+        session.original_id = Some(tsig.original_id);
+        session.received = 1;
+
+        if !within_fudge(&tsig) {
+            session.error = TsigError::BADTIME as u16;
+            session.other = time_bytes(now());
+            return Err(TsigFailure::new(TsigError::BADTIME, tsig, Some(session)));
+        }
+        Ok(Some(session))
+    }
+}
+
+//<------------------------------------ Sessions --------------------------------------->
+
+/// TSIG error codes (RFC 8945 section 3), sent with RCODE NOTAUTH.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TsigError {
+    BADSIG = 16,
+    BADKEY = 17,
+    BADTIME = 18,
+}
+
+/// One signed exchange: a request and its response messages, each MAC chained
+/// to the one before it so a stream cannot be reordered or cut short unnoticed.
+#[derive(Debug)]
+pub struct TsigSession {
+    key: TsigKey,
+    previous_mac: Vec<u8>,
+    original_id: Option<u16>,
+    sent: usize,
+    received: usize,
+    // Messages received since the last signed one, which its MAC also covers.
+    unsigned: Vec<u8>,
+    unsigned_count: usize,
+    error: u16,
+    other: Vec<u8>,
+}
+
+impl TsigSession {
+    /// A session for a request we are about to send.
+    pub fn new(key: TsigKey) -> TsigSession {
+        TsigSession {
+            key,
+            previous_mac: Vec::new(),
+            original_id: None,
+            sent: 0,
+            received: 0,
+            unsigned: Vec::new(),
+            unsigned_count: 0,
+            error: 0,
+            other: Vec::new(),
+        }
+    }
+
+    pub fn key_name(&self) -> &str {
+        &self.key.name
+    }
+
+    /// Appends a TSIG record to the message in `buffer`. The first message in
+    /// each direction carries every TSIG variable, later ones only the timers.
+    pub fn sign(&mut self, buffer: &mut BytePacketBuffer) -> Result<(), E> {
+        self.sign_at(buffer, now())
+    }
+
+    fn sign_at(&mut self, buffer: &mut BytePacketBuffer, time_signed: u64) -> Result<(), E> {
+        let id = u16::from_be_bytes([buffer.buf[0], buffer.buf[1]]);
+        let mut tsig = Tsig {
+            key_name: self.key.name.clone(),
+            algorithm: self.key.algorithm.name().to_string(),
+            time_signed,
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: self.original_id.unwrap_or(id),
+            error: self.error,
+            other: self.other.clone(),
+        };
+        let data = self.digest(&buffer.buf[..buffer.pos], &tsig, self.sent == 0);
+        tsig.mac = self.key.algorithm.sign(&self.key.secret, &data);
+        append(buffer, &tsig)?;
+
+        self.previous_mac = tsig.mac;
+        self.sent += 1;
+        Ok(())
+    }
+
+    /// Checks one response message of the exchange. Messages after the first
+    /// may come unsigned; Ok(false) means this one was, and is only covered
+    /// once a later signed message arrives.
+    pub fn verify(&mut self, buffer: &BytePacketBuffer) -> Result<bool, String> {
+        let (message, tsig) = split_tsig(buffer).map_err(|e| e.to_string())?;
+        let Some(tsig) = tsig else {
+            if self.received == 0 {
+                return Err("response is not signed".to_string());
+            }
+            self.unsigned_count += 1;
+            if self.unsigned_count > MAX_UNSIGNED_MESSAGES {
+                return Err("too many unsigned messages".to_string());
+            }
+            self.unsigned.extend_from_slice(&message);
+            return Ok(false);
+        };
+
+        if tsig.key_name != self.key.name {
+            return Err(format!("response signed with key {}", tsig.key_name));
+        }
+        if tsig.mac.is_empty() && tsig.error != 0 {
+            return Err(format!("server reported TSIG error {}", tsig.error));
+        }
+        let mut messages = std::mem::take(&mut self.unsigned);
+        messages.extend_from_slice(&message);
+        if !self.verify_mac(&messages, &tsig, self.received == 0) {
+            return Err("bad TSIG signature".to_string());
+        }
+        if tsig.error != 0 {
+            return Err(format!("server reported TSIG error {}", tsig.error));
+        }
+        if !within_fudge(&tsig) {
+            return Err("TSIG time outside the allowed fudge".to_string());
+        }
+
+        self.previous_mac = tsig.mac;
+        self.received += 1;
+        self.unsigned_count = 0;
+        Ok(true)
+    }
+
+    fn verify_mac(&self, messages: &[u8], tsig: &Tsig, full: bool) -> bool {
+        let algorithm: Result<TsigAlgorithm, _> = tsig.algorithm.parse();
+        if algorithm != Ok(self.key.algorithm) {
+            return false;
+        }
+        let data = self.digest(messages, tsig, full);
+        self.key.algorithm.verify(&self.key.secret, &data, &tsig.mac)
+    }
+
+    /// The data a MAC covers (RFC 8945 section 4.3): the MAC it answers, the
+    /// message(s), then the TSIG variables or, for later messages, the timers.
+    fn digest(&self, messages: &[u8], tsig: &Tsig, full: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+        if !self.previous_mac.is_empty() {
+            data.extend_from_slice(&(self.previous_mac.len() as u16).to_be_bytes());
+            data.extend_from_slice(&self.previous_mac);
+        }
+        data.extend_from_slice(messages);
+        if full {
+            data.extend(wire_name(&tsig.key_name));
+            data.extend_from_slice(&CLASS_ANY.to_be_bytes());
+            data.extend_from_slice(&0u32.to_be_bytes());
+            data.extend(wire_name(&tsig.algorithm));
+            data.extend(time_bytes(tsig.time_signed));
+            data.extend_from_slice(&tsig.fudge.to_be_bytes());
+            data.extend_from_slice(&tsig.error.to_be_bytes());
+            data.extend_from_slice(&(tsig.other.len() as u16).to_be_bytes());
+            data.extend_from_slice(&tsig.other);
+        } else {
+            data.extend(time_bytes(tsig.time_signed));
+            data.extend_from_slice(&tsig.fudge.to_be_bytes());
+        }
+        data
+    }
+}
+
+/// A request whose TSIG did not check out. The error response still carries a
+/// TSIG record, signed only when the key and MAC were good (BADTIME).
+#[derive(Debug)]
+pub struct TsigFailure {
+    pub error: TsigError,
+    request: Box<Tsig>,
+    session: Option<Box<TsigSession>>,
+}
+
+impl TsigFailure {
+    fn new(error: TsigError, request: Tsig, session: Option<TsigSession>) -> TsigFailure {
+        TsigFailure {
+            error,
+            request: Box::new(request),
+            session: session.map(Box::new),
+        }
+    }
+
+    pub fn key_name(&self) -> &str {
+        &self.request.key_name
+    }
+
+    pub fn sign(&mut self, buffer: &mut BytePacketBuffer) -> Result<(), E> {
+        if let Some(session) = &mut self.session {
+            return session.sign(buffer);
+        }
+        let tsig = Tsig {
+            time_signed: now(),
+            mac: Vec::new(),
+            error: self.error as u16,
+            other: Vec::new(),
+            ..(*self.request).clone()
+        };
+        append(buffer, &tsig)
+    }
+}
+
+//<---------------------------------- Wire format ------------------------------------->
+
+/// The TSIG RDATA fields, with the owner name as `key_name`.
+#[derive(Clone, Debug)]
+struct Tsig {
+    key_name: String,
+    algorithm: String,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+/// Splits a received message into the bytes its MAC covers (the message without
+/// its TSIG record, with ARCOUNT and ID as they were before signing) and the
+/// TSIG record itself, which has to be the last additional record.
+fn split_tsig(buffer: &BytePacketBuffer) -> Result<(Vec<u8>, Option<Tsig>), E> {
+    let mut reader = buffer.clone();
+    reader.pos = 0;
+    let mut header = DnsHeader::new();
+    header.read(&mut reader)?;
+    for _ in 0..header.questions {
+        let mut question = DnsQuestion::new(String::new(), QueryType::UNKNOWN(0));
+        question.read(&mut reader)?;
+    }
+    let records = header.answers as usize + header.authoritative_entries as usize + header.resource_entries as usize;
+    let mut last = (reader.pos(), None);
+    for _ in 0..records {
+        let start = reader.pos();
+        last = (start, Some(DnsRecord::read(&mut reader)?));
+    }
+    let end = reader.pos();
+
+    let tsig = match last {
+        (
+            start,
+            Some(DnsRecord::TSIG { domain, algorithm, time_signed, fudge, mac, original_id, error, other }),
+        ) if header.resource_entries > 0 => {
+            let tsig = Tsig { key_name: domain, algorithm, time_signed, fudge, mac, original_id, error, other };
+            let mut message = buffer.buf[..start].to_vec();
+            message[0..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+            message[10..12].copy_from_slice(&(header.resource_entries - 1).to_be_bytes());
+            return Ok((message, Some(tsig)));
+        }
+        _ => None,
+    };
+    Ok((buffer.buf[..end].to_vec(), tsig))
+}
+
+/// Writes the TSIG record after the message in `buffer` and counts it in ARCOUNT.
+fn append(buffer: &mut BytePacketBuffer, tsig: &Tsig) -> Result<(), E> {
+    let record = DnsRecord::TSIG {
+        domain: tsig.key_name.clone(),
+        algorithm: tsig.algorithm.clone(),
+        time_signed: tsig.time_signed,
+        fudge: tsig.fudge,
+        mac: tsig.mac.clone(),
+        original_id: tsig.original_id,
+        error: tsig.error,
+        other: tsig.other.clone(),
+    };
+    record.write(buffer)?;
+    let additional = u16::from_be_bytes([buffer.buf[10], buffer.buf[11]]) + 1;
+    buffer.buf[10..12].copy_from_slice(&additional.to_be_bytes());
+    Ok(())
+}
+
+/// A name in canonical form: uncompressed, lower case labels.
+fn wire_name(name: &str) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        wire.push(label.len() as u8);
+        wire.extend(label.to_lowercase().bytes());
+    }
+    wire.push(0);
+    wire
+}
+
+/// Time signed is a 48 bit count of seconds.
+fn time_bytes(time: u64) -> Vec<u8> {
+    time.to_be_bytes()[2..].to_vec()
+}
+
+fn within_fudge(tsig: &Tsig) -> bool {
+    now().abs_diff(tsig.time_signed) <= u64::from(tsig.fudge)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Dnspacket::DnsPacket;
+
+    fn key(name: &str) -> TsigKey {
+        TsigKey::parse(&format!("{} hmac-sha256 c2VjcmV0IGtleSBmb3IgdGVzdHM=", name)).unwrap()
+    }
+
+    fn message(id: u16, response: bool) -> BytePacketBuffer {
+        let mut packet = DnsPacket::new();
+        packet.header.id = id;
+        packet.header.response = response;
+        packet.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::AXFR));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn signed_request_and_response_stream_verify() {
+        let keyring = Keyring::new(&[key("xfr-key")]);
+        let mut client = TsigSession::new(key("xfr-key"));
+        let mut request = message(7, false);
+        client.sign(&mut request).unwrap();
+        assert_eq!(request.buf[11], 1);
+
+        let mut server = keyring.verify_request(&request).unwrap().unwrap();
+        assert_eq!(server.key_name(), "xfr-key");
+
+        let mut responses = vec![message(7, true), message(7, true), message(7, true)];
+        for response in &mut responses {
+            server.sign(response).unwrap();
+        }
+        for response in &responses {
+            assert_eq!(client.verify(response), Ok(true));
+        }
+
+        // The chain breaks if a message goes missing.
+        let mut client = TsigSession::new(key("xfr-key"));
+        let mut request = message(7, false);
+        client.sign(&mut request).unwrap();
+        let mut server = keyring.verify_request(&request).unwrap().unwrap();
+        let mut responses = vec![message(7, true), message(7, true)];
+        for response in &mut responses {
+            server.sign(response).unwrap();
+        }
+        assert_eq!(client.verify(&responses[1]), Err("bad TSIG signature".to_string()));
+    }
+
+    #[test]
+    fn unsigned_requests_have_no_session() {
+        let keyring = Keyring::new(&[key("xfr-key")]);
+        assert!(keyring.verify_request(&message(7, false)).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_keys_bad_macs_and_stale_times() {
+        let keyring = Keyring::new(&[key("xfr-key")]);
+
+        let mut request = message(7, false);
+        TsigSession::new(key("other-key")).sign(&mut request).unwrap();
+        assert_eq!(keyring.verify_request(&request).unwrap_err().error, TsigError::BADKEY);
+
+        let wrong_secret = TsigKey::new("xfr-key", TsigAlgorithm::HmacSha256, b"wrong".to_vec());
+        let mut request = message(7, false);
+        TsigSession::new(wrong_secret).sign(&mut request).unwrap();
+        assert_eq!(keyring.verify_request(&request).unwrap_err().error, TsigError::BADSIG);
+
+        let mut client = TsigSession::new(key("xfr-key"));
+        let mut request = message(7, false);
+        client.sign_at(&mut request, now() - 3600).unwrap();
+        let mut failure = keyring.verify_request(&request).unwrap_err();
+        assert_eq!(failure.error, TsigError::BADTIME);
+
+        // BADTIME answers are signed, so the client can trust the reported time.
+        let mut response = message(7, true);
+        failure.sign(&mut response).unwrap();
+        assert_eq!(client.verify(&response), Err("server reported TSIG error 18".to_string()));
+    }
+
+    #[test]
+    fn parses_keys() {
+        let key = TsigKey::parse("Xfr-Key. hmac-sha512 c2VjcmV0").unwrap();
+        assert_eq!(key.name, "xfr-key");
+        assert_eq!(key.algorithm, TsigAlgorithm::HmacSha512);
+        assert_eq!(key.secret, b"secret");
+        assert!(TsigKey::parse("xfr-key hmac-md5 c2VjcmV0").is_err());
+        assert!(TsigKey::parse("xfr-key hmac-sha256 !!!").is_err());
+    }
+}
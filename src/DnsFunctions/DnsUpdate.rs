@@ -0,0 +1,418 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Error as E;
+
+use crate::DnsBailiwick::is_subdomain;
+use crate::DnsZone::{serial_gt, Zone};
+use crate::Dnspacket::{BytePacketBuffer, DnsHeader, DnsQuestion, DnsRecord, QueryType, ResultCode};
+
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+
+/// One entry of the prerequisite or update section. What it means depends on
+/// the class, which is why these are read straight off the wire rather than
+/// through `DnsPacket`, and `record` is None when the rdata is empty.
+#[derive(Clone, Debug)]
+pub struct UpdateRecord {
+    pub name: String,
+    pub rtype: QueryType,
+    pub class: u16,
+    pub ttl: u32,
+    pub record: Option<DnsRecord>,
+}
+
+/// An UPDATE message (RFC 2136): the question section names the zone, the
+/// answer section holds prerequisites and the authority section the updates.
+#[derive(Clone, Debug)]
+pub struct UpdateMessage {
+    pub zone: String,
+    pub prerequisites: Vec<UpdateRecord>,
+    pub updates: Vec<UpdateRecord>,
+}
+
+//<------------------------------------ Parsing ---------------------------------------->
+
+pub fn parse_update(buffer: &BytePacketBuffer) -> Result<UpdateMessage, ResultCode> {
+    let mut buffer = buffer.clone();
+    buffer.pos = 0;
+    let formerr = |_: E| ResultCode::FORMERR;
+
+    let mut header = DnsHeader::new();
+    header.read(&mut buffer).map_err(formerr)?;
+    if header.questions != 1 {
+        return Err(ResultCode::FORMERR);
+    }
+    let mut zone = DnsQuestion::new(String::new(), QueryType::SOA);
+    zone.read(&mut buffer).map_err(formerr)?;
+    if zone.qtype != QueryType::SOA {
+        return Err(ResultCode::FORMERR);
+    }
+
+    let prerequisites = (0..header.answers)
+        .map(|_| read_update_record(&mut buffer))
+        .collect::<Result<_, _>>()
+        .map_err(formerr)?;
+    let updates = (0..header.authoritative_entries)
+        .map(|_| read_update_record(&mut buffer))
+        .collect::<Result<_, _>>()
+        .map_err(formerr)?;
+    Ok(UpdateMessage {
+        zone: zone.name.trim_end_matches('.').to_lowercase(),
+        prerequisites,
+        updates,
+    })
+}
+
+fn read_update_record(buffer: &mut BytePacketBuffer) -> Result<UpdateRecord, E> {
+    let start = buffer.pos();
+    let mut name = String::new();
+    buffer.read_qname(&mut name)?;
+    let rtype = QueryType::from_num(buffer.read_u16()?);
+    let class = buffer.read_u16()?;
+    let ttl = buffer.read_u32()?;
+    let data_len = buffer.read_u16()? as usize;
+    let end = buffer.pos() + data_len;
+
+    let record = if data_len == 0 {
+        None
+    } else {
+        buffer.pos = start;
+        Some(DnsRecord::read(buffer)?)
+    };
+    buffer.pos = end;
+    Ok(UpdateRecord {
+        name,
+        rtype,
+        class,
+        ttl,
+        record,
+    })
+}
+
+//<------------------------------------ Applying --------------------------------------->
+
+/// Checks the prerequisites and applies the updates to `zone` as a whole or
+/// not at all. Unless the update brings its own newer SOA, the serial is
+/// bumped; the zone journals the change for IXFR. Returns whether anything changed.
+pub fn apply_update(zone: &mut Zone, update: &UpdateMessage) -> Result<bool, ResultCode> {
+    let origin = zone.origin.clone();
+    let mut records: Vec<DnsRecord> = zone.records().cloned().collect();
+    check_prerequisites(&origin, &records, &update.prerequisites)?;
+    prescan(&origin, &update.updates)?;
+
+    let old_serial = zone.serial();
+    let mut changed = false;
+    for update in &update.updates {
+        changed |= apply_one(&origin, &mut records, update);
+    }
+    if !changed {
+        return Ok(false);
+    }
+
+    for record in records.iter_mut() {
+        if let DnsRecord::SOA { serial, .. } = record {
+            if *serial == old_serial {
+                *serial = serial.wrapping_add(1);
+            }
+        }
+    }
+    zone.replace(records).map_err(|e| {
+        eprintln!("Error: {}", e);
+        ResultCode::SERVFAIL
+    })?;
+    Ok(true)
+}
+
+/// RFC 2136 section 3.2.
+fn check_prerequisites(
+    origin: &str,
+    records: &[DnsRecord],
+    prerequisites: &[UpdateRecord],
+) -> Result<(), ResultCode> {
+    let mut expected: HashMap<(&str, QueryType), HashSet<DnsRecord>> = HashMap::new();
+    for prerequisite in prerequisites {
+        if prerequisite.ttl != 0 {
+            return Err(ResultCode::FORMERR);
+        }
+        if !is_subdomain(&prerequisite.name, origin) {
+            return Err(ResultCode::NOTZONE);
+        }
+        let name = prerequisite.name.as_str();
+        let exists = |rtype: QueryType| {
+            records
+                .iter()
+                .any(|r| r.domain() == Some(name) && (rtype == QueryType::ANY || r.query_type() == rtype))
+        };
+
+        match (prerequisite.class, &prerequisite.record) {
+            (CLASS_ANY, None) if prerequisite.rtype == QueryType::ANY => {
+                if !exists(QueryType::ANY) {
+                    return Err(ResultCode::NXDOMAIN);
+                }
+            }
+            (CLASS_ANY, None) => {
+                if !exists(prerequisite.rtype) {
+                    return Err(ResultCode::NXRRSET);
+                }
+            }
+            (CLASS_NONE, None) if prerequisite.rtype == QueryType::ANY => {
+                if exists(QueryType::ANY) {
+                    return Err(ResultCode::YXDOMAIN);
+                }
+            }
+            (CLASS_NONE, None) => {
+                if exists(prerequisite.rtype) {
+                    return Err(ResultCode::YXRRSET);
+                }
+            }
+            (CLASS_IN, Some(record)) => {
+                expected
+                    .entry((name, prerequisite.rtype))
+                    .or_default()
+                    .insert(record.with_ttl(0));
+            }
+            _ => return Err(ResultCode::FORMERR),
+        }
+    }
+
+    // Value dependent prerequisites must match the whole RRset, TTLs aside.
+    for ((name, rtype), expected) in expected {
+        let actual: HashSet<DnsRecord> = records
+            .iter()
+            .filter(|r| r.domain() == Some(name) && r.query_type() == rtype)
+            .map(|r| r.with_ttl(0))
+            .collect();
+        if actual != expected {
+            return Err(ResultCode::NXRRSET);
+        }
+    }
+    Ok(())
+}
+
+/// RFC 2136 section 3.4.1: reject the whole message before touching the zone.
+fn prescan(origin: &str, updates: &[UpdateRecord]) -> Result<(), ResultCode> {
+    for update in updates {
+        if !is_subdomain(&update.name, origin) {
+            return Err(ResultCode::NOTZONE);
+        }
+        let meta = matches!(update.rtype, QueryType::ANY | QueryType::AXFR | QueryType::IXFR | QueryType::OPT);
+        match (update.class, &update.record) {
+            (CLASS_IN, Some(DnsRecord::UNKNOWN { .. })) => return Err(ResultCode::NOTIMP),
+            (CLASS_IN, Some(_)) if !meta => (),
+            (CLASS_ANY, None) if update.ttl == 0 && !matches!(update.rtype, QueryType::AXFR | QueryType::IXFR) => (),
+            (CLASS_NONE, Some(_)) if update.ttl == 0 && !meta => (),
+            _ => return Err(ResultCode::FORMERR),
+        }
+    }
+    Ok(())
+}
+
+/// RFC 2136 section 3.4.2, for one update that passed the prescan.
+fn apply_one(origin: &str, records: &mut Vec<DnsRecord>, update: &UpdateRecord) -> bool {
+    let name = update.name.as_str();
+    let at_apex = name == origin;
+    let owned = |r: &DnsRecord| r.domain() == Some(name);
+
+    match (update.class, &update.record) {
+        (CLASS_IN, Some(record)) => {
+            let rtype = record.query_type();
+            if rtype == QueryType::SOA {
+                let newer = match (records.iter().find(|r| r.query_type() == QueryType::SOA), record) {
+                    (Some(DnsRecord::SOA { serial: old, .. }), DnsRecord::SOA { serial: new, .. }) => serial_gt(*new, *old),
+                    _ => false,
+                };
+                if !at_apex || !newer {
+                    return false;
+                }
+                records.retain(|r| r.query_type() != QueryType::SOA);
+                records.insert(0, record.clone());
+                return true;
+            }
+
+            // A CNAME cannot share its name with other data.
+            let has_cname = records.iter().any(|r| owned(r) && r.query_type() == QueryType::CNAME);
+            let has_other = records.iter().any(|r| owned(r) && r.query_type() != QueryType::CNAME);
+            if (rtype == QueryType::CNAME && has_other) || (rtype != QueryType::CNAME && has_cname) {
+                return false;
+            }
+
+            let existing = records.iter_mut().find(|r| {
+                owned(r)
+                    && r.query_type() == rtype
+                    && (rtype == QueryType::CNAME || r.with_ttl(0) == record.with_ttl(0))
+            });
+            match existing {
+                Some(existing) if existing == record => false,
+                Some(existing) => {
+                    *existing = record.clone();
+                    true
+                }
+                None => {
+                    records.push(record.clone());
+                    true
+                }
+            }
+        }
+        (CLASS_ANY, None) => {
+            let before = records.len();
+            records.retain(|r| {
+                let protected = at_apex && matches!(r.query_type(), QueryType::SOA | QueryType::NS);
+                !owned(r) || protected || (update.rtype != QueryType::ANY && r.query_type() != update.rtype)
+            });
+            records.len() != before
+        }
+        (CLASS_NONE, Some(record)) => {
+            let rtype = record.query_type();
+            if rtype == QueryType::SOA {
+                return false;
+            }
+            if at_apex && rtype == QueryType::NS {
+                let apex_ns = records.iter().filter(|r| owned(r) && r.query_type() == QueryType::NS).count();
+                if apex_ns <= 1 {
+                    return false;
+                }
+            }
+            let before = records.len();
+            records.retain(|r| !(owned(r) && r.with_ttl(0) == record.with_ttl(0)));
+            records.len() != before
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DnsZoneFile::parse_zone;
+    use std::net::Ipv4Addr;
+
+    const ZONE: &str = "
+$TTL 300
+@     SOA ns1 hostmaster 10 3600 600 86400 60
+      NS  ns1
+ns1   A   192.0.2.1
+www   A   192.0.2.80
+www   A   192.0.2.81
+alias CNAME www
+";
+
+    fn zone() -> Zone {
+        Zone::new("example.com", parse_zone(ZONE, "example.com").unwrap()).unwrap()
+    }
+
+    fn a(name: &str, last: u8, ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: name.to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, last),
+            ttl,
+        }
+    }
+
+    fn entry(class: u16, rtype: QueryType, name: &str, record: Option<DnsRecord>) -> UpdateRecord {
+        UpdateRecord {
+            name: name.to_string(),
+            rtype,
+            class,
+            ttl: match (&record, class) {
+                (Some(DnsRecord::A { ttl, .. } | DnsRecord::CNAME { ttl, .. }), CLASS_IN) => *ttl,
+                _ => 0,
+            },
+            record,
+        }
+    }
+
+    fn message(prerequisites: Vec<UpdateRecord>, updates: Vec<UpdateRecord>) -> UpdateMessage {
+        UpdateMessage {
+            zone: "example.com".to_string(),
+            prerequisites,
+            updates,
+        }
+    }
+
+    fn lookup(zone: &Zone, name: &str) -> Vec<DnsRecord> {
+        zone.answer(name, QueryType::A).answers
+    }
+
+    #[test]
+    fn adds_records_and_bumps_the_serial() {
+        let mut zone = zone();
+        let update = message(
+            vec![entry(CLASS_NONE, QueryType::ANY, "host.example.com", None)],
+            vec![entry(CLASS_IN, QueryType::A, "host.example.com", Some(a("host.example.com", 9, 120)))],
+        );
+        assert_eq!(apply_update(&mut zone, &update), Ok(true));
+        assert_eq!(lookup(&zone, "host.example.com"), vec![a("host.example.com", 9, 120)]);
+        assert_eq!(zone.serial(), 11);
+        assert_eq!(zone.changes_since(10).map(|changes| changes.len()), Some(1));
+
+        // The name exists now, so the same prerequisite fails and nothing changes.
+        assert_eq!(apply_update(&mut zone, &update), Err(ResultCode::YXDOMAIN));
+        assert_eq!(zone.serial(), 11);
+    }
+
+    #[test]
+    fn value_dependent_prerequisites_compare_whole_rrsets() {
+        let mut zone = zone();
+        let partial = message(
+            vec![entry(CLASS_IN, QueryType::A, "www.example.com", Some(a("www.example.com", 80, 0)))],
+            vec![entry(CLASS_ANY, QueryType::A, "www.example.com", None)],
+        );
+        assert_eq!(apply_update(&mut zone, &partial), Err(ResultCode::NXRRSET));
+
+        let full = message(
+            vec![
+                entry(CLASS_IN, QueryType::A, "www.example.com", Some(a("www.example.com", 80, 0))),
+                entry(CLASS_IN, QueryType::A, "www.example.com", Some(a("www.example.com", 81, 0))),
+            ],
+            vec![entry(CLASS_ANY, QueryType::A, "www.example.com", None)],
+        );
+        assert_eq!(apply_update(&mut zone, &full), Ok(true));
+        assert!(lookup(&zone, "www.example.com").is_empty());
+    }
+
+    #[test]
+    fn updates_are_all_or_nothing() {
+        let mut zone = zone();
+        let update = message(
+            Vec::new(),
+            vec![
+                entry(CLASS_IN, QueryType::A, "new.example.com", Some(a("new.example.com", 5, 60))),
+                entry(CLASS_IN, QueryType::A, "www.example.org", Some(a("www.example.org", 5, 60))),
+            ],
+        );
+        assert_eq!(apply_update(&mut zone, &update), Err(ResultCode::NOTZONE));
+        assert!(lookup(&zone, "new.example.com").is_empty());
+        assert_eq!(zone.serial(), 10);
+    }
+
+    #[test]
+    fn protects_the_apex_and_cname_exclusivity() {
+        let mut zone = zone();
+        let update = message(
+            Vec::new(),
+            vec![
+                entry(CLASS_ANY, QueryType::ANY, "example.com", None),
+                entry(CLASS_NONE, QueryType::NS, "example.com", Some(DnsRecord::NS {
+                    domain: "example.com".to_string(),
+                    host: "ns1.example.com".to_string(),
+                    ttl: 0,
+                })),
+                entry(CLASS_IN, QueryType::A, "alias.example.com", Some(a("alias.example.com", 7, 60))),
+            ],
+        );
+        assert_eq!(apply_update(&mut zone, &update), Ok(false));
+        assert_eq!(zone.serial(), 10);
+        assert_eq!(zone.answer("example.com", QueryType::NS).answers.len(), 1);
+    }
+
+    #[test]
+    fn deletes_single_records_ignoring_ttl() {
+        let mut zone = zone();
+        let update = message(
+            Vec::new(),
+            vec![entry(CLASS_NONE, QueryType::A, "www.example.com", Some(a("www.example.com", 80, 0)))],
+        );
+        assert_eq!(apply_update(&mut zone, &update), Ok(true));
+        assert_eq!(lookup(&zone, "www.example.com"), vec![a("www.example.com", 81, 300)]);
+    }
+}
@@ -0,0 +1,436 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use crate::DnsBailiwick::is_subdomain;
+use crate::DnsErrors::DnsResolverError;
+use crate::Dnspacket::{DnsPacket, DnsRecord, QueryType, ResultCode};
+
+const MAX_CNAME_CHAIN: usize = 8;
+const MAX_JOURNAL_ENTRIES: usize = 64;
+
+/// The changes that took a zone from one SOA serial to the next, as IXFR sends them.
+#[derive(Clone, Debug)]
+pub struct ZoneDiff {
+    pub old_soa: DnsRecord,
+    pub new_soa: DnsRecord,
+    pub removed: Vec<DnsRecord>,
+    pub added: Vec<DnsRecord>,
+}
+
+//<------------------------------------- Zone ------------------------------------------>
+
+/// The records of one zone we are authoritative for, indexed by owner name.
+pub struct Zone {
+    pub origin: String,
+    records: BTreeMap<String, Vec<DnsRecord>>,
+    names: HashSet<String>,
+    journal: VecDeque<ZoneDiff>,
+}
+
+impl Zone {
+    pub fn new(origin: &str, records: Vec<DnsRecord>) -> Result<Zone, DnsResolverError> {
+        let origin = origin.trim_end_matches('.').to_lowercase();
+        let mut zone = Zone {
+            origin,
+            records: BTreeMap::new(),
+            names: HashSet::new(),
+            journal: VecDeque::new(),
+        };
+        for record in records {
+            let owner = record.domain().unwrap_or_default().to_string();
+            if !is_subdomain(&owner, &zone.origin) {
+                return Err(zone_error(&zone.origin, &format!("{} is outside the zone", owner)));
+            }
+            let rrset = zone.records.entry(owner).or_default();
+            if !rrset.contains(&record) {
+                rrset.push(record);
+            }
+        }
+
+        let apex = zone.records.get(&zone.origin);
+        let soa_count = apex.map_or(0, |rrset| {
+            rrset.iter().filter(|r| matches!(r, DnsRecord::SOA { .. })).count()
+        });
+        if soa_count != 1 {
+            return Err(zone_error(&zone.origin, "the apex needs exactly one SOA record"));
+        }
+        zone.index_names();
+        Ok(zone)
+    }
+
+    /// Every existing name, including empty non-terminals between the apex and
+    /// the owners, so NXDOMAIN and NODATA can be told apart.
+    fn index_names(&mut self) {
+        self.names.clear();
+        for owner in self.records.keys() {
+            let mut name = owner.as_str();
+            loop {
+                if !self.names.insert(name.to_string()) || name == self.origin {
+                    break;
+                }
+                name = name.split_once('.').map_or("", |(_, parent)| parent);
+            }
+        }
+        self.names.insert(self.origin.clone());
+    }
+
+    pub fn soa(&self) -> &DnsRecord {
+        self.records[&self.origin]
+            .iter()
+            .find(|r| matches!(r, DnsRecord::SOA { .. }))
+            .expect("zone without SOA")
+    }
+
+    pub fn serial(&self) -> u32 {
+        soa_serial(self.soa())
+    }
+
+    /// Every record in canonical order with the SOA first, as AXFR sends them.
+    pub fn records(&self) -> impl Iterator<Item = &DnsRecord> {
+        let soa = self.soa();
+        std::iter::once(soa).chain(self.records.values().flatten().filter(move |r| *r != soa))
+    }
+
+    /// Swaps in a new version of the zone and journals the difference so IXFR
+    /// clients can catch up. The serial has to move forward (RFC 1982).
+    pub fn replace(&mut self, records: Vec<DnsRecord>) -> Result<(), DnsResolverError> {
+        let updated = Zone::new(&self.origin, records)?;
+        let old: HashSet<&DnsRecord> = self.records().skip(1).collect();
+        let new: HashSet<&DnsRecord> = updated.records().skip(1).collect();
+        if old == new && self.soa() == updated.soa() {
+            return Ok(());
+        }
+        if !serial_gt(updated.serial(), self.serial()) {
+            return Err(zone_error(
+                &self.origin,
+                &format!("serial {} is not newer than {}", updated.serial(), self.serial()),
+            ));
+        }
+
+        let diff = ZoneDiff {
+            old_soa: self.soa().clone(),
+            new_soa: updated.soa().clone(),
+            removed: old.difference(&new).map(|r| (*r).clone()).collect(),
+            added: new.difference(&old).map(|r| (*r).clone()).collect(),
+        };
+        let mut journal = std::mem::take(&mut self.journal);
+        journal.push_back(diff);
+        if journal.len() > MAX_JOURNAL_ENTRIES {
+            journal.pop_front();
+        }
+        *self = Zone { journal, ..updated };
+        Ok(())
+    }
+
+    /// Applies one journaled change, as received through IXFR.
+    pub fn apply(&mut self, diff: &ZoneDiff) -> Result<(), DnsResolverError> {
+        if diff.old_soa != *self.soa() {
+            return Err(zone_error(&self.origin, "change does not start at the current serial"));
+        }
+        let mut records: Vec<DnsRecord> = self
+            .records()
+            .skip(1)
+            .filter(|r| !diff.removed.contains(r))
+            .cloned()
+            .collect();
+        records.push(diff.new_soa.clone());
+        records.extend(diff.added.iter().cloned());
+        self.replace(records)
+    }
+
+    /// The chain of changes from `serial` to the current version, or None when
+    /// the journal no longer reaches back that far.
+    pub fn changes_since(&self, serial: u32) -> Option<Vec<&ZoneDiff>> {
+        if serial == self.serial() {
+            return Some(Vec::new());
+        }
+        let start = self.journal.iter().position(|diff| soa_serial(&diff.old_soa) == serial)?;
+        Some(self.journal.iter().skip(start).collect())
+    }
+
+    fn rrset(&self, name: &str, qtype: QueryType) -> Vec<DnsRecord> {
+        self.records
+            .get(name)
+            .map(|rrset| {
+                rrset
+                    .iter()
+                    .filter(|r| qtype == QueryType::ANY || r.query_type() == qtype)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Topmost delegation point between the apex and `name`, if any.
+    fn find_cut(&self, name: &str) -> Option<String> {
+        let labels: Vec<&str> = name.split('.').collect();
+        let origin_labels = if self.origin.is_empty() {
+            0
+        } else {
+            self.origin.split('.').count()
+        };
+        for count in origin_labels + 1..=labels.len() {
+            let candidate = labels[labels.len() - count..].join(".");
+            if !self.rrset(&candidate, QueryType::NS).is_empty() {
+                return Some(candidate);
+            }
+        }
+        None
+    }
+
+    /// Records owned by `name`, synthesised from the closest wildcard when the
+    /// name itself does not exist (RFC 4592).
+    fn node(&self, name: &str) -> Option<Vec<DnsRecord>> {
+        if let Some(rrset) = self.records.get(name) {
+            return Some(rrset.clone());
+        }
+        if self.names.contains(name) {
+            return Some(Vec::new());
+        }
+
+        let mut encloser = name;
+        while !self.names.contains(encloser) {
+            encloser = encloser.split_once('.').map_or("", |(_, parent)| parent);
+        }
+        let wildcard = if encloser.is_empty() {
+            "*".to_string()
+        } else {
+            format!("*.{}", encloser)
+        };
+        self.records
+            .get(&wildcard)
+            .map(|rrset| rrset.iter().map(|r| r.with_domain(name)).collect())
+    }
+
+    pub fn answer(&self, name: &str, qtype: QueryType) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        let name = name.to_lowercase();
+
+        if let Some(cut) = self.find_cut(&name) {
+            packet.authorities = self.rrset(&cut, QueryType::NS);
+            self.add_addresses(&mut packet);
+            return packet;
+        }
+        packet.header.authoritative_answer = true;
+
+        let mut owner = name.clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let Some(rrset) = self.node(&owner) else {
+                if owner == name {
+                    packet.header.rescode = ResultCode::NXDOMAIN;
+                    packet.authorities.push(self.negative_soa());
+                }
+                break;
+            };
+
+            let cname = rrset.iter().find(|r| matches!(r, DnsRecord::CNAME { .. }));
+            if let (Some(cname), false) = (cname, qtype == QueryType::CNAME || qtype == QueryType::ANY) {
+                packet.answers.push(cname.clone());
+                let DnsRecord::CNAME { host, .. } = cname else { break };
+                // Only keep following while the target is ours and not delegated away.
+                if !is_subdomain(host, &self.origin) || self.find_cut(host).is_some() {
+                    break;
+                }
+                owner = host.clone();
+                continue;
+            }
+
+            let matching: Vec<DnsRecord> = rrset
+                .into_iter()
+                .filter(|r| qtype == QueryType::ANY || r.query_type() == qtype)
+                .collect();
+            if matching.is_empty() && owner == name {
+                packet.authorities.push(self.negative_soa());
+            }
+            packet.answers.extend(matching);
+            break;
+        }
+
+        self.add_addresses(&mut packet);
+        packet
+    }
+
+    /// SOA for negative answers, with the TTL capped by the minimum field (RFC 2308).
+    fn negative_soa(&self) -> DnsRecord {
+        let mut soa = self.soa().clone();
+        if let DnsRecord::SOA { ttl, minimum, .. } = &mut soa {
+            *ttl = (*ttl).min(*minimum);
+        }
+        soa
+    }
+
+    /// Addresses of in-zone nameserver and mail hosts for the additional section,
+    /// including glue below delegation points.
+    fn add_addresses(&self, packet: &mut DnsPacket) {
+        let hosts: Vec<String> = packet
+            .answers
+            .iter()
+            .chain(packet.authorities.iter())
+            .filter_map(|record| match record {
+                DnsRecord::NS { host, .. } | DnsRecord::MX { host, .. } => Some(host.clone()),
+                _ => None,
+            })
+            .collect();
+        for host in hosts {
+            if !is_subdomain(&host, &self.origin) {
+                continue;
+            }
+            for qtype in [QueryType::A, QueryType::AAAA] {
+                for record in self.rrset(&host, qtype) {
+                    if !packet.resources.contains(&record) {
+                        packet.resources.push(record);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn soa_serial(soa: &DnsRecord) -> u32 {
+    match soa {
+        DnsRecord::SOA { serial, .. } => *serial,
+        _ => 0,
+    }
+}
+
+/// Serial number arithmetic from RFC 1982: `a` is newer than `b`.
+pub fn serial_gt(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+fn zone_error(origin: &str, message: &str) -> DnsResolverError {
+    DnsResolverError::ZoneFileError(format!("zone {}: {}", origin, message))
+}
+
+//<------------------------------------ Zone set --------------------------------------->
+
+pub struct ZoneSet {
+    zones: HashMap<String, Zone>,
+}
+
+impl ZoneSet {
+    pub fn new() -> ZoneSet {
+        ZoneSet {
+            zones: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, zone: Zone) {
+        self.zones.insert(zone.origin.clone(), zone);
+    }
+
+    pub fn get(&self, origin: &str) -> Option<&Zone> {
+        self.zones.get(origin)
+    }
+
+    pub fn remove(&mut self, origin: &str) -> Option<Zone> {
+        self.zones.remove(origin)
+    }
+
+    pub fn get_mut(&mut self, origin: &str) -> Option<&mut Zone> {
+        self.zones.get_mut(origin)
+    }
+
+    /// The most specific zone containing `name`.
+    pub fn find(&self, name: &str) -> Option<&Zone> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        let mut candidate = name.as_str();
+        loop {
+            if let Some(zone) = self.zones.get(candidate) {
+                return Some(zone);
+            }
+            if candidate.is_empty() {
+                return None;
+            }
+            candidate = candidate.split_once('.').map_or("", |(_, parent)| parent);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DnsZoneFile::parse_zone;
+    use std::net::Ipv4Addr;
+
+    const ZONE: &str = "
+$TTL 3600
+@       IN  SOA ns1 hostmaster (
+                2024010101 ; serial
+                7200 3600 1209600 300 )
+        IN  NS  ns1
+        IN  NS  ns.other.net.
+        IN  MX  10 mail
+ns1         A   192.0.2.1
+mail        A   192.0.2.25
+www     60  A   192.0.2.80
+alias       CNAME www
+*.apps      A   192.0.2.90
+a.b.deep    A   192.0.2.99
+sub         NS  ns.sub
+ns.sub      A   192.0.2.53
+";
+
+    fn zone() -> Zone {
+        Zone::new("example.com", parse_zone(ZONE, "example.com.").unwrap()).unwrap()
+    }
+
+    fn a(domain: &str, octets: [u8; 4], ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr: Ipv4Addr::from(octets),
+            ttl,
+        }
+    }
+
+    #[test]
+    fn answers_authoritatively() {
+        let packet = zone().answer("WWW.example.com", QueryType::A);
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.answers, vec![a("www.example.com", [192, 0, 2, 80], 60)]);
+    }
+
+    #[test]
+    fn nxdomain_and_nodata_carry_soa() {
+        let zone = zone();
+        let missing = zone.answer("missing.example.com", QueryType::A);
+        assert_eq!(missing.header.rescode, ResultCode::NXDOMAIN);
+        assert!(matches!(missing.authorities[0], DnsRecord::SOA { ttl: 300, .. }));
+
+        let empty_non_terminal = zone.answer("b.deep.example.com", QueryType::A);
+        assert_eq!(empty_non_terminal.header.rescode, ResultCode::NOERROR);
+        assert!(empty_non_terminal.answers.is_empty());
+        assert_eq!(empty_non_terminal.authorities.len(), 1);
+
+        let nodata = zone.answer("www.example.com", QueryType::AAAA);
+        assert_eq!(nodata.header.rescode, ResultCode::NOERROR);
+        assert!(matches!(nodata.authorities[0], DnsRecord::SOA { .. }));
+    }
+
+    #[test]
+    fn synthesises_wildcards() {
+        let packet = zone().answer("x.y.apps.example.com", QueryType::A);
+        assert_eq!(packet.answers, vec![a("x.y.apps.example.com", [192, 0, 2, 90], 3600)]);
+    }
+
+    #[test]
+    fn follows_cnames_inside_the_zone() {
+        let packet = zone().answer("alias.example.com", QueryType::A);
+        assert_eq!(packet.answers.len(), 2);
+        assert_eq!(packet.answers[1], a("www.example.com", [192, 0, 2, 80], 60));
+    }
+
+    #[test]
+    fn refers_at_delegation_points_with_glue() {
+        let packet = zone().answer("host.sub.example.com", QueryType::A);
+        assert!(!packet.header.authoritative_answer);
+        assert!(packet.answers.is_empty());
+        assert!(matches!(&packet.authorities[0], DnsRecord::NS { domain, .. } if domain == "sub.example.com"));
+        assert_eq!(packet.resources, vec![a("ns.sub.example.com", [192, 0, 2, 53], 3600)]);
+    }
+
+    #[test]
+    fn adds_addresses_for_mail_hosts() {
+        let packet = zone().answer("example.com", QueryType::MX);
+        assert_eq!(packet.resources, vec![a("mail.example.com", [192, 0, 2, 25], 3600)]);
+    }
+}
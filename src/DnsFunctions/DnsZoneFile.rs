@@ -0,0 +1,484 @@
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+use crate::DnsErrors::DnsResolverError;
+use crate::Dnspacket::DnsRecord;
+
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// One logical entry of a master file: parentheses have been joined, comments
+/// stripped, and `indented` records whether the line started with blanks.
+struct Entry {
+    line_number: usize,
+    indented: bool,
+    tokens: Vec<String>,
+}
+
+struct ParserState {
+    origin: String,
+    default_ttl: Option<u32>,
+    last_ttl: Option<u32>,
+    last_owner: Option<String>,
+}
+
+//<------------------------------------ Parser ---------------------------------------->
+
+/// Reads an RFC 1035 master file. Names come back lowercased and without the
+/// trailing dot, the same way the packet parser hands them out.
+pub fn parse_zone_file(path: &Path, origin: &str) -> Result<Vec<DnsRecord>, DnsResolverError> {
+    parse_zone_at(&read_file(path)?, path, origin)
+}
+
+/// Master file `contents` given inline; `$INCLUDE` is relative to the
+/// working directory.
+#[cfg(test)]
+pub fn parse_zone(contents: &str, origin: &str) -> Result<Vec<DnsRecord>, DnsResolverError> {
+    parse_zone_at(contents, Path::new("<inline>"), origin)
+}
+
+fn parse_zone_at(contents: &str, path: &Path, origin: &str) -> Result<Vec<DnsRecord>, DnsResolverError> {
+    let mut state = ParserState {
+        origin: normalise(origin),
+        default_ttl: None,
+        last_ttl: None,
+        last_owner: None,
+    };
+    let mut records = Vec::new();
+    parse_contents(contents, path, &mut state, &mut records, 0)?;
+    Ok(records)
+}
+
+fn parse_file(
+    path: &Path,
+    state: &mut ParserState,
+    records: &mut Vec<DnsRecord>,
+    depth: usize,
+) -> Result<(), DnsResolverError> {
+    parse_contents(&read_file(path)?, path, state, records, depth)
+}
+
+fn read_file(path: &Path) -> Result<String, DnsResolverError> {
+    fs::read_to_string(path).map_err(|e| DnsResolverError::ZoneFileError(format!("{}: {}", path.display(), e)))
+}
+
+fn parse_contents(
+    contents: &str,
+    path: &Path,
+    state: &mut ParserState,
+    records: &mut Vec<DnsRecord>,
+    depth: usize,
+) -> Result<(), DnsResolverError> {
+    let error = |line_number: usize, message: &str| {
+        DnsResolverError::ZoneFileError(format!("{}:{}: {}", path.display(), line_number, message))
+    };
+
+    for entry in tokenize(contents).map_err(|(line, message)| error(line, &message))? {
+        let line_number = entry.line_number;
+        let tokens = &entry.tokens;
+
+        match tokens[0].to_ascii_uppercase().as_str() {
+            "$ORIGIN" => {
+                let origin = tokens.get(1).ok_or_else(|| error(line_number, "$ORIGIN needs a name"))?;
+                state.origin = absolute_name(origin, &state.origin);
+                continue;
+            }
+            "$TTL" => {
+                let ttl = tokens.get(1).ok_or_else(|| error(line_number, "$TTL needs a value"))?;
+                state.default_ttl = Some(parse_ttl(ttl).ok_or_else(|| error(line_number, "invalid $TTL"))?);
+                continue;
+            }
+            "$INCLUDE" => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(error(line_number, "$INCLUDE nested too deeply"));
+                }
+                let file = tokens.get(1).ok_or_else(|| error(line_number, "$INCLUDE needs a file"))?;
+                let include_path = include_path(path, file);
+                // The included file may change the origin, but that must not leak back.
+                let saved_origin = state.origin.clone();
+                if let Some(origin) = tokens.get(2) {
+                    state.origin = absolute_name(origin, &state.origin);
+                }
+                parse_file(&include_path, state, records, depth + 1)?;
+                state.origin = saved_origin;
+                continue;
+            }
+            _ => (),
+        }
+
+        let mut index = 0;
+        let owner = if entry.indented {
+            state
+                .last_owner
+                .clone()
+                .ok_or_else(|| error(line_number, "record without an owner name"))?
+        } else {
+            index += 1;
+            absolute_name(&tokens[0], &state.origin)
+        };
+
+        let mut ttl = None;
+        for _ in 0..2 {
+            let Some(token) = tokens.get(index) else { break };
+            if is_class(token) {
+                if !token.eq_ignore_ascii_case("IN") {
+                    return Err(error(line_number, &format!("unsupported class {}", token)));
+                }
+                index += 1;
+            } else if let Some(value) = parse_ttl(token) {
+                ttl = Some(value);
+                index += 1;
+            } else {
+                break;
+            }
+        }
+
+        let rtype = tokens
+            .get(index)
+            .ok_or_else(|| error(line_number, "missing record type"))?
+            .to_ascii_uppercase();
+        let rdata = &tokens[index + 1..];
+
+        let ttl = match ttl {
+            Some(ttl) => {
+                state.last_ttl = Some(ttl);
+                ttl
+            }
+            None => state
+                .default_ttl
+                .or(state.last_ttl)
+                .or_else(|| soa_minimum(&rtype, rdata))
+                .ok_or_else(|| error(line_number, "no TTL given and no $TTL in effect"))?,
+        };
+
+        let record = parse_record(&owner, ttl, &rtype, rdata, &state.origin)
+            .map_err(|message| error(line_number, &message))?;
+        state.last_owner = Some(owner);
+        records.push(record);
+    }
+
+    Ok(())
+}
+
+fn parse_record(
+    owner: &str,
+    ttl: u32,
+    rtype: &str,
+    rdata: &[String],
+    origin: &str,
+) -> Result<DnsRecord, String> {
+    let field = |index: usize| {
+        rdata
+            .get(index)
+            .map(String::as_str)
+            .ok_or_else(|| format!("{} record is missing data", rtype))
+    };
+    let number = |index: usize| -> Result<u32, String> {
+        let value = field(index)?;
+        parse_ttl(value).ok_or_else(|| format!("invalid number `{}`", value))
+    };
+    let expected = match rtype {
+        "A" | "AAAA" | "NS" | "CNAME" | "PTR" => 1,
+        "MX" => 2,
+        "SOA" => 7,
+        _ => return Err(format!("unsupported record type {}", rtype)),
+    };
+    if rdata.len() != expected {
+        return Err(format!("{} record expects {} fields, got {}", rtype, expected, rdata.len()));
+    }
+
+    let domain = owner.to_string();
+    let record = match rtype {
+        "A" => DnsRecord::A {
+            domain,
+            addr: field(0)?
+                .parse::<Ipv4Addr>()
+                .map_err(|_| format!("invalid IPv4 address `{}`", rdata[0]))?,
+            ttl,
+        },
+        "AAAA" => DnsRecord::AAAA {
+            domain,
+            addr: field(0)?
+                .parse::<Ipv6Addr>()
+                .map_err(|_| format!("invalid IPv6 address `{}`", rdata[0]))?,
+            ttl,
+        },
+        "NS" => DnsRecord::NS {
+            domain,
+            host: absolute_name(field(0)?, origin),
+            ttl,
+        },
+        "CNAME" => DnsRecord::CNAME {
+            domain,
+            host: absolute_name(field(0)?, origin),
+            ttl,
+        },
+        "PTR" => DnsRecord::PTR {
+            domain,
+            host: absolute_name(field(0)?, origin),
+            ttl,
+        },
+        "MX" => DnsRecord::MX {
+            domain,
+            priority: field(0)?
+                .parse()
+                .map_err(|_| format!("invalid MX preference `{}`", rdata[0]))?,
+            host: absolute_name(field(1)?, origin),
+            ttl,
+        },
+        _ => DnsRecord::SOA {
+            domain,
+            m_name: absolute_name(field(0)?, origin),
+            r_name: absolute_name(field(1)?, origin),
+            serial: field(2)?
+                .parse()
+                .map_err(|_| format!("invalid SOA serial `{}`", rdata[2]))?,
+            refresh: number(3)?,
+            retry: number(4)?,
+            expire: number(5)?,
+            minimum: number(6)?,
+            ttl,
+        },
+    };
+    Ok(record)
+}
+
+/// Splits the file into entries, joining lines inside parentheses and
+/// dropping `;` comments outside quoted strings.
+fn tokenize(contents: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries = Vec::new();
+    let mut current: Option<Entry> = None;
+    let mut depth = 0;
+
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        if current.is_none() {
+            current = Some(Entry {
+                line_number,
+                indented: line.starts_with(' ') || line.starts_with('\t'),
+                tokens: Vec::new(),
+            });
+        }
+        let entry = current.as_mut().unwrap();
+
+        let mut token = String::new();
+        let mut quoted = false;
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => quoted = !quoted,
+                '\\' if quoted => {
+                    if let Some(next) = chars.next() {
+                        token.push(next);
+                    }
+                }
+                ';' if !quoted => break,
+                '(' if !quoted => depth += 1,
+                ')' if !quoted => {
+                    if depth == 0 {
+                        return Err((line_number, "unbalanced `)`".to_string()));
+                    }
+                    depth -= 1;
+                }
+                c if c.is_whitespace() && !quoted => {
+                    if !token.is_empty() {
+                        entry.tokens.push(std::mem::take(&mut token));
+                    }
+                    continue;
+                }
+                c => token.push(c),
+            }
+            if matches!(c, '(' | ')') && !token.is_empty() {
+                entry.tokens.push(std::mem::take(&mut token));
+            }
+        }
+        if quoted {
+            return Err((line_number, "unterminated quoted string".to_string()));
+        }
+        if !token.is_empty() {
+            entry.tokens.push(token);
+        }
+
+        if depth == 0 {
+            let entry = current.take().unwrap();
+            if !entry.tokens.is_empty() {
+                entries.push(entry);
+            }
+        }
+    }
+
+    if depth != 0 {
+        let line_number = current.map_or(0, |entry| entry.line_number);
+        return Err((line_number, "unbalanced `(`".to_string()));
+    }
+    Ok(entries)
+}
+
+fn absolute_name(name: &str, origin: &str) -> String {
+    if name == "@" {
+        return origin.to_string();
+    }
+    if name == "." {
+        return String::new();
+    }
+    if let Some(absolute) = name.strip_suffix('.') {
+        return absolute.to_lowercase();
+    }
+    if origin.is_empty() {
+        name.to_lowercase()
+    } else {
+        format!("{}.{}", name.to_lowercase(), origin)
+    }
+}
+
+fn normalise(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+fn include_path(current: &Path, file: &str) -> PathBuf {
+    let file = Path::new(file);
+    if file.is_absolute() {
+        return file.to_path_buf();
+    }
+    match current.parent() {
+        Some(dir) => dir.join(file),
+        None => file.to_path_buf(),
+    }
+}
+
+fn is_class(token: &str) -> bool {
+    matches!(token.to_ascii_uppercase().as_str(), "IN" | "CH" | "HS" | "CS")
+}
+
+/// Plain seconds or BIND style units such as `1h30m` or `2w`.
+fn parse_ttl(value: &str) -> Option<u32> {
+    if let Ok(seconds) = value.parse::<u32>() {
+        return Some(seconds);
+    }
+    let mut total: u32 = 0;
+    let mut digits = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None,
+        };
+        let amount: u32 = digits.parse().ok()?;
+        total = total.checked_add(amount.checked_mul(unit)?)?;
+        digits.clear();
+    }
+    if !digits.is_empty() {
+        return None;
+    }
+    Some(total)
+}
+
+/// RFC 1035 lets a zone without $TTL fall back on the SOA minimum field.
+fn soa_minimum(rtype: &str, rdata: &[String]) -> Option<u32> {
+    if rtype != "SOA" {
+        return None;
+    }
+    rdata.get(6).and_then(|minimum| parse_ttl(minimum))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn a(domain: &str, octets: [u8; 4], ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr: Ipv4Addr::from(octets),
+            ttl,
+        }
+    }
+
+    #[test]
+    fn parses_relative_names_ttls_and_parentheses() {
+        let zone = "
+$TTL 3600
+@       IN  SOA ns1 hostmaster. (
+                2024010101 ; serial
+                7200 3600 1209600 300 )
+        IN  MX  10 mail
+www     60  A   192.0.2.80
+            A   192.0.2.81
+";
+        let records = parse_zone(zone, "Example.COM.").unwrap();
+        assert_eq!(
+            records,
+            vec![
+                DnsRecord::SOA {
+                    domain: "example.com".to_string(),
+                    m_name: "ns1.example.com".to_string(),
+                    r_name: "hostmaster".to_string(),
+                    serial: 2024010101,
+                    refresh: 7200,
+                    retry: 3600,
+                    expire: 1209600,
+                    minimum: 300,
+                    ttl: 3600,
+                },
+                DnsRecord::MX {
+                    domain: "example.com".to_string(),
+                    priority: 10,
+                    host: "mail.example.com".to_string(),
+                    ttl: 3600,
+                },
+                a("www.example.com", [192, 0, 2, 80], 60),
+                // Records without a TTL take $TTL, not the previous record's.
+                a("www.example.com", [192, 0, 2, 81], 3600),
+            ]
+        );
+    }
+
+    #[test]
+    fn origin_directive_and_units() {
+        let records = parse_zone("$ORIGIN example.org.\n$TTL 1h\nhost 1d IN A 192.0.2.1\n", "").unwrap();
+        assert_eq!(records, vec![a("host.example.org", [192, 0, 2, 1], 86400)]);
+
+        assert_eq!(parse_ttl("1w2d3h4m5s"), Some(604800 + 2 * 86400 + 3 * 3600 + 4 * 60 + 5));
+        assert_eq!(parse_ttl("90M"), Some(5400));
+        assert_eq!(parse_ttl("1h30"), None);
+        assert_eq!(parse_ttl("1y"), None);
+    }
+
+    #[test]
+    fn includes_files_relative_to_the_zone_file() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/DnsFunctions/testdata/zones/example.com.zone");
+        let records = parse_zone_file(&path, "example.com").unwrap();
+        assert!(records.contains(&DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns1.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+            ttl: 3600,
+        }));
+        assert!(records.contains(&a("www.example.com", [192, 0, 2, 80], 60)));
+        // Included with an origin of its own, which it changes in turn.
+        assert!(records.contains(&a("host.sub.example.com", [192, 0, 2, 53], 3600)));
+        assert!(records.contains(&a("host.other.example.com", [192, 0, 2, 54], 3600)));
+        // Neither origin outlives the include.
+        assert!(records.contains(&a("after.example.com", [192, 0, 2, 9], 3600)));
+
+        let err = parse_zone("$INCLUDE missing.inc\n", "example.com").unwrap_err();
+        assert!(err.to_string().contains("missing.inc"));
+    }
+
+    #[test]
+    fn rejects_unknown_types_with_line_number() {
+        let err = parse_zone("$TTL 60\n@ SOA ns h 1 2 3 4 5\n@ WKS foo\n", "example.com").unwrap_err();
+        assert!(err.to_string().contains(":3:"));
+    }
+}
@@ -1,17 +1,22 @@
 use std::io::{Error as E, ErrorKind};
 use std::net::{Ipv4Addr, Ipv6Addr};
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct BytePacketBuffer {
-    pub buf: [u8; 4096],
+    pub buf: Vec<u8>,
     pub pos: usize,
 }
 
 
 impl BytePacketBuffer {
     pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer::with_size(4096)
+    }
+
+    /// A buffer for messages larger than UDP allows, such as zone transfers over TCP.
+    pub fn with_size(size: usize) -> BytePacketBuffer {
         BytePacketBuffer {
-            buf: [0; 4096],
+            buf: vec![0; size],
             pos: 0,
         }
     }
@@ -46,7 +51,7 @@ impl BytePacketBuffer {
     }
 
     pub fn read(&mut self) -> Result<u8, E> {
-        if self.pos >= 4096 {
+        if self.pos >= self.buf.len() {
             return Err(E::new(ErrorKind::InvalidInput, "End of buffer - read"));
         }
         let res = self.buf[self.pos];
@@ -56,14 +61,14 @@ impl BytePacketBuffer {
     }
 
     pub fn get(&self, pos: usize) -> Result<u8, E> {
-        if pos >= 4096 {
+        if pos >= self.buf.len() {
             return Err(E::new(ErrorKind::InvalidInput, "End of buffer - get"));
         }
         Ok(self.buf[pos])
     }
 
     pub fn get_range(&self, start: usize, len: usize) -> Result<&[u8], E> {
-        if start + len >= 4096 {
+        if start + len >= self.buf.len() {
             return Err(E::new(ErrorKind::InvalidInput, "End of buffer - get_range"));
         }
         Ok(&self.buf[start..start + len as usize])
@@ -83,7 +88,16 @@ impl BytePacketBuffer {
         Ok(res)
     }
 
-    fn read_qname(&mut self, outstr: &mut String) -> Result<(), E> {
+    pub fn read_qname(&mut self, outstr: &mut String) -> Result<(), E> {
+        self.read_qname_cased(outstr, false)
+    }
+
+    /// Reads a name keeping the case of every label exactly as it is on the wire.
+    fn read_qname_preserving_case(&mut self, outstr: &mut String) -> Result<(), E> {
+        self.read_qname_cased(outstr, true)
+    }
+
+    fn read_qname_cased(&mut self, outstr: &mut String, preserve_case: bool) -> Result<(), E> {
         let mut pos = self.pos();
         let mut delim = "";
 
@@ -120,7 +134,12 @@ impl BytePacketBuffer {
 
                 outstr.push_str(delim);
                 let str_buffer = self.get_range(pos, len as usize)?;
-                outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+                let label = String::from_utf8_lossy(str_buffer);
+                if preserve_case {
+                    outstr.push_str(&label);
+                } else {
+                    outstr.push_str(&label.to_lowercase());
+                }
                 delim = ".";
 
                 pos += len as usize;
@@ -134,7 +153,7 @@ impl BytePacketBuffer {
     }
 
     fn write(&mut self, val: u8) -> Result<(), E> {
-        if self.pos >= 4096 {
+        if self.pos >= self.buf.len() {
             return Err(E::new(ErrorKind::InvalidInput, "Limit of jumps exceeded"));
         }
         self.buf[self.pos] = val;
@@ -163,7 +182,7 @@ impl BytePacketBuffer {
     }
 
     fn write_qname(&mut self, qname: &str) -> Result<(), E> {
-        for label in qname.split('.') {
+        for label in qname.split('.').filter(|label| !label.is_empty()) {
             let len = label.len();
             if len > 0x3f {
                 return Err(E::new(
@@ -184,13 +203,18 @@ impl BytePacketBuffer {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
-enum ResultCode {
+pub enum ResultCode {
     NOERROR = 0,
     FORMERR = 1,
     SERVFAIL = 2,
     NXDOMAIN = 3,
     NOTIMP = 4,
     REFUSED = 5,
+    YXDOMAIN = 6,
+    YXRRSET = 7,
+    NXRRSET = 8,
+    NOTAUTH = 9,
+    NOTZONE = 10,
 }
 
 impl ResultCode {
@@ -201,11 +225,44 @@ impl ResultCode {
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
+            6 => ResultCode::YXDOMAIN,
+            7 => ResultCode::YXRRSET,
+            8 => ResultCode::NXRRSET,
+            9 => ResultCode::NOTAUTH,
+            10 => ResultCode::NOTZONE,
             0 | _ => ResultCode::NOERROR,
         }
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    QUERY,
+    NOTIFY,
+    UPDATE,
+    UNKNOWN(u8),
+}
+
+impl Opcode {
+    pub fn to_num(&self) -> u8 {
+        match *self {
+            Opcode::QUERY => 0,
+            Opcode::NOTIFY => 4,
+            Opcode::UPDATE => 5,
+            Opcode::UNKNOWN(x) => x,
+        }
+    }
+
+    pub fn from_num(num: u8) -> Opcode {
+        match num {
+            0 => Opcode::QUERY,
+            4 => Opcode::NOTIFY,
+            5 => Opcode::UPDATE,
+            _ => Opcode::UNKNOWN(num),
+        }
+    }
+}
+
 //<------------------------------ DNS header -------------------------------------->
 
 #[derive(Clone, Debug)]
@@ -215,7 +272,7 @@ pub struct DnsHeader {
     pub recursion_desired: bool,
     pub truncated_message: bool,
     pub authoritative_answer: bool,
-    pub opcode: u8,
+    pub opcode: Opcode,
     pub response: bool,
 
     pub rescode: ResultCode,
@@ -238,7 +295,7 @@ impl DnsHeader {
             recursion_desired: false,
             truncated_message: false,
             authoritative_answer: false,
-            opcode: 0,
+            opcode: Opcode::QUERY,
             response: false,
 
             rescode: ResultCode::NOERROR,
@@ -261,7 +318,7 @@ impl DnsHeader {
         self.recursion_desired = (a & (1 << 0)) > 0;
         self.truncated_message = (a & (1 << 1)) > 0;
         self.authoritative_answer = (a & (1 << 2)) > 0;
-        self.opcode = (a >> 3) & 0x0F;
+        self.opcode = Opcode::from_num((a >> 3) & 0x0F);
         self.response = (a & (1 << 7)) > 0;
 
         self.rescode = ResultCode::from_num(b & 0x0F);
@@ -285,7 +342,7 @@ impl DnsHeader {
             (self.recursion_desired as u8)
                 | ((self.truncated_message as u8) << 1)
                 | ((self.authoritative_answer as u8) << 2)
-                | (self.opcode << 3)
+                | (self.opcode.to_num() << 3)
                 | ((self.response as u8) << 7) as u8,
         )?;
 
@@ -314,8 +371,15 @@ pub enum QueryType {
     A,
     NS,
     CNAME,
+    SOA,
+    PTR,
     MX,
     AAAA,
+    OPT,
+    IXFR,
+    AXFR,
+    ANY,
+    TSIG,
 }
 
 impl QueryType {
@@ -325,8 +389,15 @@ impl QueryType {
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
             QueryType::AAAA => 28,
+            QueryType::OPT => 41,
+            QueryType::IXFR => 251,
+            QueryType::AXFR => 252,
+            QueryType::ANY => 255,
+            QueryType::TSIG => 250,
         }
     }
 
@@ -335,8 +406,15 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
             28 => QueryType::AAAA,
+            41 => QueryType::OPT,
+            251 => QueryType::IXFR,
+            252 => QueryType::AXFR,
+            255 => QueryType::ANY,
+            250 => QueryType::TSIG,
             _ => QueryType::UNKNOWN(num),
         }
     }
@@ -348,6 +426,7 @@ impl QueryType {
 pub struct DnsQuestion {
     pub name: String,
     pub qtype: QueryType,
+    pub qclass: u16,
 }
 
 impl DnsQuestion {
@@ -355,13 +434,14 @@ impl DnsQuestion {
         DnsQuestion {
             name: name,
             qtype: qtype,
+            qclass: 1,
         }
     }
 
     pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<(), E> {
-        buffer.read_qname(&mut self.name)?;
+        buffer.read_qname_preserving_case(&mut self.name)?;
         self.qtype = QueryType::from_num(buffer.read_u16()?);
-        let _ = buffer.read_u16()?;
+        self.qclass = buffer.read_u16()?;
 
         Ok(())
     }
@@ -371,7 +451,7 @@ impl DnsQuestion {
 
         let typenum = self.qtype.to_num();
         buffer.write_u16(typenum)?;
-        buffer.write_u16(1)?;
+        buffer.write_u16(self.qclass)?;
 
         Ok(())
     }
@@ -383,10 +463,12 @@ impl DnsQuestion {
 #[allow(dead_code)]
 
 pub enum DnsRecord {
+    /// A type we do not model, kept as raw RDATA (RFC 3597) so it can be
+    /// passed on as received.
     UNKNOWN {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
         ttl: u32,
     },
     A {
@@ -404,6 +486,22 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     },
+    PTR {
+        domain: String,
+        host: String,
+        ttl: u32,
+    },
+    SOA {
+        domain: String,
+        m_name: String,
+        r_name: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    },
     MX {
         domain: String,
         priority: u16,
@@ -415,6 +513,22 @@ pub enum DnsRecord {
         addr: Ipv6Addr,
         ttl: u32,
     },
+    OPT {
+        packet_len: u16,
+        flags: u32,
+        data: Vec<u8>,
+    },
+    /// Transaction signature (RFC 8945); `domain` is the key name.
+    TSIG {
+        domain: String,
+        algorithm: String,
+        time_signed: u64,
+        fudge: u16,
+        mac: Vec<u8>,
+        original_id: u16,
+        error: u16,
+        other: Vec<u8>,
+    },
 }
 
 impl DnsRecord {
@@ -424,9 +538,18 @@ impl DnsRecord {
 
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16();
+        let class = buffer.read_u16()?;
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
+        // Dynamic updates use empty rdata to name a whole RRset.
+        if data_len == 0 && qtype != QueryType::OPT {
+            return Ok(DnsRecord::UNKNOWN {
+                domain: domain,
+                qtype: qtype_num,
+                data: Vec::new(),
+                ttl: ttl,
+            });
+        }
         match qtype {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
@@ -485,6 +608,34 @@ impl DnsRecord {
                     ttl: ttl,
                 })
             }
+            QueryType::PTR => {
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                Ok(DnsRecord::PTR {
+                    domain: domain,
+                    host: host,
+                    ttl: ttl,
+                })
+            }
+            QueryType::SOA => {
+                let mut m_name = String::new();
+                buffer.read_qname(&mut m_name)?;
+                let mut r_name = String::new();
+                buffer.read_qname(&mut r_name)?;
+
+                Ok(DnsRecord::SOA {
+                    domain: domain,
+                    m_name: m_name,
+                    r_name: r_name,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    ttl: ttl,
+                })
+            }
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
                 let mut mx = String::new();
@@ -497,19 +648,132 @@ impl DnsRecord {
                     ttl: ttl,
                 })
             }
-            QueryType::UNKNOWN(_) => {
+            QueryType::OPT => {
+                let data = buffer.get_range(buffer.pos(), data_len as usize)?.to_vec();
+                buffer.step(data_len as usize)?;
+
+                Ok(DnsRecord::OPT {
+                    packet_len: class,
+                    flags: ttl,
+                    data: data,
+                })
+            }
+            QueryType::TSIG => {
+                let mut algorithm = String::new();
+                buffer.read_qname(&mut algorithm)?;
+                let time_signed = ((buffer.read_u16()? as u64) << 32) | buffer.read_u32()? as u64;
+                let fudge = buffer.read_u16()?;
+                let mac_len = buffer.read_u16()? as usize;
+                let mac = buffer.get_range(buffer.pos(), mac_len)?.to_vec();
+                buffer.step(mac_len)?;
+                let original_id = buffer.read_u16()?;
+                let error = buffer.read_u16()?;
+                let other_len = buffer.read_u16()? as usize;
+                let other = buffer.get_range(buffer.pos(), other_len)?.to_vec();
+                buffer.step(other_len)?;
+
+                Ok(DnsRecord::TSIG {
+                    domain: domain,
+                    algorithm: algorithm,
+                    time_signed: time_signed,
+                    fudge: fudge,
+                    mac: mac,
+                    original_id: original_id,
+                    error: error,
+                    other: other,
+                })
+            }
+            QueryType::UNKNOWN(_) | QueryType::IXFR | QueryType::AXFR | QueryType::ANY => {
+                let data = buffer.get_range(buffer.pos(), data_len as usize)?.to_vec();
                 buffer.step(data_len as usize)?;
 
                 Ok(DnsRecord::UNKNOWN {
                     domain: domain,
                     qtype: qtype_num,
-                    data_len: data_len,
+                    data: data,
                     ttl: ttl,
                 })
             }
         }
     }
 
+    pub fn query_type(&self) -> QueryType {
+        match self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::from_num(*qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+            DnsRecord::TSIG { .. } => QueryType::TSIG,
+        }
+    }
+
+    pub fn domain(&self) -> Option<&str> {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::TSIG { domain, .. } => Some(domain),
+            DnsRecord::OPT { .. } => None,
+        }
+    }
+
+    pub fn with_domain(&self, name: &str) -> DnsRecord {
+        let mut record = self.clone();
+        match &mut record {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::TSIG { domain, .. } => *domain = name.to_string(),
+            DnsRecord::OPT { .. } => (),
+        }
+        record
+    }
+
+    pub fn ttl(&self) -> Option<u32> {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. } => Some(*ttl),
+            DnsRecord::OPT { .. } | DnsRecord::TSIG { .. } => None,
+        }
+    }
+
+    pub fn with_ttl(&self, new_ttl: u32) -> DnsRecord {
+        let mut record = self.clone();
+        match &mut record {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. } => *ttl = new_ttl,
+            DnsRecord::OPT { .. } | DnsRecord::TSIG { .. } => (),
+        }
+        record
+    }
+
     pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize, E> {
         let start_pos = buffer.pos();
 
@@ -567,6 +831,54 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SOA {
+                ref domain,
+                ref m_name,
+                ref r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(m_name)?;
+                buffer.write_qname(r_name)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
             DnsRecord::MX {
                 ref domain,
                 priority,
@@ -602,8 +914,73 @@ impl DnsRecord {
                     buffer.write_u16(*octet)?;
                 }
             }
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Skipping record: {:?}", self);
+            DnsRecord::OPT {
+                packet_len,
+                flags,
+                ref data,
+            } => {
+                buffer.write_u8(0)?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(packet_len)?;
+                buffer.write_u32(flags)?;
+                buffer.write_u16(data.len() as u16)?;
+
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
+            }
+            DnsRecord::TSIG {
+                ref domain,
+                ref algorithm,
+                time_signed,
+                fudge,
+                ref mac,
+                original_id,
+                error,
+                ref other,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TSIG.to_num())?;
+                // Class ANY and TTL 0, as RFC 8945 requires.
+                buffer.write_u16(255)?;
+                buffer.write_u32(0)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(algorithm)?;
+                buffer.write_u16((time_signed >> 32) as u16)?;
+                buffer.write_u32(time_signed as u32)?;
+                buffer.write_u16(fudge)?;
+                buffer.write_u16(mac.len() as u16)?;
+                for b in mac {
+                    buffer.write_u8(*b)?;
+                }
+                buffer.write_u16(original_id)?;
+                buffer.write_u16(error)?;
+                buffer.write_u16(other.len() as u16)?;
+                for b in other {
+                    buffer.write_u8(*b)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
             }
         }
 
@@ -683,4 +1060,88 @@ impl DnsPacket {
 
         Ok(())
     }
+
+    /// The value of the EDNS option `code` in the OPT record, if there is one.
+    pub fn edns_option(&self, code: u16) -> Option<&[u8]> {
+        self.resources.iter().find_map(|record| match record {
+            DnsRecord::OPT { data, .. } => edns_options(data)
+                .find(|(option, _)| *option == code)
+                .map(|(_, value)| value),
+            _ => None,
+        })
+    }
+
+    /// Puts an EDNS option into the OPT record in place of any with the same
+    /// code. Packets without EDNS are left alone.
+    pub fn set_edns_option(&mut self, code: u16, value: &[u8]) {
+        for record in self.resources.iter_mut() {
+            if let DnsRecord::OPT { data, .. } = record {
+                let mut options: Vec<u8> = Vec::with_capacity(data.len() + 4 + value.len());
+                for (other, other_value) in edns_options(data).filter(|(other, _)| *other != code) {
+                    options.extend(edns_option(other, other_value));
+                }
+                options.extend(edns_option(code, value));
+                *data = options;
+            }
+        }
+    }
+}
+
+/// The `(code, value)` pairs of an OPT record's RDATA, up to the first malformed one.
+pub fn edns_options(data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        let [code_high, code_low, len_high, len_low, tail @ ..] = rest else {
+            return None;
+        };
+        let len = u16::from_be_bytes([*len_high, *len_low]) as usize;
+        if tail.len() < len {
+            return None;
+        }
+        let (value, tail) = tail.split_at(len);
+        rest = tail;
+        Some((u16::from_be_bytes([*code_high, *code_low]), value))
+    })
+}
+
+/// An EDNS option in the wire format OPT RDATA holds.
+pub fn edns_option(code: u16, value: &[u8]) -> Vec<u8> {
+    let mut option = Vec::with_capacity(4 + value.len());
+    option.extend(code.to_be_bytes());
+    option.extend((value.len() as u16).to_be_bytes());
+    option.extend(value);
+    option
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_on_records_of_unmodelled_types() {
+        // "v=spf1 -all" as TXT RDATA: one length-prefixed string.
+        let mut txt = vec![11];
+        txt.extend(b"v=spf1 -all");
+        let mut response = DnsPacket::new();
+        response.header.response = true;
+        response.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::UNKNOWN(16)));
+        response.answers.push(DnsRecord::UNKNOWN {
+            domain: "example.com".to_string(),
+            qtype: 16,
+            data: txt,
+            ttl: 300,
+        });
+        response.answers.push(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 300,
+        });
+
+        let mut buffer = BytePacketBuffer::new();
+        response.clone().write(&mut buffer).unwrap();
+        buffer.pos = 0;
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed.header.answers, 2);
+        assert_eq!(parsed.answers, response.answers);
+    }
 }
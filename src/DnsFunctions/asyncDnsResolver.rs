@@ -1,31 +1,222 @@
 mod Dnspacket;
 mod DnsErrors;
+mod DnsConfig;
+mod DnsInfraCache;
+mod DnsBailiwick;
+mod DnsInflight;
+mod DnsForwarder;
+mod DnsRouting;
+mod DnsZone;
+mod DnsZoneFile;
+mod DnsTransfer;
+mod DnsUpdate;
+mod DnsTsig;
+mod DnsHosts;
+mod DnsBlocklist;
+mod DnsRpz;
+mod DnsAcl;
+mod DnsRateLimit;
+mod DnsCache;
+mod DnsEcs;
+mod Dns64;
+mod DnsCookie;
+mod DnsTls;
+mod DnsHttps;
+mod DnsTransport;
 
-use Dnspacket::DnsRecord;
-use tokio::net::UdpSocket;
-use std::{net::{Ipv4Addr,IpAddr}, sync::Arc};
+use Dnspacket::{BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, Opcode, QueryType, ResultCode};
+use DnsConfig::{Config, QnameMinimisation, ResolutionMode, ViewConfig};
+use DnsForwarder::{Transport, Upstream, UpstreamPool, UpstreamPrivacy};
+use DnsTransport::Transports;
+use DnsRouting::{Route, RoutingTable};
+use DnsInfraCache::{EdnsStatus, InfraCache};
+use DnsBailiwick::{is_subdomain, scrub_response};
+use DnsInflight::{InflightQueries, QueryKey};
+use DnsZone::{Zone, ZoneSet};
+use DnsHosts::HostsTable;
+use DnsBlocklist::{BlockResponse, Blocklist};
+use DnsAcl::{Acl, AclAction};
+use DnsRateLimit::{RateDecision, RateLimiter};
+use DnsCache::ResponseCache;
+use DnsEcs::ClientSubnet;
+use Dns64::synthesize_ptr;
+use DnsCookie::{is_bad_cookie, set_bad_cookie, ClientCookies, CookieCheck, ServerCookies};
+use DnsRpz::{Nameservers, PolicyAction, PolicyHit, ResponsePolicy};
+use DnsUpdate::{apply_update, parse_update};
+use DnsTsig::{Keyring, TsigFailure, TsigKey, TsigSession};
+use DnsTransfer::{axfr_records, ixfr_records, parse_transfer, transfer_messages, Transfer, MAX_MESSAGE_SIZE};
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio_rustls::TlsAcceptor;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use std::{collections::{HashMap, HashSet}, future::Future, net::{Ipv4Addr,IpAddr,SocketAddr}, path::{Path, PathBuf}, pin::Pin, sync::{Arc, RwLock}, time::{Duration, Instant}};
 use DnsErrors::DnsResolverError;
 
+const ROOT_SERVERS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+const MAX_ITERATIONS: usize = 32;
+// How deep lookups of nameserver names and CNAME targets may nest.
+const MAX_DEPTH: usize = 4;
+const EDNS_PAYLOAD_SIZE: u16 = 1232;
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(2);
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+// Until a secondary zone has been transferred once there are no SOA timers to go by.
+const SECONDARY_INITIAL_RETRY: Duration = Duration::from_secs(30);
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+// Short, so clients notice soon when a name is taken off a blocklist.
+const BLOCKED_TTL: u32 = 10;
+// NOTIFY is retried with a doubling timeout, about a minute in all.
+const NOTIFY_ATTEMPTS: usize = 5;
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+// RFC 9156 section 2.3 limits on how many queries minimisation may add.
+const MAX_MINIMISE_COUNT: usize = 10;
+const MINIMISE_ONE_LAB: usize = 4;
+
+type ResolveFuture<'a> = Pin<Box<dyn Future<Output = Result<DnsPacket,DnsResolverError>> + Send + 'a>>;
+
+struct ResolverContext {
+    config: Config,
+    infra: InfraCache,
+    // Configured views in the order they are tried, then the one for everyone else.
+    views: Vec<View>,
+    default_view: View,
+    zones: RwLock<ZoneSet>,
+    hosts: RwLock<HostsTable>,
+    blocklist: RwLock<Blocklist>,
+    // Indexed from the policy zones in `zones`, and re-indexed when they change.
+    policy: RwLock<ResponsePolicy>,
+    secondaries: HashMap<String, Arc<Secondary>>,
+    // Wakes the task that sends NOTIFY for a local zone whose contents changed.
+    zone_changed: HashMap<String, Arc<tokio::sync::Notify>>,
+    keyring: Keyring,
+    rate_limiter: RateLimiter,
+    // Both None when DNS cookies are turned off.
+    server_cookies: Option<ServerCookies>,
+    client_cookies: Option<ClientCookies>,
+    transports: Transports,
+}
+
+/// What a group of clients sees: zones of its own, answered before the shared
+/// ones, its own routes on top of the shared ones, and its own cache.
+struct View {
+    config: ViewConfig,
+    zones: RwLock<ZoneSet>,
+    routes: RoutingTable,
+    inflight: InflightQueries,
+    cache: ResponseCache,
+}
+
+impl View {
+    fn matches(&self, client: &Client, subnet: Option<&ClientSubnet>) -> bool {
+        self.config.clients.iter().any(|range| range.contains(client.addr.ip()))
+            || subnet.is_some_and(|subnet| {
+                self.config.client_subnets.iter().any(|range| range.contains(subnet.address))
+            })
+            || client.key.as_ref().is_some_and(|key| self.config.keys.contains(key))
+    }
+}
+
+/// A zone we copy from its primaries by zone transfer.
+struct Secondary {
+    origin: String,
+    primaries: Vec<SocketAddr>,
+    key: Option<TsigKey>,
+    refresh_now: tokio::sync::Notify,
+}
+
+/// Where a message came from, and the TSIG key it was signed with, if any.
+struct Client {
+    addr: SocketAddr,
+    key: Option<String>,
+}
+
+impl Client {
+    fn new(addr: SocketAddr, session: Option<&TsigSession>) -> Client {
+        Client {
+            addr,
+            key: session.map(|session| session.key_name().to_string()),
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config = match std::env::args().nth(1) {
+        Some(path) => Config::from_file(&path)?,
+        None => Config::new(),
+    };
+    let ctx = Arc::new(resolver_context(config)?);
+
     let in_socket = Arc::new(UdpSocket::bind("127.0.0.1:2053").await?);
     println!("Listening on {}", in_socket.local_addr()?);
+    let tcp_listener = TcpListener::bind(in_socket.local_addr()?).await?;
+    let dot_listener =
+        bind_tls(&ctx.config, in_socket.local_addr()?.ip(), ctx.config.dot_port, &[b"dot"]).await?;
+    if let Some((listener, _)) = &dot_listener {
+        println!("Listening for DNS over TLS on {}", listener.local_addr()?);
+    }
+    let doh_listener =
+        bind_tls(&ctx.config, in_socket.local_addr()?.ip(), ctx.config.doh_port, &[b"h2", b"http/1.1"]).await?;
+    if let Some((listener, _)) = &doh_listener {
+        println!("Listening for DNS over HTTPS on {}", listener.local_addr()?);
+    }
 
-    let out_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
-    println!("Bound to {}", out_socket.local_addr()?);
+    let mut pools: Vec<&Arc<UpstreamPool>> = Vec::new();
+    for pool in ctx.views.iter().chain([&ctx.default_view]).flat_map(|view| view.routes.pools()) {
+        if !pools.iter().any(|known| Arc::ptr_eq(known, pool)) {
+            pools.push(pool);
+        }
+    }
+    for pool in pools {
+        tokio::spawn(health_check(ctx.clone(), pool.clone()));
+    }
+    for secondary in ctx.secondaries.values() {
+        tokio::spawn(maintain_secondary(ctx.clone(), secondary.clone()));
+    }
+    for (origin, changed) in &ctx.zone_changed {
+        tokio::spawn(send_notifies(ctx.clone(), origin.clone(), changed.clone()));
+    }
+    tokio::spawn(serve_tcp(tcp_listener, ctx.clone()));
+    if let Some((listener, acceptor)) = dot_listener {
+        tokio::spawn(serve_tls(listener, acceptor, ctx.clone()));
+    }
+    if let Some((listener, acceptor)) = doh_listener {
+        tokio::spawn(serve_https(listener, acceptor, ctx.clone()));
+    }
+    tokio::spawn(reload_on_hangup(ctx.clone()));
 
     let mut root_query_buffer = Dnspacket::BytePacketBuffer::new();
 
     loop{
         let (size, src) = in_socket.recv_from(&mut root_query_buffer.buf).await?;
         println!("Received {} bytes from {}", size, src);
-        
+
         let buffer = root_query_buffer.clone();
-        let out_socket = out_socket.clone();
+        let ctx = ctx.clone();
         let in_socket = in_socket.clone();
 
         tokio::spawn(async move{
-            if let Err(e) = handle_request(buffer, src, in_socket, out_socket).await{
+            if let Err(e) = handle_request(buffer, src, in_socket, ctx).await{
                 eprintln!("Error: {}", e);
             };
         });
@@ -33,83 +224,2456 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 }
 
+/// Everything request handling needs, loaded from `config`.
+fn resolver_context(config: Config) -> Result<ResolverContext, DnsResolverError> {
+    let zones = load_zones(&config.zones)?;
+    let hosts = load_hosts(&config)?;
+    let blocklist = load_blocklist(&config)?;
+    let mut policy = ResponsePolicy::new(&config.response_policy_zones);
+    policy.refresh(&zones);
+
+    let routes = routing_table(&config);
+    let default_view = View {
+        config: ViewConfig {
+            name: "default".to_string(),
+            ..ViewConfig::default()
+        },
+        zones: RwLock::new(ZoneSet::new()),
+        routes: routes.clone(),
+        inflight: InflightQueries::new(config.max_waiters_per_query),
+        cache: ResponseCache::new(config.cache_size),
+    };
+    let views = config
+        .views
+        .iter()
+        .map(|view| load_view(&config, view, &routes))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let secondaries = config
+        .secondary_zones
+        .iter()
+        .map(|(origin, primaries)| {
+            let secondary = Secondary {
+                origin: origin.clone(),
+                primaries: primaries.clone(),
+                key: config.secondary_key(origin).cloned(),
+                refresh_now: tokio::sync::Notify::new(),
+            };
+            (origin.clone(), Arc::new(secondary))
+        })
+        .collect();
+
+    let zone_changed = config
+        .zones
+        .iter()
+        .map(|(origin, _)| (origin.clone(), Arc::new(tokio::sync::Notify::new())))
+        .collect();
+    let keyring = Keyring::new(&config.tsig_keys);
+    let rate_limiter = RateLimiter::new(config.rate_limits.clone());
+    let (server_cookies, client_cookies) = match config.cookies {
+        true => (
+            Some(ServerCookies::new(config.cookie_secret.unwrap_or_else(rand::random))),
+            Some(ClientCookies::new(rand::random())),
+        ),
+        false => (None, None),
+    };
+    let transports = Transports::new(config.upstream_ca.as_deref())?;
+    Ok(ResolverContext {
+        config,
+        infra: InfraCache::new(),
+        views,
+        default_view,
+        zones: RwLock::new(zones),
+        hosts: RwLock::new(hosts),
+        blocklist: RwLock::new(blocklist),
+        policy: RwLock::new(policy),
+        secondaries,
+        zone_changed,
+        keyring,
+        rate_limiter,
+        server_cookies,
+        client_cookies,
+        transports,
+    })
+}
+
+/// A listener on `port` of the listening address for TLS connections
+/// offering the ALPN protocols `alpn`, if the port is configured.
+async fn bind_tls(
+    config: &Config,
+    address: IpAddr,
+    port: Option<u16>,
+    alpn: &[&[u8]],
+) -> Result<Option<(TcpListener, TlsAcceptor)>, DnsResolverError> {
+    let Some(port) = port else {
+        return Ok(None);
+    };
+    let (Some(certificate), Some(key)) = (&config.tls_certificate, &config.tls_key) else {
+        unreachable!("the config checks TLS ports come with a certificate and key");
+    };
+    let acceptor = DnsTls::acceptor(certificate, key, alpn)?;
+    let listener = TcpListener::bind(SocketAddr::new(address, port)).await?;
+    Ok(Some((listener, acceptor)))
+}
+
+/// Forwarding mode is a forward route for the root; per-domain forward and stub
+/// zones sit on top of it and win by longest suffix.
+fn routing_table(config: &Config) -> RoutingTable {
+    let mut routes = RoutingTable::new();
+    if config.mode == ResolutionMode::Forward {
+        let pool = UpstreamPool::new(config.forwarders.clone(), config.forward_strategy);
+        routes.insert("", Route::Forward(Arc::new(pool)));
+    }
+    for (zone, servers) in &config.forward_zones {
+        let pool = UpstreamPool::new(servers.clone(), config.forward_strategy);
+        routes.insert(zone, Route::Forward(Arc::new(pool)));
+    }
+    for (zone, servers) in &config.stub_zones {
+        routes.insert(zone, Route::Stub(servers.clone()));
+    }
+    routes
+}
+
+/// A view's routes are the shared ones with its own forward and stub zones on top.
+fn load_view(config: &Config, view: &ViewConfig, shared: &RoutingTable) -> Result<View, DnsResolverError> {
+    let mut routes = shared.clone();
+    for (zone, servers) in &view.forward_zones {
+        let pool = UpstreamPool::new(servers.clone(), config.forward_strategy);
+        routes.insert(zone, Route::Forward(Arc::new(pool)));
+    }
+    for (zone, servers) in &view.stub_zones {
+        routes.insert(zone, Route::Stub(servers.clone()));
+    }
+    Ok(View {
+        config: view.clone(),
+        zones: RwLock::new(load_zones(&view.zones)?),
+        routes,
+        inflight: InflightQueries::new(config.max_waiters_per_query),
+        cache: ResponseCache::new(config.cache_size),
+    })
+}
+
+fn load_zones(files: &[(String, PathBuf)]) -> Result<ZoneSet, DnsResolverError> {
+    let mut zones = ZoneSet::new();
+    for (origin, path) in files {
+        let records = DnsZoneFile::parse_zone_file(path, origin)?;
+        let zone = Zone::new(origin, records)?;
+        println!("Loaded zone {} from {}", origin, path.display());
+        zones.insert(zone);
+    }
+    Ok(zones)
+}
+
+fn load_hosts(config: &Config) -> Result<HostsTable, DnsResolverError> {
+    let mut hosts = HostsTable::new();
+    for path in &config.hosts_files {
+        hosts.load_hosts_file(path)?;
+    }
+    for record in &config.static_records {
+        hosts.add(record.clone());
+    }
+    Ok(hosts)
+}
+
+fn load_blocklist(config: &Config) -> Result<Blocklist, DnsResolverError> {
+    let mut blocklist = Blocklist::new();
+    for path in &config.blocklists {
+        blocklist.load_blocklist(path)?;
+    }
+    for path in &config.allowlists {
+        blocklist.load_allowlist(path)?;
+    }
+    Ok(blocklist)
+}
+
+/// Re-reads every zone, hosts file and block or allow list on SIGHUP. A zone
+/// whose file no longer parses, or whose serial did not move forward, keeps
+/// serving the previous version; so do the hosts files and the lists if one of
+/// them fails to load.
+async fn reload_on_hangup(ctx: Arc<ResolverContext>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        for (origin, path) in &ctx.config.zones {
+            match reload_zone(&ctx.zones, origin, path) {
+                Ok((previous, serial)) => {
+                    println!("Reloaded zone {} at serial {}", origin, serial);
+                    if serial != previous {
+                        zone_changed(&ctx, origin);
+                    }
+                }
+                Err(e) => eprintln!("Error: {}", e),
+            }
+        }
+        for view in &ctx.views {
+            for (origin, path) in &view.config.zones {
+                match reload_zone(&view.zones, origin, path) {
+                    Ok((_, serial)) => println!("Reloaded zone {} in view {} at serial {}", origin, view.config.name, serial),
+                    Err(e) => eprintln!("Error: {}", e),
+                }
+            }
+        }
+        match load_hosts(&ctx.config) {
+            Ok(hosts) => *ctx.hosts.write().unwrap() = hosts,
+            Err(e) => eprintln!("Error: {}", e),
+        }
+        // Lists can be large; queries keep using the old set while they load.
+        match load_blocklist(&ctx.config) {
+            Ok(blocklist) => *ctx.blocklist.write().unwrap() = blocklist,
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+}
+
+/// Re-reads a zone file, returning the serials before and after.
+fn reload_zone(zones: &RwLock<ZoneSet>, origin: &str, path: &Path) -> Result<(u32, u32), DnsResolverError> {
+    let records = DnsZoneFile::parse_zone_file(path, origin)?;
+    match zones.write().unwrap().get_mut(origin) {
+        Some(zone) => {
+            let previous = zone.serial();
+            zone.replace(records).map(|_| (previous, zone.serial()))
+        }
+        None => Ok((0, 0)),
+    }
+}
+
 async fn handle_request(
     buffer: Dnspacket::BytePacketBuffer,
     src: std::net::SocketAddr,
     in_socket: Arc<UdpSocket>,
-    out_socket: Arc<UdpSocket>,
+    ctx: Arc<ResolverContext>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let response_buffer = recursive_resolver(out_socket,buffer).await?;
+    // Rate limits only apply over UDP, where source addresses can be spoofed.
+    if !ctx.rate_limiter.allow_query(src.ip()) {
+        return Ok(());
+    }
+    let request = DnsPacket::from_buffer(&mut buffer.clone())
+        .map_err(|e| DnsResolverError::ParseError(e.to_string()))?;
+
+    let mut signer = ctx.keyring.verify_request(&buffer);
+    let cookie = check_cookie(&ctx, &request, src);
+    let mut response = match &signer {
+        Err(failure) => tsig_error_response(&request, src, failure),
+        Ok(_) if cookie == CookieCheck::Malformed => empty_response(&request, ResultCode::FORMERR),
+        Ok(_) if ctx.config.require_cookies && matches!(cookie, CookieCheck::ClientOnly(_)) => {
+            let mut response = empty_response(&request, ResultCode::NOERROR);
+            set_bad_cookie(&mut response);
+            response
+        }
+        // Zone transfers need TCP; see handle_tcp_connection.
+        Ok(_) if matches!(request.questions.first(), Some(q) if is_transfer(q)) => {
+            empty_response(&request, ResultCode::REFUSED)
+        }
+        Ok(session) => match answer_message(&ctx, request, &buffer, &Client::new(src, session.as_ref())).await {
+            Some(response) => response,
+            None => return Ok(()),
+        },
+    };
+    if let Some(cookies) = &ctx.server_cookies {
+        cookies.attach(&cookie, &mut response, src.ip());
+    }
+    // A valid server cookie shows the source address is not spoofed.
+    if !matches!(cookie, CookieCheck::Valid(_)) {
+        match ctx.rate_limiter.check_response(src.ip(), &response) {
+            RateDecision::Send => (),
+            RateDecision::Drop => return Ok(()),
+            RateDecision::Slip => truncate(&mut response),
+        }
+    }
+
+    let mut response_buffer = Dnspacket::BytePacketBuffer::new();
+    response.write(&mut response_buffer)?;
+    sign_response(&mut signer, &mut response_buffer)?;
     in_socket.send_to(&response_buffer.buf[0..response_buffer.pos], src).await?;
     Ok(())
 }
 
+fn check_cookie(ctx: &ResolverContext, request: &DnsPacket, src: SocketAddr) -> CookieCheck {
+    match &ctx.server_cookies {
+        Some(cookies) => cookies.check(request, src.ip()),
+        None => CookieCheck::Absent,
+    }
+}
+
+/// Dispatches a client message on its opcode. `buffer` holds the message as
+/// received, for opcodes whose sections `DnsPacket` cannot represent.
+/// Answers a message, or None for one that is to be dropped. Failing to
+/// resolve is answered with SERVFAIL, not silence over UDP or a closed
+/// connection over TCP.
+async fn answer_message(
+    ctx: &ResolverContext,
+    request: DnsPacket,
+    buffer: &BytePacketBuffer,
+    client: &Client,
+) -> Option<DnsPacket> {
+    let servfail = empty_response(&request, ResultCode::SERVFAIL);
+    match handle_message(ctx, request, buffer, client).await {
+        Ok(response) => Some(response),
+        Err(DnsResolverError::Dropped) => None,
+        Err(e) => {
+            eprintln!("Error: answering {}: {}", client.addr, e);
+            Some(servfail)
+        }
+    }
+}
+
+async fn handle_message(
+    ctx: &ResolverContext,
+    request: DnsPacket,
+    buffer: &BytePacketBuffer,
+    client: &Client,
+) -> Result<DnsPacket, DnsResolverError> {
+    match request.header.opcode {
+        Opcode::QUERY => recursive_resolver(ctx, request, client).await,
+        Opcode::NOTIFY => Ok(handle_notify(ctx, &request, client)),
+        Opcode::UPDATE => match check_access(&ctx.config.update_acl, &request, client, "UPDATE")? {
+            Some(refused) => Ok(refused),
+            None => Ok(handle_update(ctx, &request, buffer, client)),
+        },
+        _ => Ok(empty_response(&request, ResultCode::NOTIMP)),
+    }
+}
+
+/// The answer to a request whose TSIG did not verify (RFC 8945 section 5.2);
+/// `sign_response` adds the TSIG record carrying the error.
+fn tsig_error_response(request: &DnsPacket, src: SocketAddr, failure: &TsigFailure) -> DnsPacket {
+    eprintln!("TSIG {:?} for key {} from {}", failure.error, failure.key_name(), src);
+    empty_response(request, ResultCode::NOTAUTH)
+}
+
+/// Signs a response the way its request was: in the request's TSIG session,
+/// with the TSIG error if the request failed verification, or not at all.
+fn sign_response(
+    signer: &mut Result<Option<TsigSession>, TsigFailure>,
+    buffer: &mut BytePacketBuffer,
+) -> std::io::Result<()> {
+    match signer {
+        Ok(Some(session)) => session.sign(buffer),
+        Ok(None) => Ok(()),
+        Err(failure) => failure.sign(buffer),
+    }
+}
+
+/// Applies a dynamic update (RFC 2136) to a zone loaded from a zone file.
+/// Secondary zones have to be updated at their primary.
+fn handle_update(
+    ctx: &ResolverContext,
+    request: &DnsPacket,
+    buffer: &BytePacketBuffer,
+    client: &Client,
+) -> DnsPacket {
+    let update = match parse_update(buffer) {
+        Ok(update) => update,
+        Err(rescode) => return empty_response(request, rescode),
+    };
+    if !ctx.config.update_allowed(&update.zone, client.addr.ip(), client.key.as_deref()) {
+        eprintln!("Refused UPDATE of {} from {}", update.zone, client.addr);
+        return empty_response(request, ResultCode::REFUSED);
+    }
+    if ctx.secondaries.contains_key(&update.zone) {
+        return empty_response(request, ResultCode::NOTIMP);
+    }
+
+    let mut zones = ctx.zones.write().unwrap();
+    let Some(zone) = zones.get_mut(&update.zone) else {
+        return empty_response(request, ResultCode::NOTAUTH);
+    };
+    let rescode = match apply_update(zone, &update) {
+        Ok(true) => {
+            println!("Updated zone {} to serial {} for {}", update.zone, zone.serial(), client.addr);
+            zone_changed(ctx, &update.zone);
+            ResultCode::NOERROR
+        }
+        Ok(false) => ResultCode::NOERROR,
+        Err(rescode) => rescode,
+    };
+    empty_response(request, rescode)
+}
+
 async fn recursive_resolver(
-    out_socket: Arc<UdpSocket>,
-    buffer: Dnspacket::BytePacketBuffer,
-) -> Result<Dnspacket::BytePacketBuffer,DnsResolverError> {
-    let mut buffer = buffer;
-    let mut packet = Dnspacket::DnsPacket::from_buffer(&mut buffer)
-        .map_err(|e|DnsResolverError::ParseError(e.to_string()))?;
+    ctx: &ResolverContext,
+    packet: DnsPacket,
+    client: &Client,
+) -> Result<DnsPacket,DnsResolverError> {
+    let mut packet = packet;
 
-    let domain = packet.questions.get(0)
-        .map(|q| &q.name)
+    let question = packet.questions.first()
+        .cloned()
         .ok_or(DnsResolverError::NoQuestionFound)?;
+    let view = select_view(ctx, &packet, client);
+    let subnet = ctx.config.ecs.client_subnet(client.addr.ip(), ClientSubnet::from_packet(&packet));
+
+    // Pinned names, then zones we serve ourselves, are answered straight from
+    // memory, and then the view's cache. Zones are open to every client;
+    // pinned names and cached answers only to those the cache ACL lets in,
+    // and everything else to those the recursion ACL does.
+    let pinned = ctx.hosts.read().unwrap().answer(&question.name, question.qtype);
+    let zoned = match pinned {
+        Some(_) => None,
+        None => local_answer(ctx, view, &question),
+    };
+    let mut cached = None;
+    let access = match (&pinned, &zoned) {
+        (Some(_), _) => Some((&ctx.config.cache_acl, "cached answer")),
+        (None, Some(_)) => None,
+        (None, None) => {
+            cached = view.cache.get(&QueryKey::new(&question), subnet.map(|subnet| subnet.address));
+            let may_recurse = ctx.config.recursion_acl.check(client.addr.ip()) == AclAction::Allow;
+            match cached {
+                Some(_) if may_recurse => None,
+                Some(_) => Some((&ctx.config.cache_acl, "cached answer")),
+                None => Some((&ctx.config.recursion_acl, "recursion")),
+            }
+        }
+    };
+    if let Some((acl, what)) = access {
+        if let Some(refused) = check_access(acl, &packet, client, what)? {
+            return Ok(refused);
+        }
+    }
+    let local = pinned.or(zoned);
+    if local.is_none() && ctx.blocklist.read().unwrap().is_blocked(&question.name) {
+        println!("Blocked {}", question.name);
+        return Ok(blocked_response(ctx, &packet, &question));
+    }
+    if local.is_none() && awaiting_transfer(ctx, &question.name) {
+        return Ok(empty_response(&packet, ResultCode::SERVFAIL));
+    }
+    // Query name policies apply before recursing; a PASSTHRU exempts the
+    // answer from the policies on its contents too.
+    let qname_hit = match local {
+        Some(_) => None,
+        None => current_policy(ctx).qname_hit(&question.name),
+    };
+    if let Some(hit) = &qname_hit {
+        if let Some(response) = policy_response(ctx, view, &packet, &question, hit).await? {
+            return Ok(response);
+        }
+    }
+    let authoritative = local.is_some();
+    let mut response = match (local, cached) {
+        (Some(response), _) | (None, Some(response)) => response,
+        (None, None) => match ctx.config.dns64.reverse_target(&question.name) {
+            Some(target) if question.qtype == QueryType::PTR => {
+                let lookup = DnsQuestion::new(target, QueryType::PTR);
+                synthesize_ptr(&question.name, &cached_resolve(ctx, view, &lookup, subnet).await?)
+            }
+            _ => cached_resolve(ctx, view, &question, subnet).await?,
+        },
+    };
+    if question.qtype == QueryType::AAAA && ctx.config.dns64.wants_synthesis(&response) {
+        let lookup = DnsQuestion::new(question.name.clone(), QueryType::A);
+        let pinned = ctx.hosts.read().unwrap().answer(&lookup.name, lookup.qtype);
+        let a_response = match pinned.or_else(|| local_answer(ctx, view, &lookup)) {
+            Some(a_response) => a_response,
+            None => cached_resolve(ctx, view, &lookup, subnet).await?,
+        };
+        if let Some(synthesised) = ctx.config.dns64.synthesize(&response, &a_response) {
+            response = synthesised;
+        }
+    }
+    if !authoritative && qname_hit.is_none() {
+        if let Some(hit) = response_policy_hit(ctx, view, &question, &response).await {
+            if let Some(response) = policy_response(ctx, view, &packet, &question, &hit).await? {
+                return Ok(response);
+            }
+        }
+    }
+
+    packet.header.response = true;
+    packet.header.recursion_available = true;
+    // Recursive answers are never authoritative, whatever the upstream said.
+    packet.header.authoritative_answer = authoritative && response.header.authoritative_answer;
+    packet.header.rescode = response.header.rescode;
+    // Tell a client that sent its subnet how much of it the answer holds for.
+    if let Some(asked) = ClientSubnet::from_packet(&packet) {
+        let scope_prefix = ClientSubnet::from_packet(&response).map_or(0, |answered| answered.scope_prefix);
+        ClientSubnet { scope_prefix, ..asked }.attach(&mut packet);
+    }
+    packet.answers = response.answers;
+    packet.authorities = response.authorities;
+    packet.resources.retain(|r| matches!(r, DnsRecord::OPT { .. }));
+    if authoritative {
+        packet.resources.extend(response.resources);
+    }
+    Ok(packet)
+}
 
-    let mut ip = String::from("192.203.230.10");
+/// Resolves `question` for a client in `subnet` through the view's cache.
+async fn cached_resolve(
+    ctx: &ResolverContext,
+    view: &View,
+    question: &DnsQuestion,
+    subnet: Option<ClientSubnet>,
+) -> Result<DnsPacket, DnsResolverError> {
+    let key = QueryKey::new(question);
+    if let Some(response) = view.cache.get(&key, subnet.map(|subnet| subnet.address)) {
+        return Ok(response);
+    }
+    let inflight = key.clone().for_subnet(subnet);
+    let response = view.inflight.run(inflight, resolve(ctx, view, question, subnet, 0)).await?;
+    view.cache.insert(key, &response);
+    Ok(response)
+}
+
+/// The first configured view the client belongs to, or the default view.
+fn select_view<'a>(ctx: &'a ResolverContext, request: &DnsPacket, client: &Client) -> &'a View {
+    let subnet = ClientSubnet::from_packet(request);
+    ctx.views
+        .iter()
+        .find(|view| view.matches(client, subnet.as_ref()))
+        .unwrap_or(&ctx.default_view)
+}
 
-    for _ in 0..13 {
-        let (ns_domain, ns_ip ) = fetch_ns(&buffer, &ip, &out_socket).await?;
+/// The answer from the most specific zone holding `question`, the view's own
+/// or a shared one.
+fn local_answer(ctx: &ResolverContext, view: &View, question: &DnsQuestion) -> Option<DnsPacket> {
+    let view_zones = view.zones.read().unwrap();
+    let zones = ctx.zones.read().unwrap();
+    let zone = match (view_zones.find(&question.name), zones.find(&question.name)) {
+        (Some(own), Some(shared)) if shared.origin.len() > own.origin.len() => shared,
+        (Some(own), _) => own,
+        (None, shared) => shared?,
+    };
+    Some(zone.answer(&question.name, question.qtype))
+}
+
+/// None if `acl` lets the client in, else the REFUSED answer to send, or
+/// `Dropped` if nothing is to be sent.
+fn check_access(
+    acl: &Acl,
+    request: &DnsPacket,
+    client: &Client,
+    what: &str,
+) -> Result<Option<DnsPacket>, DnsResolverError> {
+    match acl.check(client.addr.ip()) {
+        AclAction::Allow => Ok(None),
+        AclAction::Refuse => {
+            eprintln!("Refused {} to {}", what, client.addr);
+            Ok(Some(empty_response(request, ResultCode::REFUSED)))
+        }
+        AclAction::Drop => Err(DnsResolverError::Dropped),
+    }
+}
+
+fn blocked_response(ctx: &ResolverContext, request: &DnsPacket, question: &DnsQuestion) -> DnsPacket {
+    let mut response = match &ctx.config.block_response {
+        BlockResponse::NxDomain => empty_response(request, ResultCode::NXDOMAIN),
+        BlockResponse::Refused => empty_response(request, ResultCode::REFUSED),
+        BlockResponse::NoData => empty_response(request, ResultCode::NOERROR),
+        BlockResponse::Sinkhole(addresses) => {
+            let mut response = empty_response(request, ResultCode::NOERROR);
+            for address in addresses {
+                let record = match (address, question.qtype) {
+                    (IpAddr::V4(addr), QueryType::A) => DnsRecord::A {
+                        domain: question.name.clone(),
+                        addr: *addr,
+                        ttl: BLOCKED_TTL,
+                    },
+                    (IpAddr::V6(addr), QueryType::AAAA) => DnsRecord::AAAA {
+                        domain: question.name.clone(),
+                        addr: *addr,
+                        ttl: BLOCKED_TTL,
+                    },
+                    _ => continue,
+                };
+                response.answers.push(record);
+            }
+            response
+        }
+    };
+    response.header.recursion_available = true;
+    response
+}
+
+//<------------------------------- Response policy ----------------------------------->
+
+/// The response policy, re-indexed first if a policy zone changed since.
+fn current_policy(ctx: &ResolverContext) -> std::sync::RwLockReadGuard<'_, ResponsePolicy> {
+    let zones = ctx.zones.read().unwrap();
+    if !ctx.policy.read().unwrap().is_current(&zones) {
+        ctx.policy.write().unwrap().refresh(&zones);
+    }
+    ctx.policy.read().unwrap()
+}
+
+/// The first response IP, NSDNAME or NSIP policy `response` triggers. The
+/// nameservers are only looked up when some policy zone has triggers for them.
+async fn response_policy_hit(
+    ctx: &ResolverContext,
+    view: &View,
+    question: &DnsQuestion,
+    response: &DnsPacket,
+) -> Option<PolicyHit> {
+    let needs_nameservers = {
+        let policy = current_policy(ctx);
+        if policy.is_empty() {
+            return None;
+        }
+        policy.needs_nameservers()
+    };
+    let nameservers = match needs_nameservers {
+        true => nameservers_for(ctx, view, &question.name).await,
+        false => Nameservers::default(),
+    };
+    current_policy(ctx).response_hit(response, &nameservers)
+}
 
-        if ns_domain == *domain{
+/// The NS set of the zone `name` is in: its own, or that of the zone whose SOA
+/// comes back with the negative answer, and the addresses of those hosts.
+async fn nameservers_for(ctx: &ResolverContext, view: &View, name: &str) -> Nameservers {
+    let mut nameservers = Nameservers::default();
+    let mut zone = name.to_lowercase();
+    for _ in 0..2 {
+        let question = DnsQuestion::new(zone.clone(), QueryType::NS);
+        let Ok(response) = view.inflight.run(QueryKey::new(&question), resolve(ctx, view, &question, None, 1)).await else {
+            return nameservers;
+        };
+        nameservers.names = response
+            .answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::NS { domain, host, .. } if *domain == zone => Some(host.clone()),
+                _ => None,
+            })
+            .collect();
+        if !nameservers.names.is_empty() {
             break;
         }
-        ip = ns_ip.to_string();
+        match response.authorities.iter().find_map(|record| match record {
+            DnsRecord::SOA { domain, .. } if *domain != zone => Some(domain.clone()),
+            _ => None,
+        }) {
+            Some(apex) => zone = apex,
+            None => return nameservers,
+        }
+    }
+
+    for host in &nameservers.names {
+        for qtype in [QueryType::A, QueryType::AAAA] {
+            let question = DnsQuestion::new(host.clone(), qtype);
+            if let Ok(response) = view.inflight.run(QueryKey::new(&question), resolve(ctx, view, &question, None, 1)).await {
+                nameservers.addresses.extend(response.answers.iter().filter_map(|record| match record {
+                    DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+                    DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+                    _ => None,
+                }));
+            }
+        }
     }
+    nameservers
+}
 
-    let answer_record = DnsRecord::A {
-        domain: domain.clone(),
-        addr: ip.parse::<Ipv4Addr>().unwrap(),
-        ttl: 1000,
+/// The answer a policy hit calls for, None for PASSTHRU. Local data is renamed to the query name;
+/// a CNAME in it is followed by resolving the target, `*.` in the target
+/// standing for the query name.
+async fn policy_response(
+    ctx: &ResolverContext,
+    view: &View,
+    request: &DnsPacket,
+    question: &DnsQuestion,
+    hit: &PolicyHit,
+) -> Result<Option<DnsPacket>, DnsResolverError> {
+    println!("Response policy {} for {} {:?}", hit, question.name, question.qtype);
+    let mut response = match &hit.action {
+        PolicyAction::NxDomain => empty_response(request, ResultCode::NXDOMAIN),
+        PolicyAction::NoData => empty_response(request, ResultCode::NOERROR),
+        PolicyAction::Passthru => return Ok(None),
+        PolicyAction::Drop => return Err(DnsResolverError::Dropped),
+        PolicyAction::LocalData(records) => {
+            let mut response = empty_response(request, ResultCode::NOERROR);
+            let cname = records.iter().find_map(|record| match record {
+                DnsRecord::CNAME { host, ttl, .. } => Some((host, *ttl)),
+                _ => None,
+            });
+            match cname {
+                Some((host, ttl)) => {
+                    let target = match host.strip_prefix("*.") {
+                        Some(suffix) => format!("{}.{}", question.name.to_lowercase(), suffix),
+                        None => host.clone(),
+                    };
+                    response.answers.push(DnsRecord::CNAME {
+                        domain: question.name.clone(),
+                        host: target.clone(),
+                        ttl,
+                    });
+                    if question.qtype != QueryType::CNAME {
+                        let lookup = DnsQuestion::new(target, question.qtype);
+                        let rest = view.inflight.run(QueryKey::new(&lookup), resolve(ctx, view, &lookup, None, 1)).await?;
+                        response.header.rescode = rest.header.rescode;
+                        response.answers.extend(rest.answers);
+                    }
+                }
+                None => {
+                    response.answers = records
+                        .iter()
+                        .filter(|record| question.qtype == QueryType::ANY || record.query_type() == question.qtype)
+                        .map(|record| record.with_domain(&question.name))
+                        .collect();
+                }
+            }
+            response
+        }
     };
-    packet.answers.push(answer_record);
-    let mut response_buffer = Dnspacket::BytePacketBuffer::new();
-    packet.write(&mut response_buffer).unwrap();
-    Ok(response_buffer)
+    response.header.recursion_available = true;
+    Ok(Some(response))
 }
 
-async fn fetch_ns(
-    buffer: &Dnspacket::BytePacketBuffer,
-    ip_addr: &str,
-    out_socket: &Arc<UdpSocket>,
-) -> Result<(String,Ipv4Addr),DnsResolverError> {
-    let root_server = (IpAddr::V4(ip_addr.parse::<Ipv4Addr>().unwrap()), 53);
-
-    out_socket
-        .send_to(
-            &buffer.buf[0..buffer.pos],
-            root_server,
-        )
+/// Leaves only the header and question, with TC set so the client asks again over TCP.
+fn truncate(response: &mut DnsPacket) {
+    response.header.truncated_message = true;
+    response.answers.clear();
+    response.authorities.clear();
+    response.resources.retain(|r| matches!(r, DnsRecord::OPT { .. }));
+}
+
+fn empty_response(request: &DnsPacket, rescode: ResultCode) -> DnsPacket {
+    let mut response = DnsPacket::new();
+    response.header.id = request.header.id;
+    response.header.opcode = request.header.opcode;
+    response.header.recursion_desired = request.header.recursion_desired;
+    response.header.response = true;
+    response.header.rescode = rescode;
+    response.questions = request.questions.clone();
+    response
+}
+
+fn is_transfer(question: &DnsQuestion) -> bool {
+    matches!(question.qtype, QueryType::AXFR | QueryType::IXFR)
+}
+
+//<------------------------------------- TCP ------------------------------------------>
+
+async fn serve_tcp(listener: TcpListener, ctx: Arc<ResolverContext>) {
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        };
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_tcp_connection(stream, src, ctx, false).await {
+                eprintln!("Error: {}", e);
+            }
+        });
+    }
+}
+
+/// DNS over TLS (RFC 7858): the TCP framing inside a TLS session.
+async fn serve_tls(listener: TcpListener, acceptor: TlsAcceptor, ctx: Arc<ResolverContext>) {
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        };
+        let ctx = ctx.clone();
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            let timeout = Duration::from_secs(ctx.config.tls_idle_timeout);
+            let stream = match tokio::time::timeout(timeout, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => return eprintln!("TLS handshake with {} failed: {}", src, e),
+                Err(_) => return eprintln!("TLS handshake with {} timed out", src),
+            };
+            if let Err(e) = handle_tcp_connection(stream, src, ctx, true).await {
+                eprintln!("Error: {}", e);
+            }
+        });
+    }
+}
+
+/// Serves length-prefixed messages (RFC 1035 section 4.2.2) until the client
+/// closes the connection or goes quiet. Over an `encrypted` stream responses
+/// are padded for clients that ask (RFC 8467).
+async fn handle_tcp_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    src: SocketAddr,
+    ctx: Arc<ResolverContext>,
+    encrypted: bool,
+) -> Result<(), DnsResolverError> {
+    let idle_timeout = match encrypted {
+        true => Duration::from_secs(ctx.config.tls_idle_timeout),
+        false => TCP_IDLE_TIMEOUT,
+    };
+    loop {
+        let length = match tokio::time::timeout(idle_timeout, stream.read_u16()).await {
+            Ok(Ok(length)) => length as usize,
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Ok(()),
+        };
+        let mut buffer = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+        // A client that stalls halfway through a message is as good as idle.
+        match tokio::time::timeout(idle_timeout, stream.read_exact(&mut buffer.buf[..length])).await {
+            Ok(read) => read?,
+            Err(_) => return Ok(()),
+        };
+        println!("Received {} bytes over TCP from {}", length, src);
+
+        let request = DnsPacket::from_buffer(&mut buffer.clone())
+            .map_err(|e| DnsResolverError::ParseError(e.to_string()))?;
+        let mut signer = ctx.keyring.verify_request(&buffer);
+        // Cookies are not required over TCP, which cannot be spoofed.
+        let cookie = check_cookie(&ctx, &request, src);
+        let padded = encrypted && DnsTls::wants_padding(&request);
+        let mut responses = match &signer {
+            Err(failure) => single_message(tsig_error_response(&request, src, failure))?,
+            Ok(_) if cookie == CookieCheck::Malformed => {
+                single_message(empty_response(&request, ResultCode::FORMERR))?
+            }
+            Ok(session) => {
+                let client = Client::new(src, session.as_ref());
+                let responses = if request.questions.first().is_some_and(is_transfer) {
+                    zone_transfer(&ctx, &request, &client)
+                } else {
+                    match answer_message(&ctx, request, &buffer, &client).await {
+                        Some(mut response) => {
+                            if let Some(cookies) = &ctx.server_cookies {
+                                cookies.attach(&cookie, &mut response, src.ip());
+                            }
+                            if padded {
+                                DnsTls::pad(&mut response, DnsTls::RESPONSE_BLOCK_SIZE)?;
+                            }
+                            single_message(response)
+                        }
+                        None => Err(DnsResolverError::Dropped),
+                    }
+                };
+                match responses {
+                    Err(DnsResolverError::Dropped) => continue,
+                    responses => responses?,
+                }
+            }
+        };
+
+        for response in &mut responses {
+            sign_response(&mut signer, response)?;
+            stream.write_u16(response.pos as u16).await?;
+            stream.write_all(&response.buf[..response.pos]).await?;
+        }
+        stream.flush().await?;
+    }
+}
+
+/// Answers AXFR and IXFR for a zone we serve, if the client is allowed to
+/// transfer it.
+fn zone_transfer(
+    ctx: &ResolverContext,
+    request: &DnsPacket,
+    client: &Client,
+) -> Result<Vec<BytePacketBuffer>, DnsResolverError> {
+    if let Some(refused) = check_access(&ctx.config.transfer_acl, request, client, "zone transfer")? {
+        return single_message(refused);
+    }
+    let question = &request.questions[0];
+    let origin = question.name.trim_end_matches('.').to_lowercase();
+    let zones = ctx.zones.read().unwrap();
+    let zone = match zones.get(&origin) {
+        Some(zone) if ctx.config.transfer_allowed(&origin, client.addr.ip(), client.key.as_deref()) => zone,
+        _ => {
+            eprintln!("Refused {:?} of {} to {}", question.qtype, origin, client.addr);
+            return single_message(empty_response(request, ResultCode::REFUSED));
+        }
+    };
+
+    let records = match question.qtype {
+        QueryType::IXFR => {
+            // The client states the version it has in the authority section.
+            let client_serial = request.authorities.iter().find_map(|record| match record {
+                DnsRecord::SOA { serial, .. } => Some(*serial),
+                _ => None,
+            });
+            match client_serial {
+                Some(serial) => ixfr_records(zone, serial),
+                None => return single_message(empty_response(request, ResultCode::FORMERR)),
+            }
+        }
+        _ => axfr_records(zone),
+    };
+    println!("Sending {:?} of {} at serial {} to {}", question.qtype, origin, zone.serial(), client.addr);
+    Ok(transfer_messages(request, records)?)
+}
+
+fn single_message(mut response: DnsPacket) -> Result<Vec<BytePacketBuffer>, DnsResolverError> {
+    let mut buffer = BytePacketBuffer::new();
+    response.write(&mut buffer)?;
+    Ok(vec![buffer])
+}
+
+//<------------------------------- DNS over HTTPS -------------------------------------->
+
+/// DNS over HTTPS (RFC 8484) over HTTP/2, or HTTP/1.1 for clients that
+/// do not offer it.
+async fn serve_https(listener: TcpListener, acceptor: TlsAcceptor, ctx: Arc<ResolverContext>) {
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        };
+        let ctx = ctx.clone();
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            let timeout = Duration::from_secs(ctx.config.tls_idle_timeout);
+            let stream = match tokio::time::timeout(timeout, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => return eprintln!("TLS handshake with {} failed: {}", src, e),
+                Err(_) => return eprintln!("TLS handshake with {} timed out", src),
+            };
+            let service = hyper::service::service_fn(|request| {
+                let ctx = ctx.clone();
+                async move { Ok::<_, std::convert::Infallible>(handle_https_request(&ctx, request, src).await) }
+            });
+            let mut builder = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
+            builder.http1().timer(TokioTimer::new()).header_read_timeout(timeout);
+            if let Err(e) = builder.serve_connection(TokioIo::new(stream), service).await {
+                eprintln!("Error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_https_request(
+    ctx: &ResolverContext,
+    request: Request<Incoming>,
+    src: SocketAddr,
+) -> Response<Full<Bytes>> {
+    if request.uri().path() != DnsHttps::PATH {
+        return http_error(StatusCode::NOT_FOUND);
+    }
+    let dns_message = request.headers().get(CONTENT_TYPE).is_some_and(|value| value == DnsHttps::CONTENT_TYPE);
+    let message = match *request.method() {
+        Method::GET => DnsHttps::get_message(request.uri()),
+        Method::POST if !dns_message => Err(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+        Method::POST => match Limited::new(request.into_body(), MAX_MESSAGE_SIZE).collect().await {
+            Ok(body) => Ok(body.to_bytes().to_vec()),
+            Err(_) => Err(StatusCode::PAYLOAD_TOO_LARGE),
+        },
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+    let message = match message {
+        Ok(message) if message.len() <= MAX_MESSAGE_SIZE => message,
+        Ok(_) => return http_error(StatusCode::PAYLOAD_TOO_LARGE),
+        Err(status) => return http_error(status),
+    };
+    println!("Received {} bytes over HTTPS from {}", message.len(), src);
+
+    match handle_https_message(ctx, &message, src).await {
+        Ok(Some((body, max_age))) => {
+            let mut response = Response::new(Full::new(Bytes::from(body)));
+            let headers = response.headers_mut();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(DnsHttps::CONTENT_TYPE));
+            if let Some(max_age) = max_age {
+                let value = format!("max-age={}", max_age);
+                headers.insert(CACHE_CONTROL, HeaderValue::from_str(&value).expect("digits are a valid header value"));
+            }
+            response
+        }
+        // The resolution was dropped; there is no DNS answer to give.
+        Ok(None) => http_error(StatusCode::SERVICE_UNAVAILABLE),
+        Err(DnsResolverError::ParseError(_)) => http_error(StatusCode::BAD_REQUEST),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            http_error(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Answers a DNS message received over HTTPS the way the other listeners
+/// do, returning the response and how long HTTP caches may keep it.
+async fn handle_https_message(
+    ctx: &ResolverContext,
+    message: &[u8],
+    src: SocketAddr,
+) -> Result<Option<(Vec<u8>, Option<u32>)>, DnsResolverError> {
+    let mut buffer = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+    buffer.buf[..message.len()].copy_from_slice(message);
+    let request = DnsPacket::from_buffer(&mut buffer.clone())
+        .map_err(|e| DnsResolverError::ParseError(e.to_string()))?;
+    let mut signer = ctx.keyring.verify_request(&buffer);
+    let padded = DnsTls::wants_padding(&request);
+    let mut response = match &signer {
+        Err(failure) => tsig_error_response(&request, src, failure),
+        // Zone transfers need TCP; see handle_tcp_connection.
+        Ok(_) if request.questions.first().is_some_and(is_transfer) => {
+            empty_response(&request, ResultCode::REFUSED)
+        }
+        Ok(session) => match answer_message(ctx, request, &buffer, &Client::new(src, session.as_ref())).await {
+            Some(response) => response,
+            None => return Ok(None),
+        },
+    };
+    if padded {
+        DnsTls::pad(&mut response, DnsTls::RESPONSE_BLOCK_SIZE)?;
+    }
+    let max_age = DnsHttps::max_age(&response);
+
+    let mut response_buffer = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+    response.write(&mut response_buffer)?;
+    sign_response(&mut signer, &mut response_buffer)?;
+    Ok(Some((response_buffer.buf[..response_buffer.pos].to_vec(), max_age)))
+}
+
+fn http_error(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = status;
+    response
+}
+
+//<--------------------------------- Outgoing NOTIFY ------------------------------------>
+
+fn zone_changed(ctx: &ResolverContext, origin: &str) {
+    if let Some(changed) = ctx.zone_changed.get(origin) {
+        changed.notify_one();
+    }
+}
+
+/// Tells the secondaries of a local zone about every new version (RFC 1996).
+/// Changes made while a round is still being retried are covered by the next
+/// round, which announces the latest serial.
+async fn send_notifies(ctx: Arc<ResolverContext>, origin: String, changed: Arc<tokio::sync::Notify>) {
+    loop {
+        changed.notified().await;
+        let Some(soa) = ctx.zones.read().unwrap().get(&origin).map(|zone| zone.soa().clone()) else {
+            continue;
+        };
+        let serial = match &soa {
+            DnsRecord::SOA { serial, .. } => *serial,
+            _ => 0,
+        };
+
+        let mut notifications = tokio::task::JoinSet::new();
+        let key = ctx.config.notify_key(&origin).cloned();
+        for target in notify_targets(&ctx, &origin).await {
+            let (origin, soa, key) = (origin.clone(), soa.clone(), key.clone());
+            notifications.spawn(async move { (target, notify_server(&origin, soa, target, key).await) });
+        }
+        while let Some(joined) = notifications.join_next().await {
+            match joined {
+                Ok((target, Ok(()))) => println!("NOTIFY for {} at serial {} acknowledged by {}", origin, serial, target),
+                Ok((target, Err(e))) => eprintln!("NOTIFY for {} at serial {} to {} unanswered: {}", origin, serial, target, e),
+                Err(e) => eprintln!("Error: {}", e),
+            }
+        }
+    }
+}
+
+/// The `also_notify` servers, and the addresses of the zone's nameservers
+/// other than the primary named in its SOA (RFC 1996 section 3.6).
+async fn notify_targets(ctx: &ResolverContext, origin: &str) -> Vec<SocketAddr> {
+    let mut targets: Vec<SocketAddr> = ctx
+        .config
+        .also_notify
+        .iter()
+        .filter(|(zone, _)| zone == origin)
+        .flat_map(|(_, servers)| servers.iter().copied())
+        .collect();
+
+    let hosts: Vec<String> = {
+        let zones = ctx.zones.read().unwrap();
+        let Some(zone) = zones.get(origin) else {
+            return targets;
+        };
+        let primary = match zone.soa() {
+            DnsRecord::SOA { m_name, .. } => m_name.to_lowercase(),
+            _ => String::new(),
+        };
+        zone.answer(origin, QueryType::NS)
+            .answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::NS { host, .. } if host.to_lowercase() != primary => Some(host.to_lowercase()),
+                _ => None,
+            })
+            .collect()
+    };
+
+    for (host, qtype) in hosts.iter().flat_map(|host| [(host, QueryType::A), (host, QueryType::AAAA)]) {
+        let lookup = DnsQuestion::new(host.clone(), qtype);
+        let local = ctx.zones.read().unwrap().find(host).map(|zone| zone.answer(host, qtype));
+        let response = match local {
+            Some(response) => response,
+            None => match ctx.default_view.inflight.run(QueryKey::new(&lookup), resolve(ctx, &ctx.default_view, &lookup, None, 0)).await {
+                Ok(response) => response,
+                Err(e) => {
+                    eprintln!("Could not resolve nameserver {} of {}: {}", host, origin, e);
+                    continue;
+                }
+            },
+        };
+        for record in &response.answers {
+            let addr = match record {
+                DnsRecord::A { addr, .. } => IpAddr::V4(*addr),
+                DnsRecord::AAAA { addr, .. } => IpAddr::V6(*addr),
+                _ => continue,
+            };
+            let target = SocketAddr::new(addr, 53);
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+    }
+    targets
+}
+
+/// Sends one NOTIFY, carrying the new SOA, until `target` acknowledges it or
+/// the attempts run out. With a key the NOTIFY is signed, and so must the
+/// acknowledgement be.
+async fn notify_server(
+    origin: &str,
+    soa: DnsRecord,
+    target: SocketAddr,
+    key: Option<TsigKey>,
+) -> Result<(), DnsResolverError> {
+    let mut request = DnsPacket::new();
+    request.header.id = rand::random();
+    request.header.opcode = Opcode::NOTIFY;
+    request.header.authoritative_answer = true;
+    request.questions.push(DnsQuestion::new(origin.to_string(), QueryType::SOA));
+    request.answers.push(soa);
+    let mut buffer = BytePacketBuffer::new();
+    request.write(&mut buffer)?;
+    let mut session = key.map(TsigSession::new);
+    if let Some(session) = &mut session {
+        session.sign(&mut buffer)?;
+    }
+
+    let bind_addr = match target {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(target).await?;
+
+    let mut wait = NOTIFY_TIMEOUT;
+    let mut last_error = DnsResolverError::Timeout;
+    for _ in 0..NOTIFY_ATTEMPTS {
+        let attempt = async {
+            socket.send(&buffer.buf[..buffer.pos]).await?;
+            notify_response(&socket, request.header.id, session.as_mut()).await
+        };
+        match tokio::time::timeout(wait, attempt).await {
+            Ok(Ok(())) => return Ok(()),
+            // Nothing listening yet, e.g. a secondary that is restarting.
+            Ok(Err(DnsResolverError::NetworkError(e))) => {
+                last_error = e.into();
+                tokio::time::sleep(wait).await;
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => last_error = DnsResolverError::Timeout,
+        }
+        wait *= 2;
+    }
+    Err(last_error)
+}
+
+async fn notify_response(
+    socket: &UdpSocket,
+    id: u16,
+    session: Option<&mut TsigSession>,
+) -> Result<(), DnsResolverError> {
+    loop {
+        let mut buffer = BytePacketBuffer::new();
+        socket.recv(&mut buffer.buf).await?;
+        let Ok(response) = DnsPacket::from_buffer(&mut buffer) else {
+            continue;
+        };
+        if response.header.id != id || !response.header.response || response.header.opcode != Opcode::NOTIFY {
+            continue;
+        }
+        if let Some(session) = session {
+            if !session.verify(&buffer).map_err(DnsResolverError::ParseError)? {
+                return Err(DnsResolverError::ParseError("acknowledgement is not signed".to_string()));
+            }
+        }
+        return match response.header.rescode {
+            ResultCode::NOERROR => Ok(()),
+            rescode => Err(DnsResolverError::ParseError(format!("answered with {:?}", rescode))),
+        };
+    }
+}
+
+//<-------------------------------- Secondary zones ------------------------------------>
+
+/// Keeps a secondary zone in step with its primaries on the SOA refresh and
+/// retry timers, or straight away when one of them sends a NOTIFY. A copy that
+/// could not be refreshed for the expire interval is dropped (RFC 1035 section 4.3.5).
+async fn maintain_secondary(ctx: Arc<ResolverContext>, secondary: Arc<Secondary>) {
+    let mut last_refresh: Option<Instant> = None;
+    loop {
+        let refreshed = match refresh_secondary(&ctx.zones, &secondary).await {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Error: refreshing {}: {}", secondary.origin, e);
+                false
+            }
+        };
+        if refreshed {
+            last_refresh = Some(Instant::now());
+        }
+
+        if expire_secondary(&ctx.zones, &secondary.origin, last_refresh) {
+            last_refresh = None;
+        }
+        let wait = match soa_timers(&ctx.zones, &secondary.origin) {
+            Some((refresh, _, _)) if refreshed => refresh,
+            Some((_, retry, _)) => retry,
+            None => SECONDARY_INITIAL_RETRY,
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(wait.max(MIN_REFRESH_INTERVAL)) => (),
+            _ = secondary.refresh_now.notified() => (),
+        }
+    }
+}
+
+/// Drops the copy of a secondary zone last refreshed longer ago than its SOA's
+/// expire interval. Returns whether it did.
+fn expire_secondary(zones: &RwLock<ZoneSet>, origin: &str, last_refresh: Option<Instant>) -> bool {
+    let expired = match (soa_timers(zones, origin), last_refresh) {
+        (Some((_, _, expire)), Some(at)) => at.elapsed() >= expire,
+        _ => false,
+    };
+    if expired {
+        eprintln!("Zone {} expired, no longer serving it", origin);
+        zones.write().unwrap().remove(origin);
+    }
+    expired
+}
+
+/// Refresh, retry and expire intervals from the SOA of the copy we hold.
+fn soa_timers(zones: &RwLock<ZoneSet>, origin: &str) -> Option<(Duration, Duration, Duration)> {
+    let zones = zones.read().unwrap();
+    match zones.get(origin)?.soa() {
+        DnsRecord::SOA { refresh, retry, expire, .. } => Some((
+            Duration::from_secs(u64::from(*refresh)),
+            Duration::from_secs(u64::from(*retry)),
+            Duration::from_secs(u64::from(*expire)),
+        )),
+        _ => None,
+    }
+}
+
+async fn refresh_secondary(zones: &RwLock<ZoneSet>, secondary: &Secondary) -> Result<(), DnsResolverError> {
+    let origin = &secondary.origin;
+    let current_soa = zones.read().unwrap().get(origin).map(|zone| zone.soa().clone());
+
+    let mut last_error = DnsResolverError::NoNameserverFound;
+    for primary in &secondary.primaries {
+        let mut result = fetch_transfer(origin, *primary, current_soa.as_ref(), secondary.key.as_ref())
+            .await
+            .and_then(|transfer| apply_transfer(zones, origin, transfer));
+        if result.is_err() && current_soa.is_some() {
+            // The primary may not do IXFR, or sent changes that do not fit our
+            // copy; a full transfer sorts out both.
+            result = fetch_transfer(origin, *primary, None, secondary.key.as_ref())
+                .await
+                .and_then(|transfer| apply_transfer(zones, origin, transfer));
+        }
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+fn apply_transfer(zones: &RwLock<ZoneSet>, origin: &str, transfer: Transfer) -> Result<(), DnsResolverError> {
+    let mut zones = zones.write().unwrap();
+    match transfer {
+        Transfer::UpToDate => return Ok(()),
+        Transfer::Full(records) => match zones.get_mut(origin) {
+            Some(zone) => zone.replace(records)?,
+            None => zones.insert(Zone::new(origin, records)?),
+        },
+        Transfer::Incremental(diffs) => {
+            let zone = zones.get_mut(origin).ok_or_else(|| {
+                DnsResolverError::ZoneFileError(format!("incremental transfer of {} without a copy", origin))
+            })?;
+            for diff in &diffs {
+                zone.apply(diff)?;
+            }
+        }
+    }
+    if let Some(zone) = zones.get(origin) {
+        println!("Transferred zone {} at serial {}", origin, zone.serial());
+    }
+    Ok(())
+}
+
+/// Pulls a zone from `primary` over TCP: IXFR from the SOA we have, or AXFR
+/// when we have nothing yet. With a key the request is signed, and so must
+/// the answer be.
+async fn fetch_transfer(
+    origin: &str,
+    primary: SocketAddr,
+    current_soa: Option<&DnsRecord>,
+    key: Option<&TsigKey>,
+) -> Result<Transfer, DnsResolverError> {
+    let mut request = DnsPacket::new();
+    request.header.id = rand::thread_rng().gen();
+    let qtype = if current_soa.is_some() { QueryType::IXFR } else { QueryType::AXFR };
+    request.questions.push(DnsQuestion::new(origin.to_string(), qtype));
+    request.authorities.extend(current_soa.cloned());
+    let current_serial = current_soa.and_then(|soa| match soa {
+        DnsRecord::SOA { serial, .. } => Some(*serial),
+        _ => None,
+    });
+
+    let mut buffer = BytePacketBuffer::new();
+    request.write(&mut buffer)?;
+    let mut session = key.cloned().map(TsigSession::new);
+    if let Some(session) = &mut session {
+        session.sign(&mut buffer)?;
+    }
+    let mut stream = within_transfer_timeout(TcpStream::connect(primary)).await?;
+    stream.write_u16(buffer.pos as u16).await?;
+    stream.write_all(&buffer.buf[..buffer.pos]).await?;
+
+    let mut records = Vec::new();
+    loop {
+        let length = within_transfer_timeout(stream.read_u16()).await? as usize;
+        let mut message = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+        within_transfer_timeout(stream.read_exact(&mut message.buf[..length])).await?;
+        let signed = match &mut session {
+            Some(session) => session
+                .verify(&message)
+                .map_err(|e| DnsResolverError::ParseError(format!("{}: {}", primary, e)))?,
+            None => true,
+        };
+        let response = DnsPacket::from_buffer(&mut message)
+            .map_err(|e| DnsResolverError::ParseError(e.to_string()))?;
+        if response.header.id != request.header.id || !response.header.response {
+            return Err(DnsResolverError::ParseError("unexpected message in zone transfer".to_string()));
+        }
+        if response.header.rescode != ResultCode::NOERROR {
+            return Err(DnsResolverError::ParseError(format!(
+                "{} refused {:?} with {:?}",
+                primary, qtype, response.header.rescode
+            )));
+        }
+
+        records.extend(response.answers);
+        if let Some(transfer) = parse_transfer(&records, current_serial).map_err(DnsResolverError::ParseError)? {
+            if !signed {
+                return Err(DnsResolverError::ParseError(format!("{}: last message is not signed", primary)));
+            }
+            return Ok(transfer);
+        }
+    }
+}
+
+async fn within_transfer_timeout<T>(
+    operation: impl Future<Output = std::io::Result<T>>,
+) -> Result<T, DnsResolverError> {
+    tokio::time::timeout(TRANSFER_TIMEOUT, operation)
         .await
-        .map_err(|e| DnsResolverError::NetworkError(e))?;
+        .map_err(|_| DnsResolverError::Timeout)?
+        .map_err(DnsResolverError::from)
+}
 
-    let mut root_answer_buffer = Dnspacket::BytePacketBuffer::new();
-    let (size, _) = out_socket.recv_from(&mut root_answer_buffer.buf).await
-        .map_err(|e| DnsResolverError::NetworkError(e))?;
+/// A NOTIFY (RFC 1996) from one of a secondary zone's primaries starts a refresh
+/// right away, provided it is signed with the zone's key if it has one; anyone
+/// else is refused.
+fn handle_notify(ctx: &ResolverContext, request: &DnsPacket, client: &Client) -> DnsPacket {
+    let secondary = request
+        .questions
+        .first()
+        .and_then(|question| ctx.secondaries.get(&question.name.trim_end_matches('.').to_lowercase()));
+    let from_primary = |secondary: &Secondary| {
+        let signed = match &secondary.key {
+            Some(key) => client.key.as_deref() == Some(key.name.as_str()),
+            None => true,
+        };
+        signed && secondary.primaries.iter().any(|primary| primary.ip() == client.addr.ip())
+    };
+    match secondary {
+        Some(secondary) if from_primary(secondary) => {
+            println!("NOTIFY for {} from {}", secondary.origin, client.addr);
+            secondary.refresh_now.notify_one();
+            let mut response = empty_response(request, ResultCode::NOERROR);
+            response.header.authoritative_answer = true;
+            response
+        }
+        _ => {
+            eprintln!("Refused NOTIFY from {}", client.addr);
+            empty_response(request, ResultCode::REFUSED)
+        }
+    }
+}
+
+/// Names in a secondary zone we hold no copy of must not be resolved elsewhere.
+fn awaiting_transfer(ctx: &ResolverContext, name: &str) -> bool {
+    let name = name.to_lowercase();
+    ctx.secondaries.keys().any(|origin| is_subdomain(&name, origin))
+}
+
+/// Walks the delegation chain from the root for `question`. Unless minimisation
+/// is off, zones are only shown the labels they need to hand out the next
+/// referral (RFC 9156); in relaxed mode a server that chokes on a minimised
+/// query makes the rest of the walk fall back to the full name.
+fn resolve<'a>(
+    ctx: &'a ResolverContext,
+    view: &'a View,
+    question: &'a DnsQuestion,
+    subnet: Option<ClientSubnet>,
+    depth: usize,
+) -> ResolveFuture<'a> {
+    Box::pin(async move {
+        let mut question = question.clone();
+        question.name = question.name.to_lowercase();
+        // Only zones on the ECS list learn where the client is.
+        let sent = subnet.filter(|_| ctx.config.ecs.covers(&question.name));
+        let response = match view.routes.lookup(&question.name) {
+            Some((_, Route::Forward(pool))) => forward(ctx, pool, &question, sent).await?,
+            Some((zone, Route::Stub(servers))) => {
+                walk_delegations(ctx, view, &question, zone, servers.clone(), sent, depth).await?
+            }
+            None => {
+                let roots = ROOT_SERVERS.iter().map(|ip| SocketAddr::new(IpAddr::V4(*ip), 53)).collect();
+                walk_delegations(ctx, view, &question, "", roots, sent, depth).await?
+            }
+        };
+        chase_cname(ctx, view, &question, response, subnet, depth).await
+    })
+}
+
+async fn walk_delegations(
+    ctx: &ResolverContext,
+    view: &View,
+    question: &DnsQuestion,
+    start_zone: &str,
+    start_servers: Vec<SocketAddr>,
+    subnet: Option<ClientSubnet>,
+    depth: usize,
+) -> Result<DnsPacket,DnsResolverError> {
+    let labels: Vec<&str> = question.name.split('.').filter(|l| !l.is_empty()).collect();
+    let mode = ctx.config.qname_minimisation;
+    let mut minimise = mode != QnameMinimisation::Off;
+    let mut minimise_count = 0;
+    let mut exposed_labels = label_count(start_zone);
+
+    let mut zone = start_zone.to_string();
+    let mut servers = start_servers;
+
+    for _ in 0..MAX_ITERATIONS {
+        let minimised = if minimise {
+            next_minimised_labels(labels.len(), exposed_labels, minimise_count)
+        } else {
+            None
+        };
+
+        if let Some(count) = minimised {
+            minimise_count += 1;
+            let probe = DnsQuestion::new(labels[labels.len() - count..].join("."), QueryType::A);
+
+            let result = fetch_ns(ctx, &probe, &zone, &servers, None).await;
+            match minimised_step(mode, &result) {
+                MinimisedStep::Stop => return result,
+                MinimisedStep::FullName => minimise = false,
+                MinimisedStep::Continue => match result.ok().and_then(|response| referral(&response, &zone)) {
+                    Some(delegation) => {
+                        exposed_labels = count.max(label_count(&delegation.zone));
+                        servers = delegation_servers(ctx, view, &delegation, depth).await?;
+                        zone = delegation.zone;
+                    }
+                    None => exposed_labels = count,
+                },
+            }
+            continue;
+        }
 
-    let packet2 = Dnspacket::DnsPacket::from_buffer(&mut root_answer_buffer).unwrap();
-    if packet2.header.answers != 0 {
-        return random_ns(&packet2.answers);
+        let response = fetch_ns(ctx, question, &zone, &servers, subnet).await?;
+
+        if !response.answers.is_empty()
+            || response.header.authoritative_answer
+            || response.header.rescode == ResultCode::NXDOMAIN
+        {
+            return Ok(response);
+        }
+        let delegation = referral(&response, &zone)
+            .ok_or(DnsResolverError::NoNameserverFound)?;
+        exposed_labels = label_count(&delegation.zone);
+        servers = delegation_servers(ctx, view, &delegation, depth).await?;
+        zone = delegation.zone;
+    }
+
+    Err(DnsResolverError::ResolutionFailed)
+}
+
+/// Addresses for a delegation's nameservers. Glue is used when the referral
+/// carried any that survived scrubbing; otherwise the nameserver names are
+/// looked up from the root like any other name.
+async fn delegation_servers(
+    ctx: &ResolverContext,
+    view: &View,
+    delegation: &Referral,
+    depth: usize,
+) -> Result<Vec<SocketAddr>,DnsResolverError> {
+    if !delegation.servers.is_empty() {
+        return Ok(delegation.servers.clone());
+    }
+    if depth >= MAX_DEPTH {
+        return Err(DnsResolverError::NoNameserverFound);
+    }
+
+    for host in &delegation.hosts {
+        // Without glue a nameserver inside the zone it serves cannot be reached.
+        if is_subdomain(host, &delegation.zone) {
+            continue;
+        }
+        let lookup = DnsQuestion::new(host.clone(), QueryType::A);
+        let response = match resolve(ctx, view, &lookup, None, depth + 1).await {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Could not resolve nameserver {}: {}", host, e);
+                continue;
+            }
+        };
+        let servers: Vec<SocketAddr> = response.answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::A { addr, .. } => Some(SocketAddr::new(IpAddr::V4(*addr), 53)),
+                _ => None,
+            })
+            .collect();
+        if !servers.is_empty() {
+            return Ok(servers);
+        }
     }
-    let ns = random_ns(&packet2.resources)?;
-    Ok(ns)
+    Err(DnsResolverError::NoNameserverFound)
 }
-fn random_ns<'a>(list: &'a Vec<Dnspacket::DnsRecord>) -> Result<(String, Ipv4Addr),DnsResolverError> {
+
+/// Scrubbing leaves a CNAME pointing out of the answering zone without the
+/// records behind it, so the target gets resolved separately and appended.
+async fn chase_cname(
+    ctx: &ResolverContext,
+    view: &View,
+    question: &DnsQuestion,
+    mut response: DnsPacket,
+    subnet: Option<ClientSubnet>,
+    depth: usize,
+) -> Result<DnsPacket,DnsResolverError> {
+    if question.qtype == QueryType::CNAME || response.header.rescode != ResultCode::NOERROR {
+        return Ok(response);
+    }
+
+    let Some(target) = cname_target(&response.answers, &question.name) else {
+        eprintln!("CNAME loop in the answer for {}", question.name);
+        response.header.rescode = ResultCode::SERVFAIL;
+        response.answers.clear();
+        response.authorities.clear();
+        return Ok(response);
+    };
+    let answered = response.answers.iter().any(|record| {
+        record.domain() == Some(target.as_str()) && record.query_type() == question.qtype
+    });
+    if target == question.name || answered || depth >= MAX_DEPTH {
+        return Ok(response);
+    }
+
+    let lookup = DnsQuestion::new(target, question.qtype);
+    let rest = resolve(ctx, view, &lookup, subnet, depth + 1).await?;
+    // The whole answer holds only for as wide a subnet as its narrowest part.
+    let scope = |packet: &DnsPacket| ClientSubnet::from_packet(packet).map_or(0, |subnet| subnet.scope_prefix);
+    if scope(&rest) > scope(&response) {
+        response.resources.retain(|r| !matches!(r, DnsRecord::OPT { .. }));
+        response.resources.extend(rest.resources.iter().filter(|r| matches!(r, DnsRecord::OPT { .. })).cloned());
+    }
+    response.header.rescode = rest.header.rescode;
+    response.answers.extend(rest.answers);
+    response.authorities = rest.authorities;
+    Ok(response)
+}
+
+/// Where the CNAME chain for `name` in `answers` ends, or None if it loops.
+fn cname_target(answers: &[DnsRecord], name: &str) -> Option<String> {
+    let mut followed = HashSet::new();
+    let mut target = name.to_string();
+    while let Some(host) = answers.iter().find_map(|record| match record {
+        DnsRecord::CNAME { domain, host, .. } if *domain == target => Some(host.clone()),
+        _ => None,
+    }) {
+        if !followed.insert(target) {
+            return None;
+        }
+        target = host;
+    }
+    Some(target)
+}
+
+#[derive(Debug, PartialEq)]
+enum MinimisedStep {
+    /// Go on minimising, below the zone the response refers to if any.
+    Continue,
+    /// Send the full name for the rest of the walk.
+    FullName,
+    /// Hand the result to the client.
+    Stop,
+}
+
+/// What the result of a minimised query means for the walk. NXDOMAIN or
+/// servers that all refuse or fail the query end it in strict mode and
+/// make relaxed mode give up on minimising.
+fn minimised_step(mode: QnameMinimisation, result: &Result<DnsPacket, DnsResolverError>) -> MinimisedStep {
+    match result {
+        Ok(response) if response.header.rescode != ResultCode::NXDOMAIN => MinimisedStep::Continue,
+        // RFC 8020: nothing exists below a name that does not exist,
+        // but plenty of servers get empty non-terminals wrong.
+        _ if mode == QnameMinimisation::Strict => MinimisedStep::Stop,
+        _ => MinimisedStep::FullName,
+    }
+}
+
+/// Number of trailing labels of the query name to send next, or `None` once the
+/// full name has to go out. The first few steps add a single label; after that
+/// the remaining labels are spread so the walk stays within MAX_MINIMISE_COUNT.
+fn next_minimised_labels(total: usize, exposed: usize, minimise_count: usize) -> Option<usize> {
+    if exposed >= total {
+        return None;
+    }
+    let step = if minimise_count < MINIMISE_ONE_LAB {
+        1
+    } else {
+        let queries_left = MAX_MINIMISE_COUNT.saturating_sub(minimise_count).max(1);
+        (total - exposed).div_ceil(queries_left)
+    };
+    let count = (exposed + step).min(total);
+    if count == total {
+        None
+    } else {
+        Some(count)
+    }
+}
+
+fn label_count(name: &str) -> usize {
+    name.split('.').filter(|l| !l.is_empty()).count()
+}
+
+/// Hands the whole question to a recursive upstream, moving down the pool's
+/// candidate list whenever a server times out or answers SERVFAIL/REFUSED.
+async fn forward(
+    ctx: &ResolverContext,
+    pool: &UpstreamPool,
+    question: &DnsQuestion,
+    subnet: Option<ClientSubnet>,
+) -> Result<DnsPacket,DnsResolverError> {
+    for upstream in pool.candidates() {
+        let edns = Edns {
+            subnet,
+            cookies: ctx.client_cookies.as_ref(),
+        };
+        match query_forwarder(ctx, &upstream, question, edns).await {
+            Ok((mut response, rtt)) => match response.header.rescode {
+                ResultCode::SERVFAIL | ResultCode::REFUSED | ResultCode::NOTIMP => {
+                    pool.record_failure(&upstream);
+                }
+                _ => {
+                    pool.record_success(&upstream, rtt);
+                    scrub_response(&mut response, "", &question.name);
+                    return Ok(response);
+                }
+            },
+            Err(e) => {
+                eprintln!("Forwarding to {} failed: {}", upstream, e);
+                pool.record_failure(&upstream);
+            }
+        }
+    }
+    Err(DnsResolverError::ResolutionFailed)
+}
+
+/// Queries a forwarder over its transport. Under the opportunistic privacy
+/// profile an encrypted forwarder that cannot be reached securely is asked
+/// in cleartext instead (RFC 8310 section 5).
+async fn query_forwarder(
+    ctx: &ResolverContext,
+    upstream: &Upstream,
+    question: &DnsQuestion,
+    edns: Edns<'_>,
+) -> Result<(DnsPacket, Duration), DnsResolverError> {
+    if !upstream.is_encrypted() {
+        return query_cleartext(ctx, upstream.addr, question, edns, FORWARD_TIMEOUT).await;
+    }
+    match query_encrypted(&ctx.transports, upstream, question, edns.subnet).await {
+        Err(e) if ctx.config.upstream_privacy == UpstreamPrivacy::Opportunistic => {
+            eprintln!("Querying {} in cleartext after: {}", upstream, e);
+            let server = SocketAddr::new(upstream.addr.ip(), 53);
+            query_cleartext(ctx, server, question, edns, FORWARD_TIMEOUT).await
+        }
+        answer => answer,
+    }
+}
+
+/// Queries a forwarder over UDP, randomising the case only while it keeps it.
+/// Many forwarders normalise the name, so a forwarder that answers in another
+/// case is asked again in plain case rather than counted as failed.
+async fn query_cleartext(
+    ctx: &ResolverContext,
+    server: SocketAddr,
+    question: &DnsQuestion,
+    edns: Edns<'_>,
+    timeout: Duration,
+) -> Result<(DnsPacket, Duration), DnsResolverError> {
+    let use_0x20 = ctx.config.use_0x20 && ctx.infra.preserves_case(server);
+    match query_server(question, server, true, Some(edns), use_0x20, timeout).await {
+        Err(DnsResolverError::CaseMismatch) => {
+            ctx.infra.record_case_mismatch(server);
+            query_server(question, server, true, Some(edns), false, timeout).await
+        }
+        answer => answer,
+    }
+}
+
+/// Queries a DNS over TLS or DNS over HTTPS forwarder. Queries are padded
+/// (RFC 8467) and carry no cookies, which TLS makes redundant.
+async fn query_encrypted(
+    transports: &Transports,
+    upstream: &Upstream,
+    question: &DnsQuestion,
+    subnet: Option<ClientSubnet>,
+) -> Result<(DnsPacket, Duration), DnsResolverError> {
+    let mut query = DnsPacket::new();
+    // DNS over HTTPS uses ID 0 so HTTP caches can share answers (RFC 8484 section 4.1).
+    if upstream.transport == Transport::Tls {
+        query.header.id = rand::random();
+    }
+    query.header.recursion_desired = true;
+    query.questions.push(question.clone());
+    query.resources.push(DnsRecord::OPT {
+        packet_len: EDNS_PAYLOAD_SIZE,
+        flags: 0,
+        data: Vec::new(),
+    });
+    if let Some(subnet) = subnet {
+        subnet.attach(&mut query);
+    }
+    DnsTls::pad(&mut query, DnsTls::QUERY_BLOCK_SIZE)?;
+    let mut buffer = BytePacketBuffer::new();
+    query.write(&mut buffer)
+        .map_err(|e| DnsResolverError::ParseError(e.to_string()))?;
+
+    let started = Instant::now();
+    let message = tokio::time::timeout(FORWARD_TIMEOUT, transports.exchange(upstream, &buffer.buf[..buffer.pos]))
+        .await
+        .map_err(|_| DnsResolverError::Timeout)??;
+    let mut answer_buffer = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+    answer_buffer.buf[..message.len()].copy_from_slice(&message);
+    let mut response = DnsPacket::from_buffer(&mut answer_buffer)
+        .map_err(|e| DnsResolverError::ParseError(e.to_string()))?;
+    if !answers_query(&query, &response, subnet) {
+        return Err(DnsResolverError::ParseError(format!("{} answered another query", upstream)));
+    }
+    response.questions[0].name = question.name.clone();
+    Ok((response, started.elapsed()))
+}
+
+/// Whether `response` is an answer to `query`: the same ID and question, the
+/// name compared regardless of case, and any client subnet for the one sent.
+fn answers_query(query: &DnsPacket, response: &DnsPacket, subnet: Option<ClientSubnet>) -> bool {
+    let (Some(sent), Some(echoed)) = (query.questions.first(), response.questions.first()) else {
+        return false;
+    };
+    let subnet_answered = match (subnet, ClientSubnet::from_packet(response)) {
+        (Some(sent), Some(echoed)) => echoed.answers(&sent),
+        _ => true,
+    };
+    response.header.id == query.header.id
+        && response.header.response
+        && echoed.qtype == sent.qtype
+        && echoed.name.eq_ignore_ascii_case(&sent.name)
+        && subnet_answered
+}
+
+/// Periodically asks every upstream for the root NS set so servers that went
+/// down are skipped, and ones that came back are used again.
+async fn health_check(ctx: Arc<ResolverContext>, pool: Arc<UpstreamPool>) {
+    let probe = DnsQuestion::new(String::new(), QueryType::NS);
+    let mut ticker = tokio::time::interval(Duration::from_secs(ctx.config.health_check_interval));
+    loop {
+        ticker.tick().await;
+        for upstream in pool.upstreams() {
+            match query_forwarder(&ctx, upstream, &probe, Edns::default()).await {
+                Ok((response, rtt))
+                    if matches!(response.header.rescode, ResultCode::NOERROR | ResultCode::NXDOMAIN) =>
+                {
+                    pool.record_success(upstream, rtt)
+                }
+                _ => pool.mark_down(upstream),
+            }
+        }
+    }
+}
+
+async fn fetch_ns(
+    ctx: &ResolverContext,
+    question: &DnsQuestion,
+    zone: &str,
+    servers: &[SocketAddr],
+    subnet: Option<ClientSubnet>,
+) -> Result<DnsPacket,DnsResolverError> {
+    let infra = &ctx.infra;
+    let mut candidates = servers.to_vec();
+
+    while let Some(server) = infra.select(zone, &candidates) {
+        candidates.retain(|s| *s != server);
+
+        let mut edns = infra.edns(server) != EdnsStatus::Unsupported;
+        let mut use_0x20 = ctx.config.use_0x20 && infra.preserves_case(server);
+        let response = loop {
+            let timeout = infra.timeout(server);
+            let options = Edns {
+                subnet,
+                cookies: ctx.client_cookies.as_ref(),
+            };
+            match query_server(question, server, false, edns.then_some(options), use_0x20, timeout).await {
+                Ok((response, rtt)) => {
+                    infra.record_rtt(server, rtt);
+                    if edns && response.header.rescode == ResultCode::FORMERR && !has_opt(&response) {
+                        infra.record_edns(server, false);
+                        edns = false;
+                        continue;
+                    }
+                    if edns {
+                        infra.record_edns(server, has_opt(&response));
+                    }
+                    break Some(response);
+                }
+                Err(DnsResolverError::Timeout) => {
+                    infra.record_timeout(server);
+                    break None;
+                }
+                Err(DnsResolverError::CaseMismatch) => {
+                    // Only replies with the wrong case arrived: either someone is
+                    // spoofing, or the server rewrites names. Ask again in plain case.
+                    infra.record_case_mismatch(server);
+                    use_0x20 = false;
+                }
+                Err(e) => {
+                    eprintln!("Query to {} failed: {}", server, e);
+                    infra.record_timeout(server);
+                    break None;
+                }
+            }
+        };
+        let Some(mut response) = response else {
+            continue;
+        };
+        scrub_response(&mut response, zone, &question.name);
+
+        if is_lame(&response, zone) {
+            infra.mark_lame(server, zone);
+            continue;
+        }
+        return Ok(response);
+    }
+    Err(DnsResolverError::NoNameserverFound)
+}
+
+/// What goes into the OPT record of an upstream query.
+#[derive(Clone, Copy, Default)]
+struct Edns<'a> {
+    subnet: Option<ClientSubnet>,
+    cookies: Option<&'a ClientCookies>,
+}
+
+async fn query_server(
+    question: &DnsQuestion,
+    server: SocketAddr,
+    recursion_desired: bool,
+    edns: Option<Edns<'_>>,
+    use_0x20: bool,
+    timeout: Duration,
+) -> Result<(DnsPacket, Duration),DnsResolverError> {
+    let mut sent_question = question.clone();
+    if use_0x20 {
+        sent_question.name = randomise_case(&question.name);
+    }
+
+    let mut query = DnsPacket::new();
+    query.header.id = rand::random();
+    query.header.recursion_desired = recursion_desired;
+    query.questions.push(sent_question.clone());
+    let Edns { subnet, cookies } = edns.unwrap_or_default();
+    if edns.is_some() {
+        query.resources.push(DnsRecord::OPT {
+            packet_len: EDNS_PAYLOAD_SIZE,
+            flags: 0,
+            data: Vec::new(),
+        });
+        if let Some(subnet) = subnet {
+            subnet.attach(&mut query);
+        }
+        if let Some(cookies) = cookies {
+            cookies.attach(&mut query, server.ip());
+        }
+    }
+    let mut buffer = Dnspacket::BytePacketBuffer::new();
+    query.write(&mut buffer)
+        .map_err(|e| DnsResolverError::ParseError(e.to_string()))?;
+
+    let bind_addr = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(server).await?;
+
+    let started = Instant::now();
+    socket.send(&buffer.buf[0..buffer.pos]).await?;
+
+    let deadline = started + timeout;
+    let mut case_mismatch = false;
+    let mut cookie_retried = false;
+    loop {
+        let mut answer_buffer = Dnspacket::BytePacketBuffer::new();
+        let received = tokio::time::timeout_at(deadline.into(), socket.recv(&mut answer_buffer.buf)).await;
+        match received {
+            Ok(result) => result?,
+            Err(_) if case_mismatch => return Err(DnsResolverError::CaseMismatch),
+            Err(_) => return Err(DnsResolverError::Timeout),
+        };
+
+        let response = match Dnspacket::DnsPacket::from_buffer(&mut answer_buffer) {
+            Ok(response) => response,
+            Err(_) => continue,
+        };
+        if !answers_query(&query, &response, subnet) {
+            continue;
+        }
+        if use_0x20 && response.questions[0].name != sent_question.name {
+            case_mismatch = true;
+            continue;
+        }
+        if let Some(cookies) = cookies {
+            if !cookies.accept(&response, server.ip()) {
+                continue;
+            }
+            // The server wants its own cookie back before it answers; it came
+            // with this response, so ask once more (RFC 7873 section 5.3).
+            if is_bad_cookie(&response) && !cookie_retried {
+                cookie_retried = true;
+                cookies.attach(&mut query, server.ip());
+                let mut buffer = Dnspacket::BytePacketBuffer::new();
+                query.write(&mut buffer)
+                    .map_err(|e| DnsResolverError::ParseError(e.to_string()))?;
+                socket.send(&buffer.buf[0..buffer.pos]).await?;
+                continue;
+            }
+        }
+
+        let mut response = response;
+        response.questions[0].name = question.name.clone();
+        return Ok((response, started.elapsed()));
+    }
+}
+
+/// DNS 0x20: flip the case of each letter at random so that a forged reply also
+/// has to guess the exact spelling of the name we sent.
+fn randomise_case(name: &str) -> String {
+    let mut rng = rand::thread_rng();
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphabetic() && rng.gen_bool(0.5) {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+fn has_opt(packet: &DnsPacket) -> bool {
+    packet.resources.iter().any(|r| matches!(r, DnsRecord::OPT { .. }))
+}
+
+/// A server is lame for `zone` when it refuses or fails the query, or hands back
+/// neither an answer nor a referral to a zone below the one it was asked about.
+fn is_lame(response: &DnsPacket, zone: &str) -> bool {
+    match response.header.rescode {
+        ResultCode::SERVFAIL | ResultCode::REFUSED | ResultCode::NOTIMP => return true,
+        ResultCode::NXDOMAIN => return false,
+        _ => (),
+    }
+    if !response.answers.is_empty() || response.header.authoritative_answer {
+        return false;
+    }
+    referral(response, zone).is_none()
+}
+
+struct Referral {
+    zone: String,
+    hosts: Vec<String>,
+    servers: Vec<SocketAddr>,
+}
+
+fn referral(response: &DnsPacket, zone: &str) -> Option<Referral> {
+    let mut next_zone: Option<String> = None;
+    let mut hosts = Vec::new();
+    for record in &response.authorities {
+        if let DnsRecord::NS { domain, host, .. } = record {
+            if domain.len() <= zone.len() || !is_subdomain(domain, zone) {
+                continue;
+            }
+            if next_zone.is_none() || next_zone.as_ref() == Some(domain) {
+                next_zone = Some(domain.clone());
+                hosts.push(host.clone());
+            }
+        }
+    }
+    let next_zone = next_zone?;
+
+    let servers = glue_servers(&response.resources, &hosts);
+    Some(Referral {
+        zone: next_zone,
+        hosts,
+        servers,
+    })
+}
+
+fn glue_servers(list: &[DnsRecord], hosts: &[String]) -> Vec<SocketAddr> {
+    let mut servers = Vec::new();
     for record in list {
         match record {
-            Dnspacket::DnsRecord::A { domain, addr, ttl } => return Ok((domain.clone(), *addr)),
+            DnsRecord::A { domain, addr, .. } if hosts.contains(domain) => {
+                servers.push(SocketAddr::new(IpAddr::V4(*addr), 53))
+            }
+            DnsRecord::AAAA { domain, addr, .. } if hosts.contains(domain) => {
+                servers.push(SocketAddr::new(IpAddr::V6(*addr), 53))
+            }
             _ => (),
         }
     }
-    Err(DnsResolverError::NoNameserverFound)
+    servers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DnsForwarder::ForwardStrategy;
+    use rustls::crypto::ring::default_provider;
+    use rustls::pki_types::ServerName;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use tokio_rustls::TlsConnector;
+
+    #[test]
+    fn minimises_one_label_at_a_time_then_spreads_the_rest() {
+        // a.b.c.d.e.f.g.h.i.j.k.l.example.com from the root.
+        let total = 14;
+        let mut exposed = 0;
+        let mut steps = Vec::new();
+        while let Some(count) = next_minimised_labels(total, exposed, steps.len()) {
+            steps.push(count);
+            exposed = count;
+        }
+        assert_eq!(steps, vec![1, 2, 3, 4, 6, 8, 10, 12, 13]);
+        assert!(steps.len() < MAX_MINIMISE_COUNT);
+
+        // Below a referral the next query adds one label; the full name is never minimised.
+        assert_eq!(next_minimised_labels(3, 2, 0), None);
+        assert_eq!(next_minimised_labels(4, 2, 0), Some(3));
+        assert_eq!(next_minimised_labels(4, 4, 0), None);
+    }
+
+    #[test]
+    fn falls_back_to_the_full_name_in_relaxed_mode() {
+        let mut nxdomain = DnsPacket::new();
+        nxdomain.header.rescode = ResultCode::NXDOMAIN;
+        let nxdomain = Ok(nxdomain);
+        // Every server refusing the query leaves fetch_ns without an answer.
+        let refused = Err(DnsResolverError::NoNameserverFound);
+        let referral = Ok(DnsPacket::new());
+
+        assert_eq!(minimised_step(QnameMinimisation::Relaxed, &nxdomain), MinimisedStep::FullName);
+        assert_eq!(minimised_step(QnameMinimisation::Relaxed, &refused), MinimisedStep::FullName);
+        assert_eq!(minimised_step(QnameMinimisation::Relaxed, &referral), MinimisedStep::Continue);
+        assert_eq!(minimised_step(QnameMinimisation::Strict, &nxdomain), MinimisedStep::Stop);
+        assert_eq!(minimised_step(QnameMinimisation::Strict, &refused), MinimisedStep::Stop);
+        assert_eq!(minimised_step(QnameMinimisation::Strict, &referral), MinimisedStep::Continue);
+    }
+
+    fn cname(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::CNAME {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            ttl: 300,
+        }
+    }
+
+    #[test]
+    fn follows_cname_chains_and_detects_loops() {
+        let chain = [cname("www.example.com", "a.example.net"), cname("a.example.net", "b.example.org")];
+        assert_eq!(cname_target(&chain, "www.example.com").as_deref(), Some("b.example.org"));
+        assert_eq!(cname_target(&chain, "other.example.com").as_deref(), Some("other.example.com"));
+
+        // A loop the query name leads into without being part of it.
+        let cycle = [
+            cname("www.example.com", "a.example.net"),
+            cname("a.example.net", "b.example.net"),
+            cname("b.example.net", "a.example.net"),
+        ];
+        assert_eq!(cname_target(&cycle, "www.example.com"), None);
+        let back = [cname("www.example.com", "a.example.net"), cname("a.example.net", "www.example.com")];
+        assert_eq!(cname_target(&back, "www.example.com"), None);
+    }
+
+    #[test]
+    fn randomised_case_keeps_the_letters() {
+        let name = "www.Example-1.com";
+        let randomised = randomise_case(name);
+        assert!(randomised.eq_ignore_ascii_case(name));
+        assert!(randomised.contains("-1."));
+    }
+
+    /// Answers queries with the question name as `rewrite` spells it.
+    async fn echo_server(rewrite: fn(&str) -> String) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let mut buffer = BytePacketBuffer::new();
+                let (_, src) = socket.recv_from(&mut buffer.buf).await.unwrap();
+                let mut response = DnsPacket::from_buffer(&mut buffer).unwrap();
+                response.header.response = true;
+                response.questions[0].name = rewrite(&response.questions[0].name);
+                let mut buffer = BytePacketBuffer::new();
+                response.write(&mut buffer).unwrap();
+                socket.send_to(&buffer.buf[..buffer.pos], src).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn rejects_replies_in_another_case() {
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+        let timeout = Duration::from_millis(300);
+
+        let swapped = echo_server(|name| {
+            name.chars()
+                .map(|c| match c.is_ascii_uppercase() {
+                    true => c.to_ascii_lowercase(),
+                    false => c.to_ascii_uppercase(),
+                })
+                .collect()
+        })
+        .await;
+        let result = query_server(&question, swapped, false, None, true, timeout).await;
+        assert!(matches!(result, Err(DnsResolverError::CaseMismatch)));
+
+        let exact = echo_server(|name| name.to_string()).await;
+        let (response, _) = query_server(&question, exact, false, None, true, timeout).await.unwrap();
+        // The client sees the name as it asked.
+        assert_eq!(response.questions[0].name, "www.example.com");
+    }
+
+    #[tokio::test]
+    async fn asks_forwarders_that_lowercase_names_again_in_plain_case() {
+        let ctx = resolver_context(Config::parse("use_0x20 = true\n").unwrap()).unwrap();
+        let forwarder = echo_server(|name| name.to_lowercase()).await;
+        // Long enough that randomising is all but certain to change the case.
+        let question = DnsQuestion::new("a-rather-long-name.example.com".to_string(), QueryType::A);
+        let (edns, timeout) = (Edns::default(), Duration::from_millis(300));
+
+        let (response, _) = query_cleartext(&ctx, forwarder, &question, edns, timeout).await.unwrap();
+        assert_eq!(response.questions[0].name, question.name);
+        assert!(!ctx.infra.preserves_case(forwarder));
+        // Now known to rewrite the case, so asked in plain case straight away.
+        let started = Instant::now();
+        query_cleartext(&ctx, forwarder, &question, edns, timeout).await.unwrap();
+        assert!(started.elapsed() < timeout);
+    }
+
+    /// Answers queries with `rescode`, or not at all without one.
+    async fn forwarder(rescode: Option<ResultCode>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let mut buffer = BytePacketBuffer::new();
+                let (_, src) = socket.recv_from(&mut buffer.buf).await.unwrap();
+                let Some(rescode) = rescode else {
+                    continue;
+                };
+                let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+                let mut response = empty_response(&request, rescode);
+                let mut buffer = BytePacketBuffer::new();
+                response.write(&mut buffer).unwrap();
+                socket.send_to(&buffer.buf[..buffer.pos], src).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn forwarding_fails_over_to_the_next_upstream() {
+        let ctx = resolver_context(Config::new()).unwrap();
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+        let mut upstreams = Vec::new();
+        for rescode in [None, Some(ResultCode::SERVFAIL), Some(ResultCode::REFUSED), Some(ResultCode::NXDOMAIN)] {
+            upstreams.push(Upstream::udp(forwarder(rescode).await));
+        }
+
+        let pool = UpstreamPool::new(upstreams.clone(), ForwardStrategy::Ordered);
+        let response = forward(&ctx, &pool, &question, None).await.unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+
+        let failing = UpstreamPool::new(upstreams[1..3].to_vec(), ForwardStrategy::Ordered);
+        let result = forward(&ctx, &failing, &question, None).await;
+        assert!(matches!(result, Err(DnsResolverError::ResolutionFailed)));
+    }
+
+    #[tokio::test]
+    async fn answers_refused_waiters_with_servfail() {
+        let ctx = resolver_context(Config::parse("max_waiters_per_query = 0\n").unwrap()).unwrap();
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+        let mut request = DnsPacket::new();
+        request.header.id = 7;
+        request.header.recursion_desired = true;
+        request.questions.push(question.clone());
+        let client = Client::new("127.0.0.1:5300".parse().unwrap(), None);
+
+        // A resolution of the same name is under way, with no room to wait for it.
+        let (done, finished) = tokio::sync::oneshot::channel::<()>();
+        let leader = ctx.default_view.inflight.run(QueryKey::new(&question).for_subnet(None), async {
+            let _ = finished.await;
+            Ok(DnsPacket::new())
+        });
+        let refused = async {
+            let response = answer_message(&ctx, request, &BytePacketBuffer::new(), &client).await;
+            let _ = done.send(());
+            response
+        };
+        let (_, response) = tokio::join!(leader, refused);
+        let response = response.unwrap();
+        assert_eq!(response.header.id, 7);
+        assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+    }
+
+    #[tokio::test]
+    async fn closes_connections_stalled_inside_a_message() {
+        let config = Config::parse("tls_idle_timeout = 1\n").unwrap();
+        let ctx = Arc::new(resolver_context(config).unwrap());
+        let (mut client, server) = tokio::io::duplex(512);
+        let src = "127.0.0.1:5300".parse().unwrap();
+        let connection = tokio::spawn(handle_tcp_connection(server, src, ctx, true));
+
+        // The length of a message that never follows.
+        client.write_u16(40).await.unwrap();
+        client.write_all(&[0; 12]).await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(5), connection).await.unwrap();
+        assert!(closed.unwrap().is_ok());
+        assert!(client.read_u8().await.is_err());
+    }
+
+    fn secondary_zone(serial: u32, www: u8) -> Vec<DnsRecord> {
+        let contents = format!(
+            "@ 3600 IN SOA ns1 hostmaster ( {} 7200 3600 60 300 )\n\
+             @ 3600 IN NS ns1\n\
+             ns1 3600 IN A 192.0.2.1\n\
+             www 3600 IN A 192.0.2.{}\n",
+            serial, www
+        );
+        DnsZoneFile::parse_zone(&contents, "example.com").unwrap()
+    }
+
+    /// A primary transferring its copy of a zone, with IXFR only while `ixfr`
+    /// is set; notes the types of transfer asked for.
+    struct StandInPrimary {
+        zone: Mutex<Zone>,
+        ixfr: AtomicBool,
+        asked: Mutex<Vec<QueryType>>,
+    }
+
+    async fn serve_transfers(primary: Arc<StandInPrimary>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let length = stream.read_u16().await.unwrap() as usize;
+                let mut buffer = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+                stream.read_exact(&mut buffer.buf[..length]).await.unwrap();
+                let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+                let qtype = request.questions[0].qtype;
+                primary.asked.lock().unwrap().push(qtype);
+                let messages = {
+                    let zone = primary.zone.lock().unwrap();
+                    let client_serial = request.authorities.iter().find_map(|record| match record {
+                        DnsRecord::SOA { serial, .. } => Some(*serial),
+                        _ => None,
+                    });
+                    match (qtype, client_serial) {
+                        (QueryType::IXFR, _) if !primary.ixfr.load(Ordering::SeqCst) => {
+                            single_message(empty_response(&request, ResultCode::NOTIMP)).unwrap()
+                        }
+                        (QueryType::IXFR, Some(serial)) => {
+                            transfer_messages(&request, ixfr_records(&zone, serial)).unwrap()
+                        }
+                        _ => transfer_messages(&request, axfr_records(&zone)).unwrap(),
+                    }
+                };
+                for message in messages {
+                    stream.write_u16(message.pos as u16).await.unwrap();
+                    stream.write_all(&message.buf[..message.pos]).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn keeps_secondary_zones_in_step_until_they_expire() {
+        let primary = Arc::new(StandInPrimary {
+            zone: Mutex::new(Zone::new("example.com", secondary_zone(1, 80)).unwrap()),
+            ixfr: AtomicBool::new(true),
+            asked: Mutex::new(Vec::new()),
+        });
+        let secondary = Secondary {
+            origin: "example.com".to_string(),
+            primaries: vec![serve_transfers(primary.clone()).await],
+            key: None,
+            refresh_now: tokio::sync::Notify::new(),
+        };
+        let zones = RwLock::new(ZoneSet::new());
+        let www = |zones: &RwLock<ZoneSet>| {
+            let zones = zones.read().unwrap();
+            let zone = zones.get("example.com").unwrap();
+            (zone.serial(), zone.answer("www.example.com", QueryType::A).answers)
+        };
+        let a = |octet| {
+            vec![DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, octet),
+                ttl: 3600,
+            }]
+        };
+
+        // Nothing held yet, so the whole zone.
+        refresh_secondary(&zones, &secondary).await.unwrap();
+        assert_eq!(www(&zones), (1, a(80)));
+
+        // Then only what changed.
+        primary.zone.lock().unwrap().replace(secondary_zone(2, 81)).unwrap();
+        refresh_secondary(&zones, &secondary).await.unwrap();
+        assert_eq!(www(&zones), (2, a(81)));
+
+        // A primary without IXFR gets asked for the whole zone again.
+        primary.ixfr.store(false, Ordering::SeqCst);
+        primary.zone.lock().unwrap().replace(secondary_zone(3, 82)).unwrap();
+        refresh_secondary(&zones, &secondary).await.unwrap();
+        assert_eq!(www(&zones), (3, a(82)));
+        assert_eq!(
+            *primary.asked.lock().unwrap(),
+            [QueryType::AXFR, QueryType::IXFR, QueryType::IXFR, QueryType::AXFR]
+        );
+
+        // Kept for the SOA's expire interval of a minute since the last refresh.
+        assert!(!expire_secondary(&zones, "example.com", Some(Instant::now())));
+        assert!(zones.read().unwrap().get("example.com").is_some());
+        let long_ago = Instant::now().checked_sub(Duration::from_secs(61)).unwrap();
+        assert!(expire_secondary(&zones, "example.com", Some(long_ago)));
+        assert!(zones.read().unwrap().get("example.com").is_none());
+    }
+
+    /// Acknowledges one NOTIFY, which has to be signed with `key`; the
+    /// acknowledgement is signed only if `sign_ack`.
+    async fn notified_secondary(key: TsigKey, sign_ack: bool) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut buffer.buf).await.unwrap();
+            let mut session = Keyring::new(&[key]).verify_request(&buffer).ok().flatten().expect("signed NOTIFY");
+            let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+            let mut response = empty_response(&request, ResultCode::NOERROR);
+            let mut buffer = BytePacketBuffer::new();
+            response.write(&mut buffer).unwrap();
+            if sign_ack {
+                session.sign(&mut buffer).unwrap();
+            }
+            socket.send_to(&buffer.buf[..buffer.pos], src).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn signs_notify_and_checks_the_acknowledgement() {
+        let key = TsigKey::parse("xfr-key hmac-sha256 c2VjcmV0IGtleSBmb3IgdGVzdHM=").unwrap();
+        let soa = secondary_zone(2, 80).into_iter().find(|record| matches!(record, DnsRecord::SOA { .. })).unwrap();
+
+        let signed = notified_secondary(key.clone(), true).await;
+        notify_server("example.com", soa.clone(), signed, Some(key.clone())).await.unwrap();
+
+        let unsigned = notified_secondary(key.clone(), false).await;
+        let result = notify_server("example.com", soa, unsigned, Some(key)).await;
+        assert!(matches!(result, Err(DnsResolverError::ParseError(_))));
+    }
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/DnsFunctions/testdata").join(name)
+    }
+
+    #[tokio::test]
+    async fn serves_dns_over_tls_until_the_client_goes_quiet() {
+        let config = Config::parse("static_record = api.internal A 10.0.0.5\ntls_idle_timeout = 1\n").unwrap();
+        let ctx = Arc::new(resolver_context(config).unwrap());
+        let acceptor = DnsTls::acceptor(&testdata("localhost.pem"), &testdata("localhost.key"), &[b"dot"]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_tls(listener, acceptor, ctx));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_parsable_certificates(DnsTls::certificates(&testdata("ca.pem")).unwrap());
+        let mut client = rustls::ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client.alpn_protocols = vec![b"dot".to_vec()];
+        let name = ServerName::try_from("localhost").unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = TlsConnector::from(Arc::new(client)).connect(name, stream).await.unwrap();
+
+        // Both queries on the one connection.
+        for id in [1, 2] {
+            let mut query = DnsPacket::new();
+            query.header.id = id;
+            query.header.recursion_desired = true;
+            query.questions.push(DnsQuestion::new("api.internal".to_string(), QueryType::A));
+            let mut buffer = BytePacketBuffer::new();
+            query.write(&mut buffer).unwrap();
+            stream.write_u16(buffer.pos as u16).await.unwrap();
+            stream.write_all(&buffer.buf[..buffer.pos]).await.unwrap();
+
+            let length = stream.read_u16().await.unwrap() as usize;
+            let mut buffer = BytePacketBuffer::new();
+            stream.read_exact(&mut buffer.buf[..length]).await.unwrap();
+            let response = DnsPacket::from_buffer(&mut buffer).unwrap();
+            assert_eq!(response.header.id, id);
+            assert_eq!(response.answers.len(), 1);
+        }
+
+        // Quiet for the idle timeout of a second, the connection is closed.
+        let quiet = Instant::now();
+        let closed = tokio::time::timeout(Duration::from_secs(5), stream.read_u8()).await.unwrap();
+        assert!(closed.is_err());
+        assert!(quiet.elapsed() >= Duration::from_millis(900));
+    }
 }